@@ -0,0 +1,85 @@
+/// Piece bitfield as sent in the `bitfield` message. The high bit of the first byte is piece 0
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bitfield {
+    bytes: Vec<u8>,
+    len: usize,
+}
+
+impl Bitfield {
+    pub fn new(len: usize) -> Self {
+        Self {
+            bytes: vec![0; len.div_ceil(8)],
+            len,
+        }
+    }
+
+    /// Builds a bitfield out of the payload of a `bitfield` message. Spare bits at the end must be
+    /// cleared and the payload must be exactly as long as `len` requires.
+    pub fn from_bytes(bytes: &[u8], len: usize) -> Option<Self> {
+        if bytes.len() != len.div_ceil(8) {
+            return None;
+        }
+
+        let bitfield = Self { bytes: bytes.to_vec(), len };
+
+        if (len..bitfield.bytes.len() * 8).any(|i| bitfield.get_bit(i)) {
+            return None;
+        }
+
+        Some(bitfield)
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn has(&self, index: usize) -> bool {
+        index < self.len && self.get_bit(index)
+    }
+
+    pub fn set(&mut self, index: usize) {
+        if index < self.len {
+            self.bytes[index / 8] |= 0x80 >> (index % 8);
+        }
+    }
+
+    pub fn unset(&mut self, index: usize) {
+        if index < self.len {
+            self.bytes[index / 8] &= !(0x80 >> (index % 8));
+        }
+    }
+
+    /// Grows or shrinks the bitfield, clearing any bits past the new length
+    pub fn resize(&mut self, len: usize) {
+        for i in len..self.len.min(self.bytes.len() * 8) {
+            self.unset(i);
+        }
+
+        self.bytes.resize(len.div_ceil(8), 0);
+        self.len = len;
+    }
+
+    pub fn count(&self) -> usize {
+        self.bytes.iter().map(|x| x.count_ones() as usize).sum()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.count() == self.len
+    }
+
+    pub fn iter_set(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.len).filter(|i| self.get_bit(*i))
+    }
+
+    fn get_bit(&self, index: usize) -> bool {
+        self.bytes[index / 8] & (0x80 >> (index % 8)) != 0
+    }
+}
@@ -0,0 +1,296 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use rand::Rng;
+
+/// Slot counts, set per torrent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChokerConfig {
+    /// Peers unchoked for reciprocating the best
+    pub unchoke_slots: usize,
+
+    /// Peers unchoked at random, so new peers get a chance to show what they've got
+    pub optimistic_slots: usize,
+}
+
+impl Default for ChokerConfig {
+    fn default() -> Self {
+        Self {
+            unchoke_slots: 4,
+            optimistic_slots: 1,
+        }
+    }
+}
+
+/// What the choker needs to know about a connected peer
+#[derive(Debug, Clone, Copy)]
+pub struct PeerStats {
+    /// Bytes per second we get from the peer
+    pub download_rate: u64,
+
+    /// Bytes per second we send to the peer
+    pub upload_rate: u64,
+
+    /// The peer is interested in our pieces. Only those are worth unchoking
+    pub peer_interested: bool,
+
+    /// We're interested in the peer's pieces
+    pub am_interested: bool,
+    pub connected_at: Instant,
+
+    /// When the peer last sent us a block
+    pub last_block_at: Option<Instant>,
+}
+
+impl PeerStats {
+    /// We want something from the peer but it hasn't sent a block in a minute
+    pub fn is_snubbed(&self, now: Instant) -> bool {
+        let last = self.last_block_at.unwrap_or(self.connected_at);
+
+        self.am_interested && now.duration_since(last) >= Choker::SNUB_TIMEOUT
+    }
+}
+
+/// Peers to send `choke` and `unchoke` to after a round
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ChokeDecision {
+    pub unchoke: Vec<SocketAddr>,
+    pub choke: Vec<SocketAddr>,
+}
+
+/// Tit-for-tat choking.
+///
+/// Every `UNCHOKE_INTERVAL` the interested peers that give us the most (or that take the most
+/// when we're seeding) get the regular unchoke slots. Snubbed peers don't get a regular slot
+/// while downloading. Every `OPTIMISTIC_INTERVAL` the optimistic slots go to random choked
+/// peers, with newly connected peers three times as likely to be picked since they have nothing
+/// to offer yet.
+#[derive(Debug)]
+pub struct Choker {
+    config: ChokerConfig,
+    unchoked: HashSet<SocketAddr>,
+    optimistic: Vec<SocketAddr>,
+    last_optimistic_at: Option<Instant>,
+}
+
+impl Choker {
+    pub const UNCHOKE_INTERVAL: Duration = Duration::from_secs(10);
+    pub const OPTIMISTIC_INTERVAL: Duration = Duration::from_secs(30);
+    pub const SNUB_TIMEOUT: Duration = Duration::from_secs(60);
+
+    /// Peers connected for less than this count as new for optimistic unchoking
+    const NEW_PEER_AGE: Duration = Duration::from_secs(3 * 30);
+
+    pub fn new(config: ChokerConfig) -> Self {
+        Self {
+            config,
+            unchoked: HashSet::new(),
+            optimistic: vec![],
+            last_optimistic_at: None,
+        }
+    }
+
+    pub fn config(&self) -> ChokerConfig {
+        self.config
+    }
+
+    /// Takes effect on the next round
+    pub fn set_config(&mut self, config: ChokerConfig) {
+        self.config = config;
+    }
+
+    pub fn is_unchoked(&self, peer: &SocketAddr) -> bool {
+        self.unchoked.contains(peer)
+    }
+
+    /// Runs a round of choking, meant to be called every `UNCHOKE_INTERVAL`. Peers missing from
+    /// `peers` are considered gone
+    pub fn run(&mut self, peers: &HashMap<SocketAddr, PeerStats>, seeding: bool, now: Instant) -> ChokeDecision {
+        self.unchoked.retain(|x| peers.contains_key(x));
+        self.optimistic.retain(|x| peers.get(x).is_some_and(|x| x.peer_interested));
+
+        let mut candidates = peers
+            .iter()
+            .filter(|(_, stats)| stats.peer_interested && (seeding || !stats.is_snubbed(now)))
+            .map(|(addr, stats)| (*addr, if seeding { stats.upload_rate } else { stats.download_rate }))
+            .collect::<Vec<(SocketAddr, u64)>>();
+
+        candidates.sort_by_key(|(_, rate)| std::cmp::Reverse(*rate));
+
+        let regular = candidates
+            .into_iter()
+            .take(self.config.unchoke_slots)
+            .map(|(addr, _)| addr)
+            .collect::<HashSet<SocketAddr>>();
+
+        let rotate = self.last_optimistic_at.is_none_or(|x| now.duration_since(x) >= Self::OPTIMISTIC_INTERVAL);
+
+        // Peers that earned a regular slot don't need the optimistic one
+        self.optimistic.retain(|x| !regular.contains(x));
+
+        if rotate {
+            self.optimistic.clear();
+            self.last_optimistic_at = Some(now);
+        }
+
+        if self.optimistic.len() < self.config.optimistic_slots {
+            self.pick_optimistic(peers, &regular, now);
+        }
+
+        let unchoked = regular
+            .into_iter()
+            .chain(self.optimistic.iter().copied())
+            .collect::<HashSet<SocketAddr>>();
+
+        let mut decision = ChokeDecision {
+            unchoke: unchoked.difference(&self.unchoked).copied().collect(),
+            choke: self.unchoked.difference(&unchoked).copied().collect(),
+        };
+
+        decision.unchoke.sort();
+        decision.choke.sort();
+
+        self.unchoked = unchoked;
+
+        decision
+    }
+
+    /// Fills the free optimistic slots with random choked, interested peers
+    fn pick_optimistic(&mut self, peers: &HashMap<SocketAddr, PeerStats>, regular: &HashSet<SocketAddr>, now: Instant) {
+        let mut pool = peers
+            .iter()
+            .filter(|(addr, stats)| stats.peer_interested && !regular.contains(addr) && !self.optimistic.contains(addr))
+            .map(|(addr, stats)| {
+                let weight = if now.duration_since(stats.connected_at) < Self::NEW_PEER_AGE { 3 } else { 1 };
+
+                (*addr, weight)
+            })
+            .collect::<Vec<(SocketAddr, u32)>>();
+
+        let mut rng = rand::thread_rng();
+
+        while self.optimistic.len() < self.config.optimistic_slots && !pool.is_empty() {
+            let total: u32 = pool.iter().map(|(_, weight)| weight).sum();
+            let mut pick = rng.gen_range(0..total);
+
+            let position = pool
+                .iter()
+                .position(|(_, weight)| {
+                    if pick < *weight {
+                        return true;
+                    }
+
+                    pick -= weight;
+
+                    false
+                })
+                .unwrap();
+
+            self.optimistic.push(pool.remove(position).0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        net::SocketAddr,
+        time::{Duration, Instant},
+    };
+
+    use super::{ChokeDecision, Choker, ChokerConfig, PeerStats};
+
+    fn peer(n: u8) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, n], 6881))
+    }
+
+    fn stats(now: Instant, download_rate: u64, upload_rate: u64) -> PeerStats {
+        PeerStats {
+            download_rate,
+            upload_rate,
+            peer_interested: true,
+            am_interested: true,
+            connected_at: now - Duration::from_secs(600),
+            last_block_at: Some(now),
+        }
+    }
+
+    #[test]
+    fn unchokes_fastest_peers() {
+        // Far enough from boot that subtracting from it can't underflow
+        let now = Instant::now() + Duration::from_secs(3600);
+        let mut choker = Choker::new(ChokerConfig { unchoke_slots: 2, optimistic_slots: 0 });
+
+        let mut peers = HashMap::from([
+            (peer(1), stats(now, 100, 900)),
+            (peer(2), stats(now, 300, 100)),
+            (peer(3), stats(now, 200, 500)),
+        ]);
+
+        // Not interested in anything we have
+        peers.insert(peer(4), PeerStats { peer_interested: false, ..stats(now, 1000, 1000) });
+
+        // Hasn't sent anything for too long
+        peers.insert(peer(5), PeerStats { last_block_at: Some(now - Duration::from_secs(61)), ..stats(now, 1000, 1000) });
+
+        assert_eq!(choker.run(&peers, false, now), ChokeDecision { unchoke: vec![peer(2), peer(3)], choke: vec![] });
+
+        // Seeding goes by upload rate and doesn't care about snubbing
+        assert_eq!(choker.run(&peers, true, now), ChokeDecision { unchoke: vec![peer(1), peer(5)], choke: vec![peer(2), peer(3)] });
+
+        peers.remove(&peer(5));
+        assert_eq!(choker.run(&peers, true, now), ChokeDecision { unchoke: vec![peer(3)], choke: vec![] });
+    }
+
+    #[test]
+    fn rotates_optimistic_unchoke() {
+        // Far enough from boot that subtracting from it can't underflow
+        let now = Instant::now() + Duration::from_secs(3600);
+        let mut choker = Choker::new(ChokerConfig { unchoke_slots: 1, optimistic_slots: 1 });
+
+        let mut peers = HashMap::from([
+            (peer(1), stats(now, 500, 0)),
+            (peer(2), stats(now, 100, 0)),
+        ]);
+
+        assert_eq!(choker.run(&peers, false, now).unchoke, vec![peer(1), peer(2)]);
+
+        // A new peer shows up but the optimistic slot is kept until the interval is over
+        peers.insert(peer(3), PeerStats { connected_at: now, ..stats(now, 0, 0) });
+        assert_eq!(choker.run(&peers, false, now + Duration::from_secs(10)), ChokeDecision::default());
+
+        // Rotating has to pick someone other than the regular slot holder
+        let later = now + Choker::OPTIMISTIC_INTERVAL;
+        choker.run(&peers, false, later);
+
+        assert!(choker.is_unchoked(&peer(1)));
+        assert_eq!(choker.unchoked.len(), 2);
+    }
+
+    #[test]
+    fn prefers_new_peers_for_optimistic_slot() {
+        // Far enough from boot that subtracting from it can't underflow
+        let now = Instant::now() + Duration::from_secs(3600);
+        let mut new_picked = 0;
+
+        for _ in 0..400 {
+            let mut choker = Choker::new(ChokerConfig { unchoke_slots: 0, optimistic_slots: 1 });
+
+            let peers = HashMap::from([
+                (peer(1), stats(now, 0, 0)),
+                (peer(2), PeerStats { connected_at: now, ..stats(now, 0, 0) }),
+            ]);
+
+            if choker.run(&peers, false, now).unchoke == vec![peer(2)] {
+                new_picked += 1;
+            }
+        }
+
+        // Expected to be 300
+        assert!(new_picked > 240 && new_picked < 360, "{}", new_picked);
+    }
+}
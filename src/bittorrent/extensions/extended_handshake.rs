@@ -0,0 +1,135 @@
+use std::{collections::HashMap, io};
+
+use crate::{bittorrent::message::invalid_data, utils::bencode::{BencodeParser, BencodeValue}};
+
+/// The BEP 10 handshake, sent as extended message id 0 right after the regular handshake
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct ExtendedHandshake {
+    /// Extension names mapped to the message ids the sender wants to receive them on. An id of 0
+    /// means the extension got disabled
+    pub m: HashMap<String, u8>,
+
+    /// Size of the info dict in bytes (ut_metadata)
+    pub metadata_size: Option<usize>,
+
+    /// Client name and version
+    pub v: Option<String>,
+
+    /// Number of outstanding requests the sender supports
+    pub reqq: Option<usize>,
+
+    /// The sender's listen port
+    pub p: Option<u16>,
+}
+
+impl ExtendedHandshake {
+    pub fn encode(&self) -> Vec<u8> {
+        let m = self.m
+            .iter()
+            .map(|(name, id)| (name.as_bytes().to_vec(), BencodeValue::Integer(*id as i64)))
+            .collect();
+
+        let mut dict = HashMap::from([
+            ("m".as_bytes().to_vec(), BencodeValue::Dict(m)),
+        ]);
+
+        if let Some(metadata_size) = self.metadata_size {
+            dict.insert("metadata_size".as_bytes().to_vec(), BencodeValue::Integer(metadata_size as i64));
+        }
+
+        if let Some(v) = &self.v {
+            dict.insert("v".as_bytes().to_vec(), BencodeValue::Bytes(v.as_bytes().to_vec()));
+        }
+
+        if let Some(reqq) = self.reqq {
+            dict.insert("reqq".as_bytes().to_vec(), BencodeValue::Integer(reqq as i64));
+        }
+
+        if let Some(p) = self.p {
+            dict.insert("p".as_bytes().to_vec(), BencodeValue::Integer(p as i64));
+        }
+
+        BencodeValue::Dict(dict).serialize()
+    }
+
+    pub fn decode(payload: &[u8]) -> io::Result<Self> {
+        let value = BencodeParser::new(payload)
+            .parse_value()
+            .map_err(|_| invalid_data("Failed to parse extended handshake"))?;
+
+        let dict = value
+            .dict()
+            .map_err(|_| invalid_data("Extended handshake should be a dict"))?;
+
+        // Unknown or malformed entries are skipped rather than failing the whole handshake
+        let m = dict
+            .get("m".as_bytes())
+            .and_then(|x| x.dict().ok())
+            .map(|m| {
+                m.iter()
+                    .filter_map(|(name, id)| {
+                        let name = String::from_utf8(name.clone()).ok()?;
+                        let id = u8::try_from(*id.integer().ok()?).ok()?;
+
+                        Some((name, id))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let get_integer = |key: &str| {
+            dict.get(key.as_bytes())
+                .and_then(|x| x.integer().ok())
+                .copied()
+        };
+
+        Ok(Self {
+            m,
+            metadata_size: get_integer("metadata_size").and_then(|x| usize::try_from(x).ok()),
+            v: dict
+                .get("v".as_bytes())
+                .and_then(|x| x.bytes().ok())
+                .map(|x| String::from_utf8_lossy(x).into_owned()),
+            reqq: get_integer("reqq").and_then(|x| usize::try_from(x).ok()),
+            p: get_integer("p").and_then(|x| u16::try_from(x).ok()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::bittorrent::extensions::ExtensionRegistry;
+
+    use super::ExtendedHandshake;
+
+    #[test]
+    fn encodes_and_decodes_handshake() {
+        let handshake = ExtendedHandshake {
+            m: HashMap::from([("ut_metadata".to_owned(), 3), ("ut_pex".to_owned(), 1)]),
+            metadata_size: Some(31_235),
+            v: Some("Test 1.0".to_owned()),
+            reqq: Some(500),
+            p: Some(6881),
+        };
+
+        assert_eq!(ExtendedHandshake::decode(&handshake.encode()).unwrap(), handshake);
+    }
+
+    #[test]
+    fn tracks_remote_extension_ids() {
+        let mut registry = ExtensionRegistry::default();
+
+        let mut handshake = ExtendedHandshake::decode("d1:md11:ut_metadatai3eee".as_bytes()).unwrap();
+        registry.apply_remote(&handshake);
+
+        assert_eq!(registry.remote_id("ut_metadata"), Some(3));
+        assert_eq!(registry.local_name(1), Some("ut_metadata"));
+
+        handshake.m.insert("ut_metadata".to_owned(), 0);
+        registry.apply_remote(&handshake);
+
+        assert_eq!(registry.remote_id("ut_metadata"), None);
+    }
+}
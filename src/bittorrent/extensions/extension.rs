@@ -1,5 +1,9 @@
+use std::io;
+
 pub trait Extension {
     const NAME: &'static str;
 
-    async fn process_packet(&self, data: &[u8]) -> ();
+    /// Handles the payload of an extended message that was addressed to this extension. Returns
+    /// a payload to send back to the peer, if any.
+    fn process_packet(&mut self, data: &[u8]) -> io::Result<Option<Vec<u8>>>;
 }
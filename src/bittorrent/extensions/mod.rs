@@ -1,5 +1,10 @@
 pub mod ut_metadata;
 
 mod extension;
+mod extended_handshake;
+mod registry;
 
 pub use extension::Extension;
+pub use extended_handshake::ExtendedHandshake;
+pub use registry::ExtensionRegistry;
+pub use ut_metadata::UTMetadata;
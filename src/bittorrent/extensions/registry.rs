@@ -0,0 +1,62 @@
+use std::collections::HashMap;
+
+use crate::bittorrent::{
+    extensions::{ExtendedHandshake, Extension, UTMetadata},
+    upload::MAX_UPLOAD_QUEUE,
+};
+
+/// Keeps track of the message ids assigned to each extension. Both sides pick their own ids, so
+/// messages we send use the remote's id for an extension while messages we receive use ours.
+#[derive(Debug, Clone)]
+pub struct ExtensionRegistry {
+    local: HashMap<String, u8>,
+    remote: HashMap<String, u8>,
+}
+
+impl Default for ExtensionRegistry {
+    fn default() -> Self {
+        Self {
+            local: HashMap::from([
+                (UTMetadata::NAME.to_owned(), 1),
+            ]),
+            remote: HashMap::new(),
+        }
+    }
+}
+
+impl ExtensionRegistry {
+    /// The handshake advertising every extension we support
+    pub fn local_handshake(&self, metadata_size: Option<usize>) -> ExtendedHandshake {
+        ExtendedHandshake {
+            m: self.local.clone(),
+            metadata_size,
+            v: Some(format!("rustbittorrent {}", env!("CARGO_PKG_VERSION"))),
+            reqq: Some(MAX_UPLOAD_QUEUE),
+            p: None,
+        }
+    }
+
+    /// Records the ids the remote assigned. Later handshakes may update or disable (id 0) entries
+    pub fn apply_remote(&mut self, handshake: &ExtendedHandshake) {
+        for (name, id) in &handshake.m {
+            if *id == 0 {
+                self.remote.remove(name);
+            } else {
+                self.remote.insert(name.clone(), *id);
+            }
+        }
+    }
+
+    /// Id to put on messages we send for the extension `name`
+    pub fn remote_id(&self, name: &str) -> Option<u8> {
+        self.remote.get(name).copied()
+    }
+
+    /// Name of the extension an incoming message belongs to
+    pub fn local_name(&self, id: u8) -> Option<&str> {
+        self.local
+            .iter()
+            .find(|(_, x)| **x == id)
+            .map(|(name, _)| name.as_str())
+    }
+}
@@ -1,56 +1,274 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, io};
 
-use crate::utils::bencode::BencodeValue;
-use crate::bittorrent::extensions::Extension;
+use sha1::{Digest, Sha1};
+
+use crate::bittorrent::{extensions::Extension, message::invalid_data};
+use crate::utils::bencode::{BencodeParser, BencodeValue};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MetadataMessage {
+    Request { piece: usize },
+    Data { piece: usize, total_size: usize, data: Vec<u8> },
+    Reject { piece: usize },
+}
+
+impl MetadataMessage {
+    pub fn encode(&self) -> Vec<u8> {
+        let (msg_type, piece) = match self {
+            Self::Request { piece } => (0, piece),
+            Self::Data { piece, .. } => (1, piece),
+            Self::Reject { piece } => (2, piece),
+        };
+
+        let mut dict = HashMap::from([
+            ("msg_type".as_bytes().to_vec(), BencodeValue::Integer(msg_type)),
+            ("piece".as_bytes().to_vec(), BencodeValue::Integer(*piece as i64)),
+        ]);
+
+        if let Self::Data { total_size, .. } = self {
+            dict.insert("total_size".as_bytes().to_vec(), BencodeValue::Integer(*total_size as i64));
+        }
+
+        let mut payload = BencodeValue::Dict(dict).serialize();
+
+        // The piece data is appended right after the dict, it isn't part of it
+        if let Self::Data { data, .. } = self {
+            payload.extend_from_slice(data);
+        }
+
+        payload
+    }
+
+    pub fn decode(payload: &[u8]) -> io::Result<Self> {
+        let mut parser = BencodeParser::new(payload);
+
+        let value = parser
+            .parse_value()
+            .map_err(|_| invalid_data("Failed to parse ut_metadata message"))?;
+
+        let dict = value
+            .dict()
+            .map_err(|_| invalid_data("ut_metadata message should be a dict"))?;
+
+        let get_integer = |key: &str| {
+            dict.get(key.as_bytes())
+                .and_then(|x| x.integer().ok())
+                .and_then(|x| usize::try_from(*x).ok())
+                .ok_or_else(|| invalid_data(&format!("ut_metadata message is missing {}", key)))
+        };
+
+        let piece = get_integer("piece")?;
+
+        match get_integer("msg_type")? {
+            0 => Ok(Self::Request { piece }),
+            1 => Ok(Self::Data {
+                piece,
+                total_size: get_integer("total_size")?,
+                data: payload[parser.position()..].to_vec(),
+            }),
+            2 => Ok(Self::Reject { piece }),
+            msg_type => Err(invalid_data(&format!("Unknown ut_metadata msg_type {}", msg_type))),
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct UTMetadata {
+    info_hash: [u8; 20],
     data: Vec<u8>,
-    total_size: Option<i64>,
+    total_size: Option<usize>,
+    received: Vec<bool>,
+
+    /// Metadata we already have and can hand out to peers that request it
+    local_metadata: Option<Vec<u8>>,
 }
 
 impl Extension for UTMetadata {
     const NAME: &'static str = "ut_metadata";
 
-    async fn process_packet(&self, data: &[u8]) -> () {
-        
+    fn process_packet(&mut self, data: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        match MetadataMessage::decode(data)? {
+            MetadataMessage::Request { piece } => {
+                let reply = match self.local_piece(piece) {
+                    Some(data) => MetadataMessage::Data {
+                        piece,
+                        total_size: self.local_metadata.as_ref().map_or(0, |x| x.len()),
+                        data: data.to_vec(),
+                    },
+                    None => MetadataMessage::Reject { piece },
+                };
+
+                Ok(Some(reply.encode()))
+            },
+            MetadataMessage::Data { piece, total_size, data } => {
+                if self.total_size.is_some_and(|x| x != total_size) {
+                    return Err(invalid_data("Peer changed the metadata size"));
+                }
+
+                self.set_total_size(total_size)?;
+
+                let expected_length = self.piece_size(piece)
+                    .ok_or_else(|| invalid_data("Received an out of range metadata piece"))?;
+
+                if data.len() != expected_length {
+                    return Err(invalid_data("Metadata piece has the wrong size"));
+                }
+
+                let start = piece * Self::MAX_PIECE_SIZE;
+                self.data[start..start + data.len()].copy_from_slice(&data);
+                self.received[piece] = true;
+
+                Ok(None)
+            },
+            MetadataMessage::Reject { piece } =>
+                Err(invalid_data(&format!("Peer rejected metadata piece {}", piece))),
+        }
     }
 }
 
 impl UTMetadata {
-    const MAX_PIECE_SIZE: i64 = 16_384;
+    const MAX_PIECE_SIZE: usize = 16_384;
+
+    /// Upper bound on the metadata size we accept, so a peer can't make us allocate arbitrary
+    /// amounts of memory
+    const MAX_METADATA_SIZE: usize = 32 * 1024 * 1024;
 
-    fn new() -> UTMetadata {
+    pub fn new(info_hash: &[u8; 20]) -> UTMetadata {
         Self {
+            info_hash: *info_hash,
             data: Vec::new(),
             total_size: None,
+            received: Vec::new(),
+            local_metadata: None,
         }
     }
 
-    fn next_piece_index(&self) -> i64 {
-        if let Some(total_size) = self.total_size {
-            let total_piece_count = (total_size as f64 / Self::MAX_PIECE_SIZE as f64) as i64;
-            let downloaded_pieces = self.data.len() as i64 / Self::MAX_PIECE_SIZE;
+    /// Used when we already have the info dict and only serve it
+    pub fn with_local_metadata(info_hash: &[u8; 20], metadata: Vec<u8>) -> UTMetadata {
+        Self {
+            local_metadata: Some(metadata),
+            ..Self::new(info_hash)
+        }
+    }
 
-            return total_piece_count - downloaded_pieces;
+    /// Sizes the download buffer. Only the first call has any effect
+    pub fn set_total_size(&mut self, total_size: usize) -> io::Result<()> {
+        if self.total_size.is_some() {
+            return Ok(());
         }
 
+        if total_size == 0 || total_size > Self::MAX_METADATA_SIZE {
+            return Err(invalid_data(&format!("Invalid metadata size {}", total_size)));
+        }
+
+        self.total_size = Some(total_size);
+        self.data = vec![0; total_size];
+        self.received = vec![false; total_size.div_ceil(Self::MAX_PIECE_SIZE)];
+
+        Ok(())
+    }
 
-        0
+    pub fn piece_count(&self) -> usize {
+        self.received.len()
     }
 
-    fn get_request_message(&self) -> Vec<u8> {
-        let index = self.next_piece_index();
+    pub fn next_piece_index(&self) -> Option<usize> {
+        self.received.iter().position(|x| !x)
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.total_size.is_some() && self.next_piece_index().is_none()
+    }
+
+    pub fn get_request_message(&self, piece: usize) -> Vec<u8> {
+        MetadataMessage::Request { piece }.encode()
+    }
+
+    /// Returns the reassembled info dict once every piece is in and its SHA-1 matches the
+    /// info-hash. On a mismatch the downloaded pieces are discarded so they can be fetched again.
+    pub fn verified_metadata(&mut self) -> io::Result<Vec<u8>> {
+        if !self.is_complete() {
+            return Err(invalid_data("Metadata is incomplete"));
+        }
+
+        if Sha1::digest(&self.data).as_slice() != self.info_hash {
+            self.received.fill(false);
+
+            return Err(invalid_data("Metadata doesn't match the info-hash"));
+        }
+
+        Ok(self.data.clone())
+    }
+
+    fn piece_size(&self, piece: usize) -> Option<usize> {
+        let total_size = self.total_size?;
+        let start = piece.checked_mul(Self::MAX_PIECE_SIZE)?;
+
+        (start < total_size).then(|| Self::MAX_PIECE_SIZE.min(total_size - start))
+    }
+
+    fn local_piece(&self, piece: usize) -> Option<&[u8]> {
+        let metadata = self.local_metadata.as_ref()?;
+        let start = piece.checked_mul(Self::MAX_PIECE_SIZE)?;
+
+        (start < metadata.len())
+            .then(|| &metadata[start..(start + Self::MAX_PIECE_SIZE).min(metadata.len())])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sha1::{Digest, Sha1};
+
+    use crate::bittorrent::extensions::Extension;
+
+    use super::{MetadataMessage, UTMetadata};
+
+    #[test]
+    fn reassembles_and_verifies_metadata() {
+        let metadata = (0..40_000).map(|x| (x % 251) as u8).collect::<Vec<u8>>();
+        let info_hash: [u8; 20] = Sha1::digest(&metadata).into();
+
+        let mut seeder = UTMetadata::with_local_metadata(&info_hash, metadata.clone());
+        let mut leecher = UTMetadata::new(&info_hash);
+
+        leecher.set_total_size(metadata.len()).unwrap();
+        assert_eq!(leecher.piece_count(), 3);
+
+        // Serve the pieces out of order
+        for piece in [2, 0, 1] {
+            let reply = seeder
+                .process_packet(&leecher.get_request_message(piece))
+                .unwrap()
+                .unwrap();
+
+            assert!(leecher.process_packet(&reply).unwrap().is_none());
+        }
+
+        assert!(leecher.is_complete());
+        assert_eq!(leecher.verified_metadata().unwrap(), metadata);
+    }
+
+    #[test]
+    fn rejects_metadata_with_wrong_hash() {
+        let mut leecher = UTMetadata::new(&[0; 20]);
+
+        let data = MetadataMessage::Data { piece: 0, total_size: 3, data: vec![1, 2, 3] };
+        leecher.process_packet(&data.encode()).unwrap();
+
+        assert!(leecher.verified_metadata().is_err());
+        assert_eq!(leecher.next_piece_index(), Some(0));
+    }
+
+    #[test]
+    fn replies_with_reject_without_metadata() {
+        let mut peer = UTMetadata::new(&[0; 20]);
+
+        let reply = peer
+            .process_packet(&MetadataMessage::Request { piece: 0 }.encode())
+            .unwrap()
+            .unwrap();
 
-        BencodeValue::Dict(HashMap::from([
-            (
-                "msg_type".as_bytes().to_vec(),
-                BencodeValue::Integer(0),
-            ),
-            (
-                "piece".as_bytes().to_vec(),
-                BencodeValue::Integer(index),
-            )
-        ])).serialize()
+        assert_eq!(MetadataMessage::decode(&reply).unwrap(), MetadataMessage::Reject { piece: 0 });
     }
 }
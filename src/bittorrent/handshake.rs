@@ -0,0 +1,142 @@
+use std::io;
+
+use crate::bittorrent::message::invalid_data;
+
+pub const PROTOCOL: &[u8; 19] = b"BitTorrent protocol";
+pub const HANDSHAKE_LENGTH: usize = 68;
+
+/// The 8 reserved bytes of the handshake, used to advertise protocol extensions
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ReservedBits(pub [u8; 8]);
+
+impl ReservedBits {
+    /// BEP 10: reserved_byte[5] & 0x10
+    const EXTENSION_PROTOCOL: (usize, u8) = (5, 0x10);
+
+    /// BEP 5: reserved_byte[7] & 0x01
+    const DHT: (usize, u8) = (7, 0x01);
+
+    /// BEP 6: reserved_byte[7] & 0x04
+    const FAST: (usize, u8) = (7, 0x04);
+
+    /// Everything this client knows how to speak
+    pub fn supported() -> Self {
+        Self::default()
+            .with_extension_protocol()
+            .with_dht()
+            .with_fast()
+    }
+
+    pub fn with_extension_protocol(self) -> Self {
+        self.with(Self::EXTENSION_PROTOCOL)
+    }
+
+    pub fn with_dht(self) -> Self {
+        self.with(Self::DHT)
+    }
+
+    pub fn with_fast(self) -> Self {
+        self.with(Self::FAST)
+    }
+
+    pub fn supports_extension_protocol(&self) -> bool {
+        self.has(Self::EXTENSION_PROTOCOL)
+    }
+
+    pub fn supports_dht(&self) -> bool {
+        self.has(Self::DHT)
+    }
+
+    pub fn supports_fast(&self) -> bool {
+        self.has(Self::FAST)
+    }
+
+    /// Bits both sides have set. An extension is only usable if it's in here
+    pub fn intersection(&self, other: &ReservedBits) -> ReservedBits {
+        let mut bytes = [0u8; 8];
+
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = self.0[i] & other.0[i];
+        }
+
+        ReservedBits(bytes)
+    }
+
+    fn with(mut self, (byte, mask): (usize, u8)) -> Self {
+        self.0[byte] |= mask;
+
+        self
+    }
+
+    fn has(&self, (byte, mask): (usize, u8)) -> bool {
+        self.0[byte] & mask != 0
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Handshake {
+    pub reserved: ReservedBits,
+    pub info_hash: [u8; 20],
+    pub peer_id: [u8; 20],
+}
+
+impl Handshake {
+    pub fn encode(&self) -> [u8; HANDSHAKE_LENGTH] {
+        let mut data = [0u8; HANDSHAKE_LENGTH];
+
+        data[0] = PROTOCOL.len() as u8;
+        data[1..20].copy_from_slice(PROTOCOL);
+        data[20..28].copy_from_slice(&self.reserved.0);
+        data[28..48].copy_from_slice(&self.info_hash);
+        data[48..68].copy_from_slice(&self.peer_id);
+
+        data
+    }
+
+    pub fn decode(data: &[u8; HANDSHAKE_LENGTH]) -> io::Result<Self> {
+        if data[0] as usize != PROTOCOL.len() || &data[1..20] != PROTOCOL {
+            return Err(invalid_data("Peer doesn't speak the BitTorrent protocol"));
+        }
+
+        Ok(Self {
+            reserved: ReservedBits(data[20..28].try_into().unwrap()),
+            info_hash: data[28..48].try_into().unwrap(),
+            peer_id: data[48..68].try_into().unwrap(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Handshake, ReservedBits};
+
+    #[test]
+    fn encodes_and_decodes_handshake() {
+        let handshake = Handshake {
+            reserved: ReservedBits::supported(),
+            info_hash: [1; 20],
+            peer_id: [2; 20],
+        };
+
+        let encoded = handshake.encode();
+
+        assert_eq!(encoded[25], 0x10);
+        assert_eq!(encoded[27], 0x05);
+        assert_eq!(Handshake::decode(&encoded).unwrap(), handshake);
+
+        let mut invalid = encoded;
+        invalid[3] = b'X';
+
+        assert!(Handshake::decode(&invalid).is_err());
+    }
+
+    #[test]
+    fn negotiates_common_extensions() {
+        let remote = ReservedBits::default().with_dht().with_extension_protocol();
+        let common = ReservedBits::supported().intersection(&remote);
+
+        assert!(common.supports_extension_protocol());
+        assert!(common.supports_dht());
+        assert!(!common.supports_fast());
+    }
+}
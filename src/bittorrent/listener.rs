@@ -0,0 +1,310 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::{
+    io::AsyncReadExt,
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, OwnedSemaphorePermit, Semaphore},
+    task::JoinHandle,
+    time::timeout,
+};
+
+use crate::bittorrent::handshake::{Handshake, HANDSHAKE_LENGTH};
+
+/// Holds a connection slot, both globally and for the torrent. The slot is freed when this is
+/// dropped, so it should live as long as the connection
+#[derive(Debug)]
+pub struct ConnectionPermit {
+    _global: OwnedSemaphorePermit,
+    _torrent: OwnedSemaphorePermit,
+}
+
+/// A peer that connected to us and sent a handshake for one of our torrents. Our handshake reply
+/// still has to be sent, see `PeerClient::accept`
+#[derive(Debug)]
+pub struct IncomingConnection {
+    pub stream: TcpStream,
+    pub addr: SocketAddr,
+    pub handshake: Handshake,
+    pub permit: ConnectionPermit,
+}
+
+#[derive(Debug)]
+struct Torrent {
+    connections: Arc<Semaphore>,
+    sender: mpsc::Sender<IncomingConnection>,
+}
+
+/// Connection caps shared by incoming and outgoing connections
+#[derive(Debug, Clone)]
+pub struct ConnectionLimits {
+    global: Arc<Semaphore>,
+    torrents: Arc<Mutex<HashMap<[u8; 20], Torrent>>>,
+}
+
+impl ConnectionLimits {
+    pub fn new(max_connections: usize) -> Self {
+        Self {
+            global: Arc::new(Semaphore::new(max_connections)),
+            torrents: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Takes a connection slot for the torrent. `None` if either limit is reached or the torrent
+    /// isn't registered
+    pub fn try_acquire(&self, info_hash: &[u8; 20]) -> Option<ConnectionPermit> {
+        let torrent = self.torrents.lock().unwrap().get(info_hash)?.connections.clone();
+
+        Some(ConnectionPermit {
+            _global: self.global.clone().try_acquire_owned().ok()?,
+            _torrent: torrent.try_acquire_owned().ok()?,
+        })
+    }
+
+    pub fn available(&self) -> usize {
+        self.global.available_permits()
+    }
+}
+
+/// Accepts incoming peer connections on one port for every torrent.
+///
+/// The peer speaks first, so the handshake is read before anything else to find out which
+/// torrent it's after. Connections for unknown torrents or beyond the connection limits are
+/// closed right away.
+#[derive(Debug)]
+pub struct PeerListener {
+    local_addr: SocketAddr,
+    limits: ConnectionLimits,
+    accept_task: JoinHandle<()>,
+}
+
+impl Drop for PeerListener {
+    fn drop(&mut self) {
+        self.accept_task.abort();
+    }
+}
+
+impl PeerListener {
+    /// How long a peer gets to send its handshake after connecting
+    const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+    /// Handshaked connections waiting for their torrent to pick them up
+    const QUEUE_SIZE: usize = 16;
+
+    /// Connections still sending their handshake, any beyond this are closed right away
+    const MAX_PENDING_HANDSHAKES: usize = 64;
+
+    /// Accepting usually fails for running out of file descriptors, which takes a while to clear
+    const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(500);
+
+    /// Binding an unspecified IPv6 address (`[::]`) accepts IPv4 peers too
+    pub async fn bind(addr: SocketAddr, limits: ConnectionLimits) -> io::Result<Self> {
+        let listener = if addr.is_ipv6() && addr.ip().is_unspecified() {
+            Self::bind_dual_stack(addr)?
+        } else {
+            TcpListener::bind(addr).await?
+        };
+
+        let local_addr = listener.local_addr()?;
+
+        let accept_task = tokio::spawn(Self::accept_loop(listener, limits.clone()));
+
+        Ok(Self { local_addr, limits, accept_task })
+    }
+
+    fn bind_dual_stack(addr: SocketAddr) -> io::Result<TcpListener> {
+        let socket = Socket::new(Domain::IPV6, Type::STREAM, Some(Protocol::TCP))?;
+
+        socket.set_only_v6(false)?;
+        socket.set_reuse_address(true)?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&addr.into())?;
+        socket.listen(1024)?;
+
+        TcpListener::from_std(socket.into())
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub fn limits(&self) -> &ConnectionLimits {
+        &self.limits
+    }
+
+    /// Starts accepting peers for `info_hash`, at most `max_connections` at a time including
+    /// outgoing ones. Registering a torrent again replaces the previous registration
+    pub fn register(&self, info_hash: [u8; 20], max_connections: usize) -> mpsc::Receiver<IncomingConnection> {
+        let (sender, receiver) = mpsc::channel(Self::QUEUE_SIZE);
+
+        let torrent = Torrent {
+            connections: Arc::new(Semaphore::new(max_connections)),
+            sender,
+        };
+
+        self.limits.torrents.lock().unwrap().insert(info_hash, torrent);
+
+        receiver
+    }
+
+    pub fn unregister(&self, info_hash: &[u8; 20]) {
+        self.limits.torrents.lock().unwrap().remove(info_hash);
+    }
+
+    async fn accept_loop(listener: TcpListener, limits: ConnectionLimits) {
+        let pending = Arc::new(Semaphore::new(Self::MAX_PENDING_HANDSHAKES));
+
+        loop {
+            let (stream, addr) = match listener.accept().await {
+                Ok(x) => x,
+                Err(err) => {
+                    println!("Failed to accept a peer: {}", err);
+                    tokio::time::sleep(Self::ACCEPT_ERROR_DELAY).await;
+
+                    continue;
+                },
+            };
+
+            let Ok(permit) = pending.clone().try_acquire_owned() else {
+                continue;
+            };
+
+            let addr = SocketAddr::new(addr.ip().to_canonical(), addr.port());
+
+            tokio::spawn(Self::route(stream, addr, limits.clone(), permit));
+        }
+    }
+
+    /// Reads the handshake and hands the connection to its torrent. Dropping the stream on any
+    /// failure closes the connection
+    async fn route(mut stream: TcpStream, addr: SocketAddr, limits: ConnectionLimits, _pending: OwnedSemaphorePermit) {
+        // Don't bother reading handshakes we'd have to reject anyway
+        if limits.available() == 0 {
+            return;
+        }
+
+        let mut buf = [0u8; HANDSHAKE_LENGTH];
+
+        let Ok(Ok(_)) = timeout(Self::HANDSHAKE_TIMEOUT, stream.read_exact(&mut buf)).await else {
+            return;
+        };
+
+        let Ok(handshake) = Handshake::decode(&buf) else {
+            return;
+        };
+
+        let Some(permit) = limits.try_acquire(&handshake.info_hash) else {
+            return;
+        };
+
+        let sender = limits.torrents
+            .lock()
+            .unwrap()
+            .get(&handshake.info_hash)
+            .map(|x| x.sender.clone());
+
+        if let Some(sender) = sender {
+            let _ = sender.try_send(IncomingConnection { stream, addr, handshake, permit });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpStream};
+
+    use crate::bittorrent::{
+        handshake::{Handshake, ReservedBits, HANDSHAKE_LENGTH},
+        peer_client::PeerClient,
+    };
+
+    use super::{ConnectionLimits, PeerListener};
+
+    async fn connect(listener: &PeerListener, info_hash: [u8; 20]) -> TcpStream {
+        let mut stream = TcpStream::connect(listener.local_addr()).await.unwrap();
+
+        let handshake = Handshake {
+            reserved: ReservedBits::supported(),
+            info_hash,
+            peer_id: [9; 20],
+        };
+
+        stream.write_all(&handshake.encode()).await.unwrap();
+
+        stream
+    }
+
+    /// The listener closes rejected connections without sending anything
+    async fn assert_closed(stream: &mut TcpStream) {
+        let mut buf = [0u8; 1];
+        let read = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf)).await.unwrap();
+
+        assert!(matches!(read, Ok(0) | Err(_)));
+    }
+
+    #[tokio::test]
+    async fn routes_by_info_hash_and_enforces_limits() {
+        let listener = PeerListener::bind("127.0.0.1:0".parse().unwrap(), ConnectionLimits::new(2)).await.unwrap();
+
+        let mut first = listener.register([1; 20], 1);
+        let mut second = listener.register([2; 20], 5);
+
+        let mut a = connect(&listener, [1; 20]).await;
+        let incoming = tokio::time::timeout(Duration::from_secs(5), first.recv()).await.unwrap().unwrap();
+        assert_eq!(incoming.handshake.peer_id, [9; 20]);
+
+        let client = PeerClient::accept(&[7; 20], &incoming.addr, incoming.stream, incoming.handshake).await.unwrap();
+        let mut reply = [0u8; HANDSHAKE_LENGTH];
+        a.read_exact(&mut reply).await.unwrap();
+        assert_eq!(Handshake::decode(&reply).unwrap().info_hash, [1; 20]);
+        assert_eq!(Handshake::decode(&reply).unwrap().peer_id, [7; 20]);
+
+        // Over the torrent's limit
+        let mut rejected = connect(&listener, [1; 20]).await;
+        assert_closed(&mut rejected).await;
+
+        // Unknown torrent
+        let mut unknown = connect(&listener, [3; 20]).await;
+        assert_closed(&mut unknown).await;
+
+        let _b = connect(&listener, [2; 20]).await;
+        let held = tokio::time::timeout(Duration::from_secs(5), second.recv()).await.unwrap().unwrap();
+
+        // Over the global limit, both slots are taken
+        let mut rejected = connect(&listener, [2; 20]).await;
+        assert_closed(&mut rejected).await;
+
+        // Dropping a connection frees its slot
+        drop(held);
+        let _c = connect(&listener, [2; 20]).await;
+        assert!(tokio::time::timeout(Duration::from_secs(5), second.recv()).await.unwrap().is_some());
+
+        drop(client);
+        drop(incoming.permit);
+    }
+
+    #[tokio::test]
+    async fn dual_stack_accepts_ipv4_peers() {
+        let Ok(listener) = PeerListener::bind("[::]:0".parse().unwrap(), ConnectionLimits::new(2)).await else {
+            // No IPv6 in this environment
+            return;
+        };
+
+        let mut incoming = listener.register([1; 20], 1);
+
+        let mut stream = TcpStream::connect(("127.0.0.1", listener.local_addr().port())).await.unwrap();
+        let handshake = Handshake { reserved: ReservedBits::supported(), info_hash: [1; 20], peer_id: [9; 20] };
+        stream.write_all(&handshake.encode()).await.unwrap();
+
+        let connection = tokio::time::timeout(Duration::from_secs(5), incoming.recv()).await.unwrap().unwrap();
+        assert!(connection.addr.is_ipv4());
+    }
+}
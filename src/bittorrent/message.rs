@@ -0,0 +1,261 @@
+use std::io;
+
+/// Largest message we're willing to read. A piece message carrying a 16 KiB block is far below
+/// this, the limit mostly exists so a bitfield for a huge torrent still fits.
+pub const MAX_MESSAGE_LENGTH: usize = 2 * 1024 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerMessage {
+    KeepAlive,
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    Have { piece_index: u32 },
+    Bitfield(Vec<u8>),
+    Request { index: u32, begin: u32, length: u32 },
+    Piece { index: u32, begin: u32, block: Vec<u8> },
+    Cancel { index: u32, begin: u32, length: u32 },
+    Port(u16),
+
+    // BEP 6 Fast extension. Only sent when both sides set the fast bit in the handshake
+    SuggestPiece { piece_index: u32 },
+    HaveAll,
+    HaveNone,
+    RejectRequest { index: u32, begin: u32, length: u32 },
+    AllowedFast { piece_index: u32 },
+
+    /// BEP 10 extended message. Id 0 is the extended handshake, the rest are negotiated in it
+    Extended { id: u8, payload: Vec<u8> },
+}
+
+impl PeerMessage {
+    const CHOKE: u8 = 0;
+    const UNCHOKE: u8 = 1;
+    const INTERESTED: u8 = 2;
+    const NOT_INTERESTED: u8 = 3;
+    const HAVE: u8 = 4;
+    const BITFIELD: u8 = 5;
+    const REQUEST: u8 = 6;
+    const PIECE: u8 = 7;
+    const CANCEL: u8 = 8;
+    const PORT: u8 = 9;
+    const SUGGEST_PIECE: u8 = 0x0D;
+    const HAVE_ALL: u8 = 0x0E;
+    const HAVE_NONE: u8 = 0x0F;
+    const REJECT_REQUEST: u8 = 0x10;
+    const ALLOWED_FAST: u8 = 0x11;
+    const EXTENDED: u8 = 20;
+
+    /// Serializes the message including its 4 byte length prefix
+    pub fn encode(&self) -> Vec<u8> {
+        let mut payload: Vec<u8> = vec![];
+
+        match self {
+            Self::KeepAlive => {},
+            Self::Choke => payload.push(Self::CHOKE),
+            Self::Unchoke => payload.push(Self::UNCHOKE),
+            Self::Interested => payload.push(Self::INTERESTED),
+            Self::NotInterested => payload.push(Self::NOT_INTERESTED),
+            Self::Have { piece_index } => {
+                payload.push(Self::HAVE);
+                payload.extend_from_slice(&piece_index.to_be_bytes());
+            },
+            Self::Bitfield(bitfield) => {
+                payload.push(Self::BITFIELD);
+                payload.extend_from_slice(bitfield);
+            },
+            Self::Request { index, begin, length } => {
+                payload.push(Self::REQUEST);
+                write_block_ref(&mut payload, *index, *begin, *length);
+            },
+            Self::Piece { index, begin, block } => {
+                payload.push(Self::PIECE);
+                payload.extend_from_slice(&index.to_be_bytes());
+                payload.extend_from_slice(&begin.to_be_bytes());
+                payload.extend_from_slice(block);
+            },
+            Self::Cancel { index, begin, length } => {
+                payload.push(Self::CANCEL);
+                write_block_ref(&mut payload, *index, *begin, *length);
+            },
+            Self::Port(port) => {
+                payload.push(Self::PORT);
+                payload.extend_from_slice(&port.to_be_bytes());
+            },
+            Self::SuggestPiece { piece_index } => {
+                payload.push(Self::SUGGEST_PIECE);
+                payload.extend_from_slice(&piece_index.to_be_bytes());
+            },
+            Self::HaveAll => payload.push(Self::HAVE_ALL),
+            Self::HaveNone => payload.push(Self::HAVE_NONE),
+            Self::RejectRequest { index, begin, length } => {
+                payload.push(Self::REJECT_REQUEST);
+                write_block_ref(&mut payload, *index, *begin, *length);
+            },
+            Self::AllowedFast { piece_index } => {
+                payload.push(Self::ALLOWED_FAST);
+                payload.extend_from_slice(&piece_index.to_be_bytes());
+            },
+            Self::Extended { id, payload: extended_payload } => {
+                payload.push(Self::EXTENDED);
+                payload.push(*id);
+                payload.extend_from_slice(extended_payload);
+            },
+        }
+
+        let mut data = Vec::with_capacity(payload.len() + 4);
+
+        data.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        data.extend(payload);
+
+        data
+    }
+
+    /// Parses a message payload (everything after the length prefix)
+    pub fn decode(payload: &[u8]) -> io::Result<Self> {
+        let Some((id, body)) = payload.split_first() else {
+            return Ok(Self::KeepAlive);
+        };
+
+        let message = match *id {
+            Self::CHOKE => expect_empty(body, Self::Choke)?,
+            Self::UNCHOKE => expect_empty(body, Self::Unchoke)?,
+            Self::INTERESTED => expect_empty(body, Self::Interested)?,
+            Self::NOT_INTERESTED => expect_empty(body, Self::NotInterested)?,
+            Self::HAVE => Self::Have { piece_index: read_u32(body)? },
+            Self::BITFIELD => Self::Bitfield(body.to_vec()),
+            Self::REQUEST => {
+                let (index, begin, length) = read_block_ref(body)?;
+
+                Self::Request { index, begin, length }
+            },
+            Self::PIECE => {
+                if body.len() < 8 {
+                    return Err(invalid_data("Piece message is too short"));
+                }
+
+                Self::Piece {
+                    index: read_u32(&body[0..4])?,
+                    begin: read_u32(&body[4..8])?,
+                    block: body[8..].to_vec(),
+                }
+            },
+            Self::CANCEL => {
+                let (index, begin, length) = read_block_ref(body)?;
+
+                Self::Cancel { index, begin, length }
+            },
+            Self::PORT => {
+                let port: [u8; 2] = body
+                    .try_into()
+                    .map_err(|_| invalid_data("Port message should be 2 bytes"))?;
+
+                Self::Port(u16::from_be_bytes(port))
+            },
+            Self::SUGGEST_PIECE => Self::SuggestPiece { piece_index: read_u32(body)? },
+            Self::HAVE_ALL => expect_empty(body, Self::HaveAll)?,
+            Self::HAVE_NONE => expect_empty(body, Self::HaveNone)?,
+            Self::REJECT_REQUEST => {
+                let (index, begin, length) = read_block_ref(body)?;
+
+                Self::RejectRequest { index, begin, length }
+            },
+            Self::ALLOWED_FAST => Self::AllowedFast { piece_index: read_u32(body)? },
+            Self::EXTENDED => {
+                let (id, extended_payload) = body
+                    .split_first()
+                    .ok_or_else(|| invalid_data("Extended message is missing its id"))?;
+
+                Self::Extended { id: *id, payload: extended_payload.to_vec() }
+            },
+            id => return Err(invalid_data(&format!("Unknown message id {}", id))),
+        };
+
+        Ok(message)
+    }
+}
+
+pub fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn expect_empty(body: &[u8], message: PeerMessage) -> io::Result<PeerMessage> {
+    if !body.is_empty() {
+        return Err(invalid_data("Unexpected message payload"));
+    }
+
+    Ok(message)
+}
+
+fn read_u32(bytes: &[u8]) -> io::Result<u32> {
+    let bytes: [u8; 4] = bytes
+        .try_into()
+        .map_err(|_| invalid_data("Unexpected message length"))?;
+
+    Ok(u32::from_be_bytes(bytes))
+}
+
+fn read_block_ref(body: &[u8]) -> io::Result<(u32, u32, u32)> {
+    if body.len() != 12 {
+        return Err(invalid_data("Unexpected message length"));
+    }
+
+    let values = body
+        .chunks_exact(4)
+        .map(|x| u32::from_be_bytes(x.try_into().unwrap()))
+        .collect::<Vec<u32>>();
+
+    Ok((values[0], values[1], values[2]))
+}
+
+fn write_block_ref(payload: &mut Vec<u8>, index: u32, begin: u32, length: u32) {
+    payload.extend_from_slice(&index.to_be_bytes());
+    payload.extend_from_slice(&begin.to_be_bytes());
+    payload.extend_from_slice(&length.to_be_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PeerMessage;
+
+    #[test]
+    fn encodes_and_decodes_messages() {
+        let messages = [
+            PeerMessage::KeepAlive,
+            PeerMessage::Choke,
+            PeerMessage::Unchoke,
+            PeerMessage::Interested,
+            PeerMessage::NotInterested,
+            PeerMessage::Have { piece_index: 42 },
+            PeerMessage::Bitfield(vec![0b1010_0000, 0xFF]),
+            PeerMessage::Request { index: 1, begin: 16_384, length: 16_384 },
+            PeerMessage::Piece { index: 1, begin: 0, block: vec![1, 2, 3] },
+            PeerMessage::Cancel { index: 1, begin: 16_384, length: 16_384 },
+            PeerMessage::Port(6881),
+            PeerMessage::SuggestPiece { piece_index: 7 },
+            PeerMessage::HaveAll,
+            PeerMessage::HaveNone,
+            PeerMessage::RejectRequest { index: 1, begin: 0, length: 16_384 },
+            PeerMessage::AllowedFast { piece_index: 9 },
+            PeerMessage::Extended { id: 0, payload: "de".as_bytes().to_vec() },
+        ];
+
+        for message in messages {
+            let encoded = message.encode();
+            let length = u32::from_be_bytes(encoded[0..4].try_into().unwrap()) as usize;
+
+            assert_eq!(length, encoded.len() - 4);
+            assert_eq!(PeerMessage::decode(&encoded[4..]).unwrap(), message);
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_messages() {
+        assert!(PeerMessage::decode(&[4, 0, 0]).is_err());
+        assert!(PeerMessage::decode(&[6, 0, 0, 0, 1]).is_err());
+        assert!(PeerMessage::decode(&[1, 0]).is_err());
+        assert!(PeerMessage::decode(&[7, 0, 0, 0]).is_err());
+        assert!(PeerMessage::decode(&[99]).is_err());
+    }
+}
@@ -1,2 +1,9 @@
 pub mod peer_client;
 pub mod extensions;
+pub mod message;
+pub mod bitfield;
+pub mod handshake;
+pub mod piece_picker;
+pub mod upload;
+pub mod listener;
+pub mod choker;
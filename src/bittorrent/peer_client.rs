@@ -1,15 +1,112 @@
-use std::{io, net::{Ipv4Addr, SocketAddr, SocketAddrV4}, time::Duration};
+use std::{collections::HashSet, io, net::SocketAddr, time::Duration};
 
 use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpStream, time::timeout};
 
+use crate::{
+    bittorrent::{
+        bitfield::Bitfield,
+        extensions::{ut_metadata::MetadataMessage, ExtendedHandshake, Extension, ExtensionRegistry, UTMetadata},
+        handshake::{Handshake, ReservedBits, HANDSHAKE_LENGTH},
+        message::{invalid_data, PeerMessage, MAX_MESSAGE_LENGTH},
+        piece_picker::BlockRequest,
+        upload::{RequestOutcome, UploadQueue},
+    },
+    net::rate_limit::Bandwidth,
+    storage::Storage,
+};
+
+/// Size of the blocks we request. Most clients refuse anything larger
+pub const BLOCK_SIZE: u32 = 16_384;
+
+/// How many block requests we keep in flight per peer
+const MAX_PIPELINED_REQUESTS: usize = 5;
+
+/// Peers are expected to send at least a keep-alive every two minutes
+const READ_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// `have` messages kept per peer while we don't know the piece count yet
+const MAX_PENDING_HAVES: usize = 4096;
+
+/// Choke/interest flags for both ends of the connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerState {
+    pub am_choking: bool,
+    pub am_interested: bool,
+    pub peer_choking: bool,
+    pub peer_interested: bool,
+}
+
+impl Default for PeerState {
+    /// Connections start out choked and not interested on both sides
+    fn default() -> Self {
+        Self {
+            am_choking: true,
+            am_interested: false,
+            peer_choking: true,
+            peer_interested: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BlockState {
+    Missing,
+    Requested,
+    Received,
+}
 
 #[derive(Debug)]
 pub struct PeerClient<'addr> {
     pub socket_addr: &'addr SocketAddr,
     pub infohash: [u8; 20],
     pub node_id: [u8; 20],
-    
+
+    pub state: PeerState,
+
+    /// Extensions we advertise in our handshake
+    pub reserved: ReservedBits,
+
+    /// The handshake the peer replied with, once the handshake is done
+    pub remote_handshake: Option<Handshake>,
+
+    /// Pieces the remote peer claims to have. The size is only a guess until the piece count is
+    /// known, since a `bitfield` message is padded to a full byte
+    pub peer_pieces: Bitfield,
+
+    /// Set once we have the metadata, `have` and `bitfield` messages are checked against it
+    piece_count: Option<usize>,
+
+    /// `have` messages that arrived before the piece count was known
+    pending_haves: HashSet<u32>,
+
+    /// Set by a `have_all` message (BEP 6), which doesn't tell us the piece count either
+    pub peer_has_all: bool,
+
+    /// BEP 10 message ids negotiated with the peer
+    pub extension_registry: ExtensionRegistry,
+
+    /// The peer's latest extended handshake
+    pub remote_extended_handshake: Option<ExtendedHandshake>,
+
+    /// The info dict, once we have it, for peers fetching it from us
+    local_metadata: Option<UTMetadata>,
+
+    /// Requests from the peer waiting to be served
+    pub uploads: UploadQueue,
+
+    /// Block bytes sent to the peer
+    pub uploaded: u64,
+
+    /// Limits applied to everything sent and received after the handshake. The limiters are
+    /// shared, so changing their rates applies to the connection right away
+    pub bandwidth: Bandwidth,
+
+    sent_extended_handshake: bool,
+
     stream: TcpStream,
+
+    /// Bytes read off the stream that don't make up a whole message yet
+    read_buf: Vec<u8>,
 }
 
 impl<'addr> PeerClient<'addr> {
@@ -20,45 +117,671 @@ impl<'addr> PeerClient<'addr> {
     ) -> io::Result<Self> {
         let stream = timeout(Duration::from_secs(3), TcpStream::connect(socket_addr)).await??;
 
-        Ok(Self {
-            infohash: infohash.clone(),
-            node_id: node_id.clone(),
+        Ok(Self::from_stream(node_id, socket_addr, infohash, stream))
+    }
+
+    /// Takes over a connection from `PeerListener`, which already read the peer's handshake, and
+    /// sends ours in reply
+    pub async fn accept(
+        node_id: &[u8; 20],
+        socket_addr: &'addr SocketAddr,
+        stream: TcpStream,
+        remote_handshake: Handshake,
+    ) -> io::Result<Self> {
+        if remote_handshake.peer_id == *node_id {
+            return Err(invalid_data("Connected to ourselves"));
+        }
+
+        let mut client = Self::from_stream(node_id, socket_addr, &remote_handshake.info_hash, stream);
+
+        let handshake = Handshake {
+            reserved: client.reserved,
+            info_hash: client.infohash,
+            peer_id: client.node_id,
+        };
+
+        client.stream.write_all(&handshake.encode()).await?;
+        client.remote_handshake = Some(remote_handshake);
+
+        Ok(client)
+    }
+
+    fn from_stream(node_id: &[u8; 20], socket_addr: &'addr SocketAddr, infohash: &[u8; 20], stream: TcpStream) -> Self {
+        Self {
+            infohash: *infohash,
+            node_id: *node_id,
             socket_addr,
+            state: PeerState::default(),
+            reserved: ReservedBits::supported(),
+            remote_handshake: None,
+            peer_pieces: Bitfield::new(0),
+            piece_count: None,
+            pending_haves: HashSet::new(),
+            peer_has_all: false,
+            extension_registry: ExtensionRegistry::default(),
+            remote_extended_handshake: None,
+            local_metadata: None,
+            uploads: UploadQueue::default(),
+            uploaded: 0,
+            bandwidth: Bandwidth::default(),
+            sent_extended_handshake: false,
             stream,
-        })
+            read_buf: vec![],
+        }
     }
 
-    pub async fn send_interested(&mut self) -> io::Result<()> {
-        self.stream.write_all(&[
-            0x00, 0x00, 0x00, 0x01, // Length
-            0x01,
-        ]).await?;
+    pub async fn send_message(&mut self, message: &PeerMessage) -> io::Result<()> {
+        let data = message.encode();
+
+        self.bandwidth.upload.acquire(data.len()).await;
+        self.stream.write_all(&data).await?;
+
+        match message {
+            PeerMessage::Choke => self.state.am_choking = true,
+            PeerMessage::Unchoke => self.state.am_choking = false,
+            PeerMessage::Interested => self.state.am_interested = true,
+            PeerMessage::NotInterested => self.state.am_interested = false,
+            _ => {},
+        }
+
+        Ok(())
+    }
+
+    /// Reads the next message off the wire and updates the connection state accordingly. Partial
+    /// messages stay buffered, so this is cancel safe and can be raced against other futures
+    pub async fn receive_message(&mut self) -> io::Result<PeerMessage> {
+        let mut buf = [0u8; 16_384];
+
+        loop {
+            if let Some(message) = self.take_buffered_message()? {
+                self.handle_message(&message)?;
+
+                return Ok(message);
+            }
+
+            let read = self.stream.read(&mut buf).await?;
+
+            if read == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Peer closed the connection"));
+            }
+
+            self.read_buf.extend_from_slice(&buf[..read]);
+
+            // Not reading for a while lets TCP flow control slow the peer down
+            self.bandwidth.download.acquire(read).await;
+        }
+    }
+
+    fn take_buffered_message(&mut self) -> io::Result<Option<PeerMessage>> {
+        let Some(length_buf) = self.read_buf.get(0..4) else {
+            return Ok(None);
+        };
+
+        let length = u32::from_be_bytes(length_buf.try_into().unwrap()) as usize;
+
+        if length > MAX_MESSAGE_LENGTH {
+            return Err(invalid_data(&format!("Message length {} exceeds the limit", length)));
+        }
+
+        if self.read_buf.len() < 4 + length {
+            return Ok(None);
+        }
+
+        let message = PeerMessage::decode(&self.read_buf[4..4 + length]);
+        self.read_buf.drain(..4 + length);
+
+        message.map(Some)
+    }
+
+    fn handle_message(&mut self, message: &PeerMessage) -> io::Result<()> {
+        match message {
+            PeerMessage::Choke => self.state.peer_choking = true,
+            PeerMessage::Unchoke => self.state.peer_choking = false,
+            PeerMessage::Interested => self.state.peer_interested = true,
+            PeerMessage::NotInterested => self.state.peer_interested = false,
+            PeerMessage::Have { piece_index } => match self.piece_count {
+                Some(count) if *piece_index as usize >= count => {
+                    return Err(invalid_data("Peer has a piece that doesn't exist"));
+                },
+                Some(_) => self.peer_pieces.set(*piece_index as usize),
+                None => {
+                    // Peers normally send a bitfield instead, there's no need to keep more
+                    if self.pending_haves.len() < MAX_PENDING_HAVES {
+                        self.pending_haves.insert(*piece_index);
+                    }
+                },
+            },
+            PeerMessage::Bitfield(bytes) => {
+                let mut bitfield = Bitfield::from_bytes(bytes, bytes.len() * 8).unwrap();
+
+                if let Some(count) = self.piece_count {
+                    if bytes.len() != count.div_ceil(8) {
+                        return Err(invalid_data("Bitfield doesn't match the piece count"));
+                    }
+
+                    bitfield.resize(count);
+                }
+
+                self.peer_pieces = bitfield;
+            },
+            PeerMessage::HaveAll => self.peer_has_all = true,
+            PeerMessage::HaveNone => {
+                self.peer_has_all = false;
+                self.peer_pieces = Bitfield::new(self.piece_count.unwrap_or(0));
+                self.pending_haves.clear();
+            },
+            PeerMessage::Extended { id: 0, payload } => {
+                // A broken extended handshake just means we can't use extensions with this peer
+                if let Ok(handshake) = ExtendedHandshake::decode(payload) {
+                    self.extension_registry.apply_remote(&handshake);
+                    self.remote_extended_handshake = Some(handshake);
+                }
+            },
+            _ => {},
+        }
+
+        Ok(())
+    }
+
+    /// Sizes the peer's bitfield now that the metadata is known and applies the `have` messages
+    /// that came in before. Fails if the peer claimed pieces past the end
+    pub fn set_piece_count(&mut self, count: usize) -> io::Result<()> {
+        self.piece_count = Some(count);
+        self.peer_pieces.resize(count);
+
+        for index in std::mem::take(&mut self.pending_haves) {
+            if index as usize >= count {
+                return Err(invalid_data("Peer has a piece that doesn't exist"));
+            }
+
+            self.peer_pieces.set(index as usize);
+        }
 
         Ok(())
     }
 
-    pub async fn request(&mut self) -> io::Result<()> {
+    pub fn peer_has_piece(&self, index: usize) -> bool {
+        self.peer_has_all || self.peer_pieces.has(index) || self.pending_haves.contains(&(index as u32))
+    }
+
+    /// Extensions that both sides advertised. Empty until the handshake is done
+    pub fn extensions(&self) -> ReservedBits {
+        self.remote_handshake
+            .as_ref()
+            .map(|x| self.reserved.intersection(&x.reserved))
+            .unwrap_or_default()
+    }
+
+    pub async fn send_extended_handshake(&mut self, metadata_size: Option<usize>) -> io::Result<()> {
+        let payload = self.extension_registry
+            .local_handshake(metadata_size)
+            .encode();
+
+        self.send_message(&PeerMessage::Extended { id: 0, payload }).await?;
+        self.sent_extended_handshake = true;
+
         Ok(())
     }
 
-    pub async fn send_handshake(&mut self) -> io::Result<()> {
-        let mut handshake_data: Vec<u8> = vec![];
+    /// Downloads the info dict from the peer using ut_metadata (BEP 9) and verifies it against the
+    /// info-hash. This is what turns a magnet link into a usable torrent.
+    pub async fn fetch_metadata(&mut self) -> io::Result<Vec<u8>> {
+        let unsupported = || io::Error::new(io::ErrorKind::Unsupported, "Peer doesn't support ut_metadata");
+
+        if !self.extensions().supports_extension_protocol() {
+            return Err(unsupported());
+        }
+
+        if !self.sent_extended_handshake {
+            self.send_extended_handshake(None).await?;
+        }
+
+        while self.remote_extended_handshake.is_none() {
+            timeout(READ_TIMEOUT, self.receive_message())
+                .await
+                .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "Peer stopped responding"))??;
+        }
+
+        let remote_id = self.extension_registry
+            .remote_id(UTMetadata::NAME)
+            .ok_or_else(unsupported)?;
+
+        let metadata_size = self.remote_extended_handshake
+            .as_ref()
+            .and_then(|x| x.metadata_size)
+            .ok_or_else(unsupported)?;
+
+        let mut metadata = UTMetadata::new(&self.infohash);
+        metadata.set_total_size(metadata_size)?;
+
+        // Metadata is small enough that every piece can be requested up front
+        for piece in 0..metadata.piece_count() {
+            let payload = metadata.get_request_message(piece);
+
+            self.send_message(&PeerMessage::Extended { id: remote_id, payload }).await?;
+        }
+
+        while !metadata.is_complete() {
+            let message = timeout(READ_TIMEOUT, self.receive_message())
+                .await
+                .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "Peer stopped responding"))??;
+
+            if let PeerMessage::Extended { id, payload } = message {
+                if self.extension_registry.local_name(id) != Some(UTMetadata::NAME) {
+                    continue;
+                }
+
+                if let Some(reply) = metadata.process_packet(&payload)? {
+                    self.send_message(&PeerMessage::Extended { id: remote_id, payload: reply }).await?;
+                }
+            }
+        }
+
+        metadata.verified_metadata()
+    }
+
+    pub fn set_local_metadata(&mut self, metadata: Vec<u8>) {
+        self.local_metadata = Some(UTMetadata::with_local_metadata(&self.infohash, metadata));
+    }
 
-        handshake_data.push(19);
-        handshake_data.extend_from_slice("BitTorrent protocol".as_bytes());
-        handshake_data.extend_from_slice(&[0u8; 8]);
-        handshake_data.extend_from_slice(&self.infohash);
-        handshake_data.extend_from_slice(&self.node_id);
+    /// Answers the peer's ut_metadata requests. Anything else on that extension is left over
+    /// from fetching the metadata ourselves and ignored
+    pub async fn serve_metadata(&mut self, id: u8, payload: &[u8]) -> io::Result<()> {
+        if self.extension_registry.local_name(id) != Some(UTMetadata::NAME) {
+            return Ok(());
+        }
 
-        self.stream.write_all(&handshake_data).await?;
-        println!("HANDSHAKE DATA: {:?}", handshake_data);
+        let (Some(metadata), Some(remote_id)) = (self.local_metadata.as_mut(), self.extension_registry.remote_id(UTMetadata::NAME)) else {
+            return Ok(());
+        };
 
-        let mut buf = [0u8; 68];
-        // let mut buf = Vec::new();
-        let resp = self.stream.read_exact(&mut buf).await?;
+        if !matches!(MetadataMessage::decode(payload), Ok(MetadataMessage::Request { .. })) {
+            return Ok(());
+        }
 
-        println!("Received TCP resp: {:?} {:?}", buf, resp);
+        if let Some(reply) = metadata.process_packet(payload)? {
+            self.send_message(&PeerMessage::Extended { id: remote_id, payload: reply }).await?;
+        }
 
         Ok(())
     }
+
+    pub async fn send_interested(&mut self) -> io::Result<()> {
+        self.send_message(&PeerMessage::Interested).await
+    }
+
+    pub async fn request(&mut self, index: u32, begin: u32, length: u32) -> io::Result<()> {
+        self.send_message(&PeerMessage::Request { index, begin, length }).await
+    }
+
+    /// Downloads a whole piece by pipelining block requests. Requests that are dropped because the
+    /// peer choked us are sent again once we're unchoked. The data isn't verified here.
+    pub async fn download_piece(&mut self, index: u32, piece_size: u32) -> io::Result<Vec<u8>> {
+        if !self.state.am_interested {
+            self.send_interested().await?;
+        }
+
+        let block_count = piece_size.div_ceil(BLOCK_SIZE) as usize;
+        let mut blocks = vec![BlockState::Missing; block_count];
+        let mut piece = vec![0u8; piece_size as usize];
+
+        while blocks.iter().any(|x| *x != BlockState::Received) {
+            if !self.state.peer_choking {
+                let in_flight = blocks.iter().filter(|x| **x == BlockState::Requested).count();
+
+                let to_request = blocks
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, x)| **x == BlockState::Missing)
+                    .map(|(i, _)| i)
+                    .take(MAX_PIPELINED_REQUESTS.saturating_sub(in_flight))
+                    .collect::<Vec<usize>>();
+
+                for block_index in to_request {
+                    let begin = block_index as u32 * BLOCK_SIZE;
+                    let length = BLOCK_SIZE.min(piece_size - begin);
+
+                    self.request(index, begin, length).await?;
+                    blocks[block_index] = BlockState::Requested;
+                }
+            }
+
+            let message = timeout(READ_TIMEOUT, self.receive_message())
+                .await
+                .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "Peer stopped responding"))??;
+
+            match message {
+                PeerMessage::Piece { index: piece_index, begin, block } if piece_index == index => {
+                    let block_index = (begin / BLOCK_SIZE) as usize;
+                    let expected_length = BLOCK_SIZE.min(piece_size.saturating_sub(begin)) as usize;
+
+                    if begin % BLOCK_SIZE != 0 || block_index >= block_count || block.len() != expected_length {
+                        return Err(invalid_data("Received a block we didn't ask for"));
+                    }
+
+                    if blocks[block_index] != BlockState::Received {
+                        piece[begin as usize..begin as usize + block.len()].copy_from_slice(&block);
+                        blocks[block_index] = BlockState::Received;
+                    }
+                },
+                // With the fast extension every dropped request is explicitly rejected instead
+                PeerMessage::RejectRequest { index: piece_index, begin, .. } if piece_index == index => {
+                    let block_index = (begin / BLOCK_SIZE) as usize;
+
+                    if let Some(block) = blocks.get_mut(block_index).filter(|x| **x == BlockState::Requested) {
+                        *block = BlockState::Missing;
+                    }
+                },
+                // Choking discards all pending requests on the remote end
+                PeerMessage::Choke if !self.extensions().supports_fast() => {
+                    for block in blocks.iter_mut().filter(|x| **x == BlockState::Requested) {
+                        *block = BlockState::Missing;
+                    }
+                },
+                _ => {},
+            }
+        }
+
+        Ok(piece)
+    }
+
+    /// Tells the peer which pieces we have, right after the handshake. With the fast extension a
+    /// complete or empty bitfield is sent as `have_all` or `have_none`, without it an empty
+    /// bitfield is simply left out
+    pub async fn send_pieces(&mut self, have: &Bitfield) -> io::Result<()> {
+        let fast = self.extensions().supports_fast();
+
+        if fast && have.is_complete() {
+            self.send_message(&PeerMessage::HaveAll).await
+        } else if have.count() == 0 {
+            if fast {
+                self.send_message(&PeerMessage::HaveNone).await?;
+            }
+
+            Ok(())
+        } else {
+            self.send_message(&PeerMessage::Bitfield(have.as_bytes().to_vec())).await
+        }
+    }
+
+    pub async fn send_have(&mut self, index: u32) -> io::Result<()> {
+        self.send_message(&PeerMessage::Have { piece_index: index }).await
+    }
+
+    /// Queues or cancels uploads for `request` and `cancel` messages, other messages are ignored.
+    /// Requests we won't serve are rejected if the peer supports the fast extension.
+    pub async fn handle_upload_message(&mut self, message: &PeerMessage, have: &Bitfield, storage: &Storage) -> io::Result<()> {
+        match *message {
+            PeerMessage::Request { index, begin, length } => {
+                let request = BlockRequest { index, begin, length };
+                let piece_size = storage.piece_size(index);
+
+                let outcome = self.uploads.push(request, have, self.state.am_choking, piece_size);
+
+                if outcome == RequestOutcome::Rejected && self.extensions().supports_fast() {
+                    self.send_message(&request.reject_message()).await?;
+                }
+            },
+            PeerMessage::Cancel { index, begin, length } => {
+                self.uploads.cancel(&BlockRequest { index, begin, length });
+            },
+            _ => {},
+        }
+
+        Ok(())
+    }
+
+    /// Sends the oldest queued block. Returns how many bytes were sent, `None` if nothing was
+    /// queued
+    pub async fn serve_request(&mut self, storage: &Storage) -> io::Result<Option<u32>> {
+        let Some(request) = self.uploads.pop() else {
+            return Ok(None);
+        };
+
+        let block = storage.read_block(request.index, request.begin, request.length).await?;
+
+        self.send_message(&PeerMessage::Piece { index: request.index, begin: request.begin, block }).await?;
+        self.uploaded += request.length as u64;
+
+        Ok(Some(request.length))
+    }
+
+    pub async fn unchoke(&mut self) -> io::Result<()> {
+        self.send_message(&PeerMessage::Unchoke).await
+    }
+
+    /// Choking drops every queued request. Peers with the fast extension expect each one to be
+    /// rejected explicitly
+    pub async fn choke(&mut self) -> io::Result<()> {
+        self.send_message(&PeerMessage::Choke).await?;
+
+        let dropped = self.uploads.clear();
+
+        if self.extensions().supports_fast() {
+            for request in dropped {
+                self.send_message(&request.reject_message()).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Sends our handshake and validates the reply. Fails if the peer is serving a different
+    /// torrent or if we somehow connected to ourselves.
+    pub async fn send_handshake(&mut self) -> io::Result<Handshake> {
+        let handshake = Handshake {
+            reserved: self.reserved,
+            info_hash: self.infohash,
+            peer_id: self.node_id,
+        };
+
+        self.stream.write_all(&handshake.encode()).await?;
+
+        let mut buf = [0u8; HANDSHAKE_LENGTH];
+        timeout(Duration::from_secs(10), self.stream.read_exact(&mut buf)).await??;
+
+        let remote_handshake = Handshake::decode(&buf)?;
+
+        if remote_handshake.info_hash != self.infohash {
+            return Err(invalid_data("Peer replied with a different info-hash"));
+        }
+
+        if remote_handshake.peer_id == self.node_id {
+            return Err(invalid_data("Connected to ourselves"));
+        }
+
+        self.remote_handshake = Some(remote_handshake.clone());
+
+        Ok(remote_handshake)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpListener};
+
+    use crate::{
+        bittorrent::{bitfield::Bitfield, handshake::{Handshake, ReservedBits, HANDSHAKE_LENGTH}, message::PeerMessage},
+        metainfo::{FileLayout, Info},
+        storage::Storage,
+        utils::testing::TempDir,
+    };
+
+    use super::{PeerClient, BLOCK_SIZE};
+
+    async fn read_message(stream: &mut tokio::net::TcpStream) -> PeerMessage {
+        let length = stream.read_u32().await.unwrap() as usize;
+        let mut payload = vec![0u8; length];
+        stream.read_exact(&mut payload).await.unwrap();
+
+        PeerMessage::decode(&payload).unwrap()
+    }
+
+    #[tokio::test]
+    async fn downloads_piece_and_survives_choke() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let piece_size = BLOCK_SIZE * 2 + 100;
+        let data = (0..piece_size).map(|x| x as u8).collect::<Vec<u8>>();
+        let served = data.clone();
+
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+
+            assert_eq!(read_message(&mut stream).await, PeerMessage::Interested);
+            stream.write_all(&PeerMessage::Unchoke.encode()).await.unwrap();
+
+            // Drop the first batch of requests by choking, then serve the retried ones
+            for _ in 0..3 {
+                read_message(&mut stream).await;
+            }
+            stream.write_all(&PeerMessage::Choke.encode()).await.unwrap();
+            stream.write_all(&PeerMessage::Unchoke.encode()).await.unwrap();
+
+            for _ in 0..3 {
+                let PeerMessage::Request { index, begin, length } = read_message(&mut stream).await else {
+                    panic!("Expected a request");
+                };
+
+                let block = served[begin as usize..(begin + length) as usize].to_vec();
+                stream.write_all(&PeerMessage::Piece { index, begin, block }.encode()).await.unwrap();
+            }
+        });
+
+        let mut client = PeerClient::connect(&[1; 20], &addr, &[2; 20]).await.unwrap();
+        let piece = client.download_piece(3, piece_size).await.unwrap();
+
+        assert_eq!(piece, data);
+        assert!(client.state.am_interested);
+        assert!(!client.state.peer_choking);
+    }
+
+    #[tokio::test]
+    async fn bounds_have_messages_by_piece_count() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+
+            for message in [
+                PeerMessage::Have { piece_index: 3 },
+                PeerMessage::Have { piece_index: 9 },
+                PeerMessage::Have { piece_index: u32::MAX },
+            ] {
+                stream.write_all(&message.encode()).await.unwrap();
+            }
+
+            // Keep the connection open until the client is done
+            let _ = stream.read_u8().await;
+        });
+
+        let mut client = PeerClient::connect(&[1; 20], &addr, &[2; 20]).await.unwrap();
+
+        // Before the metadata is known the indices are only remembered
+        for _ in 0..2 {
+            client.receive_message().await.unwrap();
+        }
+
+        assert!(client.peer_has_piece(9));
+        assert_eq!(client.peer_pieces.len(), 0);
+
+        client.set_piece_count(10).unwrap();
+        assert_eq!(client.peer_pieces.len(), 10);
+        assert!(client.peer_pieces.has(3) && client.peer_pieces.has(9));
+
+        assert!(client.receive_message().await.is_err());
+        assert_eq!(client.peer_pieces.len(), 10);
+    }
+
+    #[tokio::test]
+    async fn serves_requested_blocks() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let dir = TempDir::new("peer-seed");
+        let info = Info {
+            name: "seed".to_owned(),
+            piece_length: 2 * BLOCK_SIZE as u64,
+            pieces: vec![[0; 20], [0; 20]],
+            private: false,
+            layout: FileLayout::Single { length: 2 * BLOCK_SIZE as u64 + 100 },
+        };
+
+        let data = (0..2 * BLOCK_SIZE).map(|x| (x % 251) as u8).collect::<Vec<u8>>();
+        let storage = Storage::new(&info, &dir);
+        storage.write_block(0, 0, data.clone()).await.unwrap();
+
+        let leecher = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+
+            for message in [
+                PeerMessage::Interested,
+                PeerMessage::Request { index: 0, begin: BLOCK_SIZE, length: BLOCK_SIZE },
+                PeerMessage::Request { index: 0, begin: 0, length: BLOCK_SIZE },
+                PeerMessage::Cancel { index: 0, begin: 0, length: BLOCK_SIZE },
+                PeerMessage::Request { index: 1, begin: 0, length: 100 },
+            ] {
+                stream.write_all(&message.encode()).await.unwrap();
+            }
+
+            let mut received = vec![];
+
+            for _ in 0..3 {
+                received.push(read_message(&mut stream).await);
+            }
+
+            received
+        });
+
+        let mut have = Bitfield::new(2);
+        have.set(0);
+
+        let mut client = PeerClient::connect(&[1; 20], &addr, &[2; 20]).await.unwrap();
+        client.send_pieces(&have).await.unwrap();
+        client.unchoke().await.unwrap();
+
+        for _ in 0..5 {
+            let message = client.receive_message().await.unwrap();
+            client.handle_upload_message(&message, &have, &storage).await.unwrap();
+        }
+
+        assert_eq!(client.serve_request(&storage).await.unwrap(), Some(BLOCK_SIZE));
+        assert_eq!(client.serve_request(&storage).await.unwrap(), None);
+        assert_eq!(client.uploaded, BLOCK_SIZE as u64);
+
+        // The cancelled and the unavailable block are never sent
+        assert_eq!(leecher.await.unwrap(), vec![
+            PeerMessage::Bitfield(vec![0x80]),
+            PeerMessage::Unchoke,
+            PeerMessage::Piece { index: 0, begin: BLOCK_SIZE, block: data[BLOCK_SIZE as usize..].to_vec() },
+        ]);
+    }
+
+    #[tokio::test]
+    async fn rejects_handshake_for_other_torrent() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+
+            let mut buf = [0u8; HANDSHAKE_LENGTH];
+            stream.read_exact(&mut buf).await.unwrap();
+
+            let reply = Handshake {
+                reserved: ReservedBits::default().with_fast(),
+                info_hash: [9; 20],
+                peer_id: [3; 20],
+            };
+
+            stream.write_all(&reply.encode()).await.unwrap();
+        });
+
+        let mut client = PeerClient::connect(&[1; 20], &addr, &[2; 20]).await.unwrap();
+
+        assert!(client.send_handshake().await.is_err());
+        assert!(client.remote_handshake.is_none());
+    }
 }
@@ -0,0 +1,605 @@
+use std::{cmp::Reverse, collections::HashMap, net::SocketAddr};
+
+use rand::seq::SliceRandom;
+
+use crate::{
+    bittorrent::{bitfield::Bitfield, message::PeerMessage, peer_client::BLOCK_SIZE},
+    metainfo::{FileEntry, Info},
+};
+
+/// A single block request, as sent in `request` and `cancel` messages
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BlockRequest {
+    pub index: u32,
+    pub begin: u32,
+    pub length: u32,
+}
+
+impl BlockRequest {
+    pub fn request_message(&self) -> PeerMessage {
+        PeerMessage::Request { index: self.index, begin: self.begin, length: self.length }
+    }
+
+    pub fn cancel_message(&self) -> PeerMessage {
+        PeerMessage::Cancel { index: self.index, begin: self.begin, length: self.length }
+    }
+
+    pub fn reject_message(&self) -> PeerMessage {
+        PeerMessage::RejectRequest { index: self.index, begin: self.begin, length: self.length }
+    }
+}
+
+/// Download priority of a file or piece. `Skip` pieces are never requested
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum Priority {
+    Skip,
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum BlockState {
+    Missing,
+
+    /// Requested from these peers. More than one only happens in endgame mode
+    Requested(Vec<SocketAddr>),
+    Received,
+}
+
+/// What happened after a block arrived
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct BlockOutcome {
+    /// Requests for the same block still pending with other peers, which should be cancelled
+    pub cancels: Vec<(SocketAddr, BlockRequest)>,
+
+    /// Every block of the piece is in and it's ready to be hash checked
+    pub piece_complete: bool,
+
+    /// False for blocks we already have or never asked for, which should just be dropped
+    pub accepted: bool,
+}
+
+/// Decides which blocks to request from which peer.
+///
+/// Pieces that were already started are finished first. New pieces are picked at random until we
+/// have `RANDOM_FIRST_PIECES` of them, so we quickly get something to share, and rarest first
+/// after that. Once every missing block has been requested the picker enters endgame mode and
+/// hands out blocks that are already pending with other peers, so a slow peer can't hold up the
+/// last pieces.
+#[derive(Debug)]
+pub struct PiecePicker {
+    piece_length: u64,
+    total_length: u64,
+
+    have: Bitfield,
+
+    /// Number of connected peers that have each piece
+    availability: Vec<u32>,
+    peers: HashMap<SocketAddr, Bitfield>,
+
+    /// Pieces with at least one block requested or received
+    partial: HashMap<u32, Vec<BlockState>>,
+    priorities: Vec<Priority>,
+    endgame: bool,
+}
+
+impl PiecePicker {
+    pub const RANDOM_FIRST_PIECES: usize = 4;
+
+    pub fn new(piece_length: u64, total_length: u64) -> Self {
+        let piece_count = total_length.div_ceil(piece_length) as usize;
+
+        Self {
+            piece_length,
+            total_length,
+            have: Bitfield::new(piece_count),
+            availability: vec![0; piece_count],
+            peers: HashMap::new(),
+            partial: HashMap::new(),
+            priorities: vec![Priority::default(); piece_count],
+            endgame: false,
+        }
+    }
+
+    pub fn from_info(info: &Info) -> Self {
+        Self::new(info.piece_length, info.total_length())
+    }
+
+    pub fn piece_count(&self) -> usize {
+        self.have.len()
+    }
+
+    pub fn piece_size(&self, index: u32) -> u32 {
+        let start = index as u64 * self.piece_length;
+        let size = self.piece_length.min(self.total_length.saturating_sub(start));
+
+        u32::try_from(size).expect("Piece lengths are bounded when parsing the metainfo")
+    }
+
+    fn block_count(&self, index: u32) -> usize {
+        self.piece_size(index).div_ceil(BLOCK_SIZE) as usize
+    }
+
+    fn block(&self, index: u32, block: usize) -> BlockRequest {
+        let begin = block as u32 * BLOCK_SIZE;
+
+        BlockRequest {
+            index,
+            begin,
+            length: BLOCK_SIZE.min(self.piece_size(index) - begin),
+        }
+    }
+
+    pub fn have(&self) -> &Bitfield {
+        &self.have
+    }
+
+    /// Replaces the pieces we have, e.g. after checking the files on disk
+    pub fn set_have(&mut self, have: Bitfield) {
+        let mut have = have;
+        have.resize(self.piece_count());
+
+        for index in have.iter_set() {
+            self.partial.remove(&(index as u32));
+        }
+
+        self.have = have;
+    }
+
+    /// Every piece we want is downloaded and verified
+    pub fn is_finished(&self) -> bool {
+        (0..self.piece_count()).all(|i| self.have.has(i) || self.priorities[i] == Priority::Skip)
+    }
+
+    pub fn in_endgame(&self) -> bool {
+        self.endgame
+    }
+
+    pub fn availability(&self, index: u32) -> u32 {
+        self.availability.get(index as usize).copied().unwrap_or(0)
+    }
+
+    pub fn set_piece_priority(&mut self, index: u32, priority: Priority) {
+        if let Some(x) = self.priorities.get_mut(index as usize) {
+            *x = priority;
+        }
+    }
+
+    /// Maps file priorities onto pieces. A piece shared by several files gets the highest
+    /// priority among them so a wanted file is never left incomplete.
+    pub fn set_file_priorities(&mut self, files: &[FileEntry], priorities: &[Priority]) {
+        let mut piece_priorities = vec![Priority::Skip; self.piece_count()];
+        let mut offset = 0u64;
+
+        for (file, priority) in files.iter().zip(priorities) {
+            if file.length > 0 {
+                let first = offset / self.piece_length;
+                let last = (offset + file.length - 1) / self.piece_length;
+
+                for index in first..=last {
+                    let x = &mut piece_priorities[index as usize];
+                    *x = (*x).max(*priority);
+                }
+            }
+
+            offset += file.length;
+        }
+
+        self.priorities = piece_priorities;
+    }
+
+    pub fn add_peer(&mut self, peer: SocketAddr, bitfield: Bitfield) {
+        self.remove_peer(&peer);
+
+        let mut bitfield = bitfield;
+        bitfield.resize(self.piece_count());
+
+        for index in bitfield.iter_set() {
+            self.availability[index] += 1;
+        }
+
+        self.peers.insert(peer, bitfield);
+    }
+
+    pub fn add_seed(&mut self, peer: SocketAddr) {
+        let mut bitfield = Bitfield::new(self.piece_count());
+
+        for index in 0..self.piece_count() {
+            bitfield.set(index);
+        }
+
+        self.add_peer(peer, bitfield);
+    }
+
+    /// Handles a `have` message
+    pub fn peer_has(&mut self, peer: &SocketAddr, index: u32) {
+        let piece_count = self.piece_count();
+        let bitfield = self.peers
+            .entry(*peer)
+            .or_insert_with(|| Bitfield::new(piece_count));
+
+        if (index as usize) < piece_count && !bitfield.has(index as usize) {
+            bitfield.set(index as usize);
+            self.availability[index as usize] += 1;
+        }
+    }
+
+    pub fn remove_peer(&mut self, peer: &SocketAddr) {
+        self.release_requests(peer);
+
+        if let Some(bitfield) = self.peers.remove(peer) {
+            for index in bitfield.iter_set() {
+                self.availability[index] -= 1;
+            }
+        }
+    }
+
+    /// Whether `peer` has something we want
+    pub fn is_interesting(&self, peer: &SocketAddr) -> bool {
+        self.peers
+            .get(peer)
+            .is_some_and(|bitfield| bitfield.iter_set().any(|i| self.wants(i)))
+    }
+
+    fn wants(&self, index: usize) -> bool {
+        !self.have.has(index) && self.priorities[index] != Priority::Skip
+    }
+
+    /// Picks up to `count` blocks to request from `peer` and marks them as requested
+    pub fn pick_blocks(&mut self, peer: &SocketAddr, count: usize) -> Vec<BlockRequest> {
+        let Some(bitfield) = self.peers.get(peer) else {
+            return vec![];
+        };
+
+        let mut picked = vec![];
+
+        // Finish what was already started, most important first
+        let mut partial = self.partial
+            .keys()
+            .copied()
+            .filter(|i| bitfield.has(*i as usize) && self.wants(*i as usize))
+            .collect::<Vec<u32>>();
+
+        partial.sort_by_key(|i| (Reverse(self.priorities[*i as usize]), *i));
+
+        // New pieces. Shuffling first breaks ties between equally rare pieces randomly
+        let mut fresh = bitfield
+            .iter_set()
+            .filter(|i| self.wants(*i) && !self.partial.contains_key(&(*i as u32)))
+            .map(|i| i as u32)
+            .collect::<Vec<u32>>();
+
+        fresh.shuffle(&mut rand::thread_rng());
+
+        if self.have.count() >= Self::RANDOM_FIRST_PIECES {
+            fresh.sort_by_key(|i| (Reverse(self.priorities[*i as usize]), self.availability[*i as usize]));
+        } else {
+            fresh.sort_by_key(|i| Reverse(self.priorities[*i as usize]));
+        }
+
+        for index in partial.into_iter().chain(fresh) {
+            if picked.len() >= count {
+                break;
+            }
+
+            let block_count = self.block_count(index);
+            let blocks = self.partial
+                .entry(index)
+                .or_insert_with(|| vec![BlockState::Missing; block_count]);
+
+            for (block, state) in blocks.iter_mut().enumerate() {
+                if picked.len() >= count {
+                    break;
+                }
+
+                if *state == BlockState::Missing {
+                    *state = BlockState::Requested(vec![*peer]);
+                    picked.push((index, block));
+                }
+            }
+        }
+
+        if picked.is_empty() {
+            self.endgame = self.everything_requested();
+        }
+
+        if self.endgame {
+            self.pick_endgame_blocks(peer, count - picked.len(), &mut picked);
+        }
+
+        picked
+            .into_iter()
+            .map(|(index, block)| self.block(index, block))
+            .collect()
+    }
+
+    /// True once no wanted block is left that hasn't been requested from someone
+    fn everything_requested(&self) -> bool {
+        (0..self.piece_count())
+            .filter(|i| self.wants(*i))
+            .all(|i| {
+                self.partial
+                    .get(&(i as u32))
+                    .is_some_and(|blocks| !blocks.contains(&BlockState::Missing))
+            })
+    }
+
+    /// Requests blocks that are pending with other peers
+    fn pick_endgame_blocks(&mut self, peer: &SocketAddr, count: usize, picked: &mut Vec<(u32, usize)>) {
+        let Some(bitfield) = self.peers.get(peer) else {
+            return;
+        };
+
+        let mut indices = self.partial
+            .keys()
+            .copied()
+            .filter(|i| bitfield.has(*i as usize) && self.wants(*i as usize))
+            .collect::<Vec<u32>>();
+
+        indices.sort();
+
+        let mut remaining = count;
+
+        for index in indices {
+            for (block, state) in self.partial.get_mut(&index).unwrap().iter_mut().enumerate() {
+                if remaining == 0 {
+                    return;
+                }
+
+                if let BlockState::Requested(peers) = state {
+                    if !peers.contains(peer) {
+                        peers.push(*peer);
+                        picked.push((index, block));
+                        remaining -= 1;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Only blocks we asked for are accepted, and only exactly as we asked for them
+    pub fn block_received(&mut self, peer: &SocketAddr, request: &BlockRequest) -> BlockOutcome {
+        let block = (request.begin / BLOCK_SIZE) as usize;
+        let mut outcome = BlockOutcome::default();
+
+        if !self.partial.contains_key(&request.index)
+            || !request.begin.is_multiple_of(BLOCK_SIZE)
+            || block >= self.block_count(request.index)
+            || *request != self.block(request.index, block)
+        {
+            return outcome;
+        }
+
+        let blocks = self.partial.get_mut(&request.index).unwrap();
+        let state = &mut blocks[block];
+
+        // Either never requested, or another peer beat this one to it in endgame mode
+        let BlockState::Requested(peers) = state else {
+            return outcome;
+        };
+
+        outcome.cancels = peers
+            .iter()
+            .filter(|x| *x != peer)
+            .map(|x| (*x, *request))
+            .collect();
+
+        *state = BlockState::Received;
+        outcome.accepted = true;
+        outcome.piece_complete = blocks.iter().all(|x| *x == BlockState::Received);
+
+        outcome
+    }
+
+    /// The request was rejected, cancelled or lost, so the block can be requested again
+    pub fn request_failed(&mut self, peer: &SocketAddr, request: &BlockRequest) {
+        let block = (request.begin / BLOCK_SIZE) as usize;
+
+        if let Some(state) = self.partial.get_mut(&request.index).and_then(|x| x.get_mut(block)) {
+            Self::release(state, peer);
+        }
+    }
+
+    /// Releases every block pending with `peer`, e.g. when it chokes us or disconnects
+    pub fn release_requests(&mut self, peer: &SocketAddr) {
+        for blocks in self.partial.values_mut() {
+            for state in blocks.iter_mut() {
+                Self::release(state, peer);
+            }
+        }
+    }
+
+    fn release(state: &mut BlockState, peer: &SocketAddr) {
+        if let BlockState::Requested(peers) = state {
+            peers.retain(|x| x != peer);
+
+            if peers.is_empty() {
+                *state = BlockState::Missing;
+            }
+        }
+    }
+
+    /// Blocks received for pieces that aren't complete yet, e.g. to save them in the resume data
+    pub fn received_blocks(&self) -> HashMap<u32, Vec<u32>> {
+        self.partial
+            .iter()
+            .map(|(index, blocks)| {
+                let received = blocks
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, state)| **state == BlockState::Received)
+                    .map(|(block, _)| block as u32)
+                    .collect::<Vec<u32>>();
+
+                (*index, received)
+            })
+            .filter(|(_, received)| !received.is_empty())
+            .collect()
+    }
+
+    /// Marks blocks of a piece as already on disk so they aren't downloaded again
+    pub fn restore_blocks(&mut self, index: u32, received: &[u32]) {
+        if index as usize >= self.piece_count() || self.have.has(index as usize) {
+            return;
+        }
+
+        let block_count = self.block_count(index);
+        let blocks = self.partial
+            .entry(index)
+            .or_insert_with(|| vec![BlockState::Missing; block_count]);
+
+        for block in received {
+            if let Some(state) = blocks.get_mut(*block as usize) {
+                *state = BlockState::Received;
+            }
+        }
+    }
+
+    /// The piece passed its hash check
+    pub fn piece_verified(&mut self, index: u32) {
+        self.partial.remove(&index);
+        self.have.set(index as usize);
+    }
+
+    /// The piece failed its hash check and has to be downloaded again
+    pub fn piece_failed(&mut self, index: u32) {
+        self.partial.remove(&index);
+        self.endgame = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use crate::{bittorrent::{bitfield::Bitfield, peer_client::BLOCK_SIZE}, metainfo::FileEntry};
+
+    use super::{BlockRequest, PiecePicker, Priority};
+
+    fn peer(n: u8) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, n], 6881))
+    }
+
+    fn bitfield(len: usize, pieces: &[usize]) -> Bitfield {
+        let mut bitfield = Bitfield::new(len);
+
+        for piece in pieces {
+            bitfield.set(*piece);
+        }
+
+        bitfield
+    }
+
+    #[test]
+    fn splits_pieces_into_blocks() {
+        let mut picker = PiecePicker::new(2 * BLOCK_SIZE as u64, 5 * BLOCK_SIZE as u64 - 100);
+        picker.add_seed(peer(1));
+
+        let mut blocks = picker.pick_blocks(&peer(1), 10);
+        blocks.sort_by_key(|x| (x.index, x.begin));
+
+        assert_eq!(blocks.len(), 5);
+        assert_eq!(blocks[4], BlockRequest { index: 2, begin: 0, length: BLOCK_SIZE - 100 });
+    }
+
+    #[test]
+    fn picks_rarest_first_after_random_start() {
+        let mut picker = PiecePicker::new(BLOCK_SIZE as u64, 8 * BLOCK_SIZE as u64);
+
+        let mut have = Bitfield::new(8);
+        for i in 0..PiecePicker::RANDOM_FIRST_PIECES {
+            have.set(i);
+        }
+        picker.set_have(have);
+
+        picker.add_seed(peer(1));
+        picker.add_peer(peer(2), bitfield(8, &[5, 6, 7]));
+        picker.add_peer(peer(3), bitfield(8, &[4, 6, 7]));
+        picker.add_peer(peer(4), bitfield(8, &[7]));
+
+        let picked = picker.pick_blocks(&peer(1), 4)
+            .iter()
+            .map(|x| x.index)
+            .collect::<Vec<u32>>();
+
+        assert_eq!(picked[2..], [6, 7]);
+        assert_eq!(picker.availability(7), 4);
+    }
+
+    #[test]
+    fn skips_unwanted_files() {
+        let mut picker = PiecePicker::new(BLOCK_SIZE as u64, 4 * BLOCK_SIZE as u64);
+
+        let files = [
+            FileEntry { length: BLOCK_SIZE as u64 + 10, path: vec!["a".to_owned()] },
+            FileEntry { length: 3 * BLOCK_SIZE as u64 - 10, path: vec!["b".to_owned()] },
+        ];
+        picker.set_file_priorities(&files, &[Priority::Normal, Priority::Skip]);
+        picker.add_seed(peer(1));
+
+        let mut picked = picker.pick_blocks(&peer(1), 10).iter().map(|x| x.index).collect::<Vec<u32>>();
+        picked.sort();
+
+        // Piece 1 is shared with the skipped file but still needed for the wanted one
+        assert_eq!(picked, vec![0, 1]);
+    }
+
+    #[test]
+    fn endgame_requests_duplicates_and_cancels() {
+        let mut picker = PiecePicker::new(BLOCK_SIZE as u64, 2 * BLOCK_SIZE as u64);
+        picker.add_seed(peer(1));
+        picker.add_seed(peer(2));
+
+        let first = picker.pick_blocks(&peer(1), 10);
+        assert_eq!(first.len(), 2);
+        assert!(!picker.in_endgame());
+
+        let duplicates = picker.pick_blocks(&peer(2), 10);
+        assert!(picker.in_endgame());
+        assert_eq!(duplicates.len(), 2);
+
+        let outcome = picker.block_received(&peer(2), &duplicates[0]);
+        assert_eq!(outcome.cancels, vec![(peer(1), duplicates[0])]);
+        assert!(outcome.piece_complete);
+
+        // The cancelled request may still arrive
+        assert!(!picker.block_received(&peer(1), &duplicates[0]).accepted);
+    }
+
+    #[test]
+    fn releases_requests_of_removed_peers() {
+        let mut picker = PiecePicker::new(BLOCK_SIZE as u64, BLOCK_SIZE as u64);
+        picker.add_seed(peer(1));
+        picker.add_seed(peer(2));
+
+        let picked = picker.pick_blocks(&peer(1), 1);
+        picker.remove_peer(&peer(1));
+
+        assert_eq!(picker.pick_blocks(&peer(2), 1), picked);
+        assert!(!picker.in_endgame());
+        assert_eq!(picker.availability(0), 1);
+    }
+
+    #[test]
+    fn accepts_only_requested_blocks() {
+        let mut picker = PiecePicker::new(2 * BLOCK_SIZE as u64, 2 * BLOCK_SIZE as u64 - 100);
+        picker.add_seed(peer(1));
+
+        let picked = picker.pick_blocks(&peer(1), 1);
+        assert_eq!(picked, vec![BlockRequest { index: 0, begin: 0, length: BLOCK_SIZE }]);
+
+        let unrequested = BlockRequest { index: 0, begin: BLOCK_SIZE, length: BLOCK_SIZE - 100 };
+        let unaligned = BlockRequest { index: 0, begin: 1, length: BLOCK_SIZE };
+        let short = BlockRequest { index: 0, begin: 0, length: 1 };
+        let past_end = BlockRequest { index: 0, begin: 2 * BLOCK_SIZE, length: BLOCK_SIZE };
+
+        for request in [unrequested, unaligned, short, past_end] {
+            assert!(!picker.block_received(&peer(1), &request).accepted, "{:?}", request);
+        }
+
+        assert!(picker.block_received(&peer(1), &picked[0]).accepted);
+        assert!(!picker.block_received(&peer(1), &picked[0]).accepted);
+    }
+}
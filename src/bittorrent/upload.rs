@@ -0,0 +1,124 @@
+use std::collections::VecDeque;
+
+use crate::bittorrent::{bitfield::Bitfield, peer_client::BLOCK_SIZE, piece_picker::BlockRequest};
+
+/// Most requests we queue for a single peer. Advertised as `reqq` in the extended handshake
+pub const MAX_UPLOAD_QUEUE: usize = 250;
+
+/// What to do with a request the peer sent us
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestOutcome {
+    Queued,
+
+    /// Refused because we're choking the peer, don't have the piece, the request is malformed or
+    /// the queue is full. Peers with the fast extension get a `reject_request` for these
+    Rejected,
+}
+
+/// Requests a peer sent us that haven't been served yet, oldest first
+#[derive(Debug, Default)]
+pub struct UploadQueue {
+    requests: VecDeque<BlockRequest>,
+}
+
+impl UploadQueue {
+    pub fn len(&self) -> usize {
+        self.requests.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.requests.is_empty()
+    }
+
+    /// Checks and queues a request. `piece_size` is the size of the requested piece, 0 if the
+    /// index is out of range
+    pub fn push(&mut self, request: BlockRequest, have: &Bitfield, choking: bool, piece_size: u64) -> RequestOutcome {
+        let valid = request.length > 0
+            && request.length <= BLOCK_SIZE
+            && request.begin as u64 + request.length as u64 <= piece_size;
+
+        if choking
+            || !valid
+            || !have.has(request.index as usize)
+            || self.requests.len() >= MAX_UPLOAD_QUEUE
+        {
+            return RequestOutcome::Rejected;
+        }
+
+        // Duplicates would only be sent twice
+        if !self.requests.contains(&request) {
+            self.requests.push_back(request);
+        }
+
+        RequestOutcome::Queued
+    }
+
+    /// Drops a request the peer doesn't want anymore. False if it wasn't queued, e.g. because the
+    /// block is already on its way
+    pub fn cancel(&mut self, request: &BlockRequest) -> bool {
+        let len = self.requests.len();
+        self.requests.retain(|x| x != request);
+
+        self.requests.len() != len
+    }
+
+    pub fn pop(&mut self) -> Option<BlockRequest> {
+        self.requests.pop_front()
+    }
+
+    /// Empties the queue, e.g. when choking the peer
+    pub fn clear(&mut self) -> Vec<BlockRequest> {
+        self.requests.drain(..).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bittorrent::{bitfield::Bitfield, peer_client::BLOCK_SIZE, piece_picker::BlockRequest};
+
+    use super::{RequestOutcome, UploadQueue, MAX_UPLOAD_QUEUE};
+
+    fn request(index: u32, begin: u32) -> BlockRequest {
+        BlockRequest { index, begin, length: BLOCK_SIZE }
+    }
+
+    #[test]
+    fn queues_valid_requests_only() {
+        let mut have = Bitfield::new(2);
+        have.set(0);
+
+        let mut queue = UploadQueue::default();
+        let piece_size = 2 * BLOCK_SIZE as u64;
+
+        assert_eq!(queue.push(request(0, 0), &have, false, piece_size), RequestOutcome::Queued);
+        assert_eq!(queue.push(request(0, 0), &have, true, piece_size), RequestOutcome::Rejected);
+        assert_eq!(queue.push(request(1, 0), &have, false, piece_size), RequestOutcome::Rejected);
+        assert_eq!(queue.push(request(0, BLOCK_SIZE + 1), &have, false, piece_size), RequestOutcome::Rejected);
+        assert_eq!(queue.push(request(5, 0), &have, false, 0), RequestOutcome::Rejected);
+
+        assert!(queue.cancel(&request(0, 0)));
+        assert!(!queue.cancel(&request(0, 0)));
+        assert!(queue.is_empty());
+
+        // Pieces over 4 GiB would wrap around as a u32
+        assert_eq!(queue.push(request(0, 2 * BLOCK_SIZE), &have, false, (1 << 32) + piece_size), RequestOutcome::Queued);
+    }
+
+    #[test]
+    fn limits_queue_length() {
+        let mut have = Bitfield::new(MAX_UPLOAD_QUEUE + 1);
+
+        for i in 0..=MAX_UPLOAD_QUEUE {
+            have.set(i);
+        }
+
+        let mut queue = UploadQueue::default();
+
+        for i in 0..MAX_UPLOAD_QUEUE {
+            assert_eq!(queue.push(request(i as u32, 0), &have, false, BLOCK_SIZE as u64), RequestOutcome::Queued);
+        }
+
+        assert_eq!(queue.push(request(MAX_UPLOAD_QUEUE as u32, 0), &have, false, BLOCK_SIZE as u64), RequestOutcome::Rejected);
+        assert_eq!(queue.pop(), Some(request(0, 0)));
+    }
+}
@@ -1,32 +1,32 @@
-use std::{collections::HashMap, io, net::{Ipv4Addr, SocketAddr, SocketAddrV4}, str, time::Duration};
+use std::{collections::{HashMap, HashSet}, io, net::SocketAddr, path::Path, sync::{Arc, Mutex}, time::Duration};
 
-use rand::Rng;
-use tokio::time::timeout;
+use futures::{stream::FuturesUnordered, StreamExt};
+use tokio::sync::Notify;
 
-use crate::{net::udp::send_udp_packet, utils::bencode::{BencodeParser, BencodeValue}};
+use crate::{
+    dht_server::{build_error, build_response, DHTIncomingQuery, DHTQuery, PeerStore, TokenSecrets, Want},
+    kademlia::{compare_distance, InsertOutcome, RoutingTable},
+    net::{compact::{encode_compact_peer, parse_compact_peer}, udp::{RequestOptions, UdpEndpoint}},
+    utils::bencode::{BencodeParser, BencodeValue},
+};
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct CompactNodeInfo {
     pub node_id: [u8; 20],
-    pub socket_addr: SocketAddrV4,
+    pub socket_addr: SocketAddr,
 }
 
-impl From<&[u8]> for CompactNodeInfo {
-    fn from(bytes: &[u8]) -> Self {
-        let socket_addr = SocketAddrV4::new(
-            Ipv4Addr::new(bytes[20], bytes[21], bytes[22], bytes[23]),
-            u16::from_be_bytes([bytes[24], bytes[25]]),
-        );
-
-        Self {
-            // TODO: Maybe it's not wise to use unwrap here
-            node_id: bytes[0..20].try_into().unwrap(),
-            socket_addr,
-        }
+impl CompactNodeInfo {
+    /// Node id followed by a compact IPv4 (26 bytes total) or IPv6 (38 bytes total) peer
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        Some(Self {
+            node_id: bytes.get(0..20)?.try_into().ok()?,
+            socket_addr: parse_compact_peer(&bytes[20..])?,
+        })
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum DHTErrorCode {
     GenericError = 201,
     ServerError = 202,
@@ -98,7 +98,7 @@ pub struct DHTGetPeersResponse {
     pub nodes: Vec<CompactNodeInfo>,
 
     /// Peers for the provided infohash (nodes that have the torrent?)
-    pub values: Vec<SocketAddrV4>,
+    pub values: Vec<SocketAddr>,
 }
 
 impl TryFrom<&BencodeValue> for DHTGetPeersResponse {
@@ -107,33 +107,25 @@ impl TryFrom<&BencodeValue> for DHTGetPeersResponse {
     fn try_from(value: &BencodeValue) -> Result<Self, Self::Error> {
         let base = DHTBaseResponse::try_from(value)?;
 
-        let r = get_resp_dict(&value)?;
+        let r = get_resp_dict(value)?;
 
         let token = r
             .get("token".as_bytes())
             .and_then(|x| x.bytes().ok())
             .cloned();
 
-        let nodes = r
-            .get("nodes".as_bytes())
-            .and_then(|x| x.bytes().ok())
-            .ok_or("Failed to parse response error message")?
-            .chunks(26)
-            .map(|bytes| CompactNodeInfo::from(bytes))
-            .collect();
+        // Nodes that have peers may reply with just `values`
+        let nodes = parse_response_nodes(r);
 
         let values = r
             .get("values".as_bytes())
-            .and_then(|x| x.bytes().ok())
-            .map(|bytes| {
-                bytes
-                    .chunks(6)
-                    .map(|bs| {
-                        SocketAddrV4::new(
-                            Ipv4Addr::new(bs[0], bs[1], bs[2], bs[3]),
-                            u16::from_be_bytes([bs[4], bs[5]])
-                        )
-                    })
+            .and_then(|x| x.list().ok())
+            .map(|list| {
+                // Each value is a separate 6 or 18 byte compact peer string
+                list
+                    .iter()
+                    .filter_map(|x| x.bytes().ok())
+                    .filter_map(|bs| parse_compact_peer(bs))
                     .collect()
             }).unwrap_or(vec![]);
 
@@ -144,7 +136,7 @@ impl TryFrom<&BencodeValue> for DHTGetPeersResponse {
             values,
         };
 
-        return Ok(resp)
+        Ok(resp)
     }
 }
 
@@ -161,28 +153,27 @@ impl TryFrom<&BencodeValue> for DHTFindNodeResponse {
     fn try_from(value: &BencodeValue) -> Result<Self, Self::Error> {
         let base = DHTBaseResponse::try_from(value)?;
 
-        let r = get_resp_dict(&value)?;
+        let r = get_resp_dict(value)?;
 
-        let nodes = r
-            .get("nodes".as_bytes())
-            .and_then(|x| x.bytes().ok())
-            .ok_or("Failed to parse response error message")?
-            .chunks(26)
-            .map(|bytes| CompactNodeInfo::from(bytes))
-            .collect();
+        if !r.contains_key("nodes".as_bytes()) && !r.contains_key("nodes6".as_bytes()) {
+            return Err("Failed to parse response nodes".to_owned());
+        }
+
+        let nodes = parse_response_nodes(r);
 
         let resp = Self {
             base,
             nodes,
         };
 
-        return Ok(resp)
+        Ok(resp)
     }
 }
 
 #[derive(Debug)]
 pub struct DHTErrorResponse {
-    pub base: DHTBaseResponse,
+    /// Error messages don't carry the sender's id, but some implementations add an `r` dict anyway
+    pub base: Option<DHTBaseResponse>,
 
     pub error_code: DHTErrorCode,
     pub error_message: String,
@@ -192,7 +183,7 @@ impl TryFrom<&BencodeValue> for DHTErrorResponse {
     type Error = String;
 
     fn try_from(value: &BencodeValue) -> Result<Self, Self::Error> {
-        let base = DHTBaseResponse::try_from(value)?;
+        let base = DHTBaseResponse::try_from(value).ok();
         let root_dict = value.dict().map_err(|_| "BencodeValue should be an object")?;
 
         let e = root_dict
@@ -201,7 +192,7 @@ impl TryFrom<&BencodeValue> for DHTErrorResponse {
             .ok_or("Failed to parse response error")?;
 
         let error_code = e
-            .get(0)
+            .first()
             .and_then(|x| x.integer().ok())
             .ok_or("Failed to parse response error code")?;
 
@@ -216,9 +207,44 @@ impl TryFrom<&BencodeValue> for DHTErrorResponse {
             error_message: String::from_utf8_lossy(error_message).into_owned(),
         };
 
-        return Ok(error_resp)
+        Ok(error_resp)
+    }
+}
+
+/// Concatenated compact node infos. All nodes should be of the same family
+pub fn encode_compact_nodes(nodes: &[CompactNodeInfo]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(nodes.len() * 38);
+
+    for node in nodes {
+        bytes.extend_from_slice(&node.node_id);
+        bytes.extend(encode_compact_peer(&node.socket_addr));
+    }
+
+    bytes
+}
+
+/// Parses concatenated 26 (IPv4) or 38 (IPv6) byte compact node infos. A trailing partial entry
+/// is ignored
+pub fn parse_compact_nodes(bytes: &[u8], ipv6: bool) -> Vec<CompactNodeInfo> {
+    let length = if ipv6 { 38 } else { 26 };
 
+    bytes
+        .chunks_exact(length)
+        .filter_map(CompactNodeInfo::from_bytes)
+        .collect()
+}
+
+/// Nodes from both `nodes` and `nodes6` (BEP 32)
+fn parse_response_nodes(r: &HashMap<Vec<u8>, BencodeValue>) -> Vec<CompactNodeInfo> {
+    let mut nodes = vec![];
+
+    for (key, ipv6) in [("nodes", false), ("nodes6", true)] {
+        if let Some(bytes) = r.get(key.as_bytes()).and_then(|x| x.bytes().ok()) {
+            nodes.extend(parse_compact_nodes(bytes, ipv6));
+        }
     }
+
+    nodes
 }
 
 fn get_resp_dict(value: &BencodeValue) -> Result<&HashMap<Vec<u8>, BencodeValue>, String> {
@@ -240,9 +266,9 @@ fn get_response_type(value: &BencodeValue) -> Result<DHTResponseType, String> {
         .and_then(|bv| bv.bytes().ok())
         .ok_or("Failed to parse response type")?;
 
-    match y[0] as char {
-        'e' => Ok(DHTResponseType::Error),
-        'r' => Ok(DHTResponseType::Response),
+    match y.first().map(|x| *x as char) {
+        Some('e') => Ok(DHTResponseType::Error),
+        Some('r') => Ok(DHTResponseType::Response),
         _ => Err("Unsupported DHT response type".to_owned()),
     }
 }
@@ -266,7 +292,7 @@ impl<'a, T: TryFrom<&'a BencodeValue, Error = String>> TryFrom<&'a BencodeValue>
     type Error = String;
 
     fn try_from(value: &'a BencodeValue) -> Result<Self, Self::Error> {
-        let response_type = get_response_type(&value)?;
+        let response_type = get_response_type(value)?;
         
         match response_type {
             DHTResponseType::Response => Ok(DHTResponse::DHTResponse(T::try_from(value)?)),
@@ -275,77 +301,265 @@ impl<'a, T: TryFrom<&'a BencodeValue, Error = String>> TryFrom<&'a BencodeValue>
     }
 }
 
+/// Result of an iterative lookup
+#[derive(Debug, Default)]
+pub struct LookupResult {
+    /// The closest nodes to the target that responded, closest first
+    pub nodes: Vec<CompactNodeInfo>,
+
+    /// Peers for the target infohash returned by any node along the way
+    pub peers: Vec<SocketAddr>,
+
+    /// announce_peer tokens handed out by the responding nodes, by node id
+    pub tokens: HashMap<[u8; 20], Vec<u8>>,
+}
+
 #[derive(Debug)]
-pub struct DHTClient<'nodeId, 'node> {
-    pub node_id: &'nodeId [u8; 20],
-    pub root_node: &'node SocketAddr,
+pub struct DHTClient {
+    pub node_id: [u8; 20],
+    pub root_node: SocketAddr,
+
+    /// Every node that answered us ends up here and seeds later lookups
+    pub routing_table: Mutex<RoutingTable>,
 
-    tx_id: u16,
+    /// Long-lived socket shared by our queries and the queries other nodes send us
+    endpoint: Arc<UdpEndpoint>,
+    token_secrets: Mutex<TokenSecrets>,
+    peer_store: Mutex<PeerStore>,
+
+    /// Nodes that hit a full bucket with questionable nodes in it. `serve` pings those in the
+    /// background, see `add_node`
+    pending_nodes: Mutex<Vec<CompactNodeInfo>>,
+    pending_notify: Notify,
 }
 
-impl<'node, 'nodeId> DHTClient<'nodeId, 'node> {
-    pub fn new(node_id: &'nodeId [u8; 20], root_node: &'node SocketAddr) -> Self {
+impl DHTClient {
+    /// Bucket size / number of closest nodes a lookup converges on
+    pub const K: usize = 8;
+
+    /// Number of queries a lookup keeps in flight
+    pub const ALPHA: usize = 3;
+
+    /// Safety net so a lookup can't go on forever against a misbehaving network
+    const MAX_LOOKUP_QUERIES: usize = 256;
+
+    /// Peers returned per get_peers response, so the reply still fits in a single datagram
+    const MAX_VALUES: usize = 50;
+
+    /// Nodes waiting for a spot in a full bucket. Newer ones are dropped beyond this
+    const MAX_PENDING_NODES: usize = 32;
+
+    /// Nodes forget announced peers after about 30 minutes, and the tokens we get are only good for
+    /// 5 to 10 minutes, so every announce starts with a fresh lookup
+    pub const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+    pub fn new(node_id: [u8; 20], root_node: SocketAddr, endpoint: Arc<UdpEndpoint>) -> Self {
+        Self::with_routing_table(node_id, root_node, endpoint, RoutingTable::new(node_id))
+    }
+
+    /// Reuses a routing table saved by a previous run. A table built around a different node id
+    /// is useless to us so it gets dropped.
+    pub fn with_routing_table(
+        node_id: [u8; 20],
+        root_node: SocketAddr,
+        endpoint: Arc<UdpEndpoint>,
+        routing_table: RoutingTable,
+    ) -> Self {
+        let routing_table = if routing_table.own_id == node_id {
+            routing_table
+        } else {
+            RoutingTable::new(node_id)
+        };
 
         Self {
             node_id,
             root_node,
-            tx_id: 0,
+            routing_table: Mutex::new(routing_table),
+            endpoint,
+            token_secrets: Mutex::new(TokenSecrets::default()),
+            peer_store: Mutex::new(PeerStore::default()),
+            pending_nodes: Mutex::new(vec![]),
+            pending_notify: Notify::new(),
         }
     }
 
-    pub async fn get_peers(&self, infohash: &[u8; 20]) -> Result<DHTResponse<DHTGetPeersResponse>, String> {
-        let query = BencodeValue::Dict(
-            HashMap::from([
-                (
-                    "t".as_bytes().to_vec(),
-                    BencodeValue::Bytes(self.tx_id.to_be_bytes().to_vec()),
-                ),
-                (
-                    "y".as_bytes().to_vec(),
-                    BencodeValue::Bytes("q".as_bytes().to_vec()),
-                ),
-                (
-                    "q".as_bytes().to_vec(),
-                    BencodeValue::Bytes("get_peers".as_bytes().to_vec()),
-                ),
-                (
-                    "a".as_bytes().to_vec(),
-                    BencodeValue::Dict(HashMap::from([
-                        (
-                            "id".as_bytes().to_vec(),
-                            BencodeValue::Bytes(self.node_id.to_vec()),
-                        ),
-                        (
-                            "info_hash".as_bytes().to_vec(),
-                            BencodeValue::Bytes(infohash.to_vec()),
-                        ),
-                    ])),
-                ),
-            ]),
-        );
+    /// Answers queries other nodes send to our endpoint and makes room in full buckets for new
+    /// nodes. Runs until the endpoint goes away
+    pub async fn serve(&self) -> io::Result<()> {
+        let mut incoming = self.endpoint.subscribe_incoming();
+        let mut pending = FuturesUnordered::new();
+
+        loop {
+            tokio::select! {
+                datagram = incoming.recv() => {
+                    let Some((data, from)) = datagram else {
+                        break;
+                    };
+
+                    if let Some(reply) = self.handle_datagram(&data, &from) {
+                        // Failing to reply to one node shouldn't take the whole server down
+                        let _ = self.endpoint.send_to(&from, &reply).await;
+                    }
+                },
+                _ = self.pending_notify.notified() => {
+                    for node in self.pending_nodes.lock().unwrap().drain(..) {
+                        pending.push(self.add_node(node));
+                    }
+                },
+                Some(_) = pending.next(), if !pending.is_empty() => {},
+            }
+        }
 
-        let resp = timeout(
-            Duration::from_secs(5),
-            send_udp_packet(self.root_node, &query.serialize()),
-        ).await
-        .map_err(|x| format!("Timeout reached {}", x.to_string()))?
-        .map_err(|x| format!("Failed to send udp packet {}", x.to_string()))?;
+        Err(io::Error::new(io::ErrorKind::BrokenPipe, "UDP endpoint closed"))
+    }
 
-        let value = BencodeParser::new(&resp)
-            .parse_value()
-            .map_err(|_| "Failed to parse bencode response")?;
+    /// Inserts a node we heard from. If that requires pinging questionable nodes first, the node
+    /// is handed to `serve` so the caller doesn't have to wait for it
+    fn insert_node(&self, node: CompactNodeInfo) {
+        let outcome = self.routing_table.lock().unwrap().insert(node.clone());
 
-        let response = DHTResponse::<DHTGetPeersResponse>::try_from(&value)?;
-        
-        Ok(response)
+        if let InsertOutcome::PingRequired(_) = outcome {
+            let mut pending = self.pending_nodes.lock().unwrap();
+
+            if pending.len() < Self::MAX_PENDING_NODES && !pending.contains(&node) {
+                pending.push(node);
+                self.pending_notify.notify_one();
+            }
+        }
+    }
+
+    /// Builds the reply to a datagram sent to our socket. Anything that isn't a query is dropped
+    fn handle_datagram(&self, data: &[u8], from: &SocketAddr) -> Option<Vec<u8>> {
+        let value = BencodeParser::new(data).parse_value().ok()?;
+
+        let is_query = value
+            .dict()
+            .ok()
+            .and_then(|x| x.get("y".as_bytes()))
+            .and_then(|x| x.bytes().ok())
+            .is_some_and(|x| x == "q".as_bytes());
+
+        if !is_query {
+            return None;
+        }
+
+        let query = match DHTIncomingQuery::try_from(&value) {
+            Ok(query) => query,
+            Err(err) => {
+                let tx_id = err.tx_id?;
+
+                return Some(build_error(&tx_id, err.code, &err.message).serialize());
+            },
+        };
+
+        // Nodes querying us are alive, which makes them good candidates for the routing table
+        self.insert_node(CompactNodeInfo {
+            node_id: query.node_id,
+            socket_addr: *from,
+        });
+
+        // Without `want`, nodes only get nodes of the family they queried us over (BEP 32)
+        let default_want = Want { n4: from.is_ipv4(), n6: from.is_ipv6() };
+
+        let mut r = HashMap::from([
+            ("id".as_bytes().to_vec(), BencodeValue::Bytes(self.node_id.to_vec())),
+        ]);
+
+        match query.query {
+            DHTQuery::Ping => {},
+            DHTQuery::FindNode { target, want } => {
+                self.insert_closest_nodes(&mut r, &target, want.unwrap_or(default_want));
+            },
+            DHTQuery::GetPeers { info_hash, want } => {
+                let token = self.token_secrets.lock().unwrap().generate(&from.ip());
+                r.insert("token".as_bytes().to_vec(), BencodeValue::Bytes(token));
+
+                let peers = self.peer_store.lock().unwrap().get(&info_hash, Self::MAX_VALUES, from.is_ipv6());
+
+                if peers.is_empty() {
+                    self.insert_closest_nodes(&mut r, &info_hash, want.unwrap_or(default_want));
+                } else {
+                    let values = peers
+                        .iter()
+                        .map(|x| BencodeValue::Bytes(encode_compact_peer(x)))
+                        .collect();
+
+                    r.insert("values".as_bytes().to_vec(), BencodeValue::List(values));
+                }
+            },
+            DHTQuery::AnnouncePeer { info_hash, port, implied_port, token } => {
+                if !self.token_secrets.lock().unwrap().validate(&from.ip(), &token) {
+                    return Some(build_error(&query.tx_id, DHTErrorCode::ProtocolError, "Bad token").serialize());
+                }
+
+                let port = if implied_port { from.port() } else { port };
+
+                self.peer_store
+                    .lock()
+                    .unwrap()
+                    .announce(info_hash, SocketAddr::new(from.ip(), port));
+            },
+        }
+
+        Some(build_response(&query.tx_id, r).serialize())
+    }
+
+    /// Adds `nodes` and/or `nodes6` with the closest nodes to `target` of each wanted family
+    fn insert_closest_nodes(&self, r: &mut HashMap<Vec<u8>, BencodeValue>, target: &[u8; 20], want: Want) {
+        let routing_table = self.routing_table.lock().unwrap();
+
+        for (key, wanted, ipv6) in [("nodes", want.n4, false), ("nodes6", want.n6, true)] {
+            if !wanted {
+                continue;
+            }
+
+            let nodes = routing_table.closest_where(target, Self::K, |x| x.socket_addr.is_ipv6() == ipv6);
+
+            r.insert(key.as_bytes().to_vec(), BencodeValue::Bytes(encode_compact_nodes(&nodes)));
+        }
+    }
+
+    /// Asks for nodes of both families when our socket can reach both (BEP 32)
+    fn want_argument(&self) -> Option<BencodeValue> {
+        let n4 = self.endpoint.supports(&SocketAddr::from(([0, 0, 0, 0], 0)));
+        let n6 = self.endpoint.supports(&SocketAddr::from(([0u16; 8], 0)));
+
+        if !(n4 && n6) {
+            return None;
+        }
+
+        Some(BencodeValue::List(vec![
+            BencodeValue::Bytes("n4".as_bytes().to_vec()),
+            BencodeValue::Bytes("n6".as_bytes().to_vec()),
+        ]))
+    }
+
+    pub fn save_routing_table(&self, path: &Path) -> io::Result<()> {
+        self.routing_table.lock().unwrap().save(path)
     }
 
-    pub async fn find_node(&self, target: &[u8]) -> Result<DHTResponse<DHTFindNodeResponse>, String> {
-        let query = BencodeValue::Dict(
+    fn build_query(
+        &self,
+        transaction_id: &[u8],
+        method: &str,
+        arguments: Vec<(&str, BencodeValue)>,
+    ) -> BencodeValue {
+        let mut a = HashMap::from([
+            (
+                "id".as_bytes().to_vec(),
+                BencodeValue::Bytes(self.node_id.to_vec()),
+            ),
+        ]);
+
+        for (key, value) in arguments {
+            a.insert(key.as_bytes().to_vec(), value);
+        }
+
+        BencodeValue::Dict(
             HashMap::from([
                 (
                     "t".as_bytes().to_vec(),
-                    BencodeValue::Bytes(self.tx_id.to_be_bytes().to_vec()),
+                    BencodeValue::Bytes(transaction_id.to_vec()),
                 ),
                 (
                     "y".as_bytes().to_vec(),
@@ -353,37 +567,467 @@ impl<'node, 'nodeId> DHTClient<'nodeId, 'node> {
                 ),
                 (
                     "q".as_bytes().to_vec(),
-                    BencodeValue::Bytes("find_node".as_bytes().to_vec()),
+                    BencodeValue::Bytes(method.as_bytes().to_vec()),
                 ),
                 (
                     "a".as_bytes().to_vec(),
-                    BencodeValue::Dict(HashMap::from([
-                        (
-                            "id".as_bytes().to_vec(),
-                            BencodeValue::Bytes(self.node_id.to_vec()),
-                        ),
-                        (
-                            "target".as_bytes().to_vec(),
-                            BencodeValue::Bytes(target.to_owned()),
-                        ),
-                    ])),
+                    BencodeValue::Dict(a),
                 ),
             ]),
-        );
+        )
+    }
 
-        let resp = timeout(
-            Duration::from_secs(3),
-            send_udp_packet(self.root_node, &query.serialize())
-        ).await
-        .map_err(|x| format!("Timeout reached {}", x.to_string()))?
-        .map_err(|x| format!("Failed to send udp packet {}", x.to_string()))?;
+    async fn send_query<T>(
+        &self,
+        node: &SocketAddr,
+        method: &str,
+        arguments: Vec<(&str, BencodeValue)>,
+        timeout: Duration,
+    ) -> Result<DHTResponse<T>, String>
+    where
+        T: for<'a> TryFrom<&'a BencodeValue, Error = String>,
+    {
+        let transaction_id = self.endpoint.next_transaction_id();
+        let query = self.build_query(&transaction_id, method, arguments);
+
+        let options = RequestOptions { timeout, retries: 0 };
+
+        let resp = self.endpoint
+            .request(node, &transaction_id, &query.serialize(), options)
+            .await
+            .map_err(|x| format!("Failed to send udp packet {}", x))?;
 
         let value = BencodeParser::new(&resp)
             .parse_value()
             .map_err(|_| "Failed to parse bencode response")?;
 
-        let response = DHTResponse::<DHTFindNodeResponse>::try_from(&value)?;
-        
-        Ok(response)
+        DHTResponse::<T>::try_from(&value)
+    }
+
+    pub async fn ping(&self, node: &SocketAddr) -> Result<DHTResponse<DHTBaseResponse>, String> {
+        self.send_query(node, "ping", vec![], Duration::from_secs(3)).await
+    }
+
+    /// Adds a node to the routing table. If its bucket is full of questionable nodes they get
+    /// pinged first, and the ones that don't answer make room for the new node.
+    pub async fn add_node(&self, node: CompactNodeInfo) -> InsertOutcome {
+        let outcome = self.routing_table.lock().unwrap().insert(node.clone());
+
+        let InsertOutcome::PingRequired(questionable) = outcome else {
+            return outcome;
+        };
+
+        for questionable_node in questionable {
+            let response = self.ping(&questionable_node.socket_addr).await;
+            let mut routing_table = self.routing_table.lock().unwrap();
+
+            match response {
+                Ok(DHTResponse::DHTResponse(_)) => routing_table.mark_seen(&questionable_node.node_id),
+                _ => routing_table.mark_failed(&questionable_node.node_id),
+            }
+        }
+
+        // Nodes that failed once are still not bad, in that case the new node is dropped
+        match self.routing_table.lock().unwrap().insert(node) {
+            InsertOutcome::PingRequired(_) => InsertOutcome::Rejected,
+            outcome => outcome,
+        }
+    }
+
+    /// Fills the routing table by looking up our own id
+    pub async fn bootstrap(&self) -> Result<usize, String> {
+        self.lookup(&self.node_id).await?;
+
+        Ok(self.routing_table.lock().unwrap().len())
+    }
+
+    pub async fn get_peers(
+        &self,
+        node: &SocketAddr,
+        infohash: &[u8; 20],
+    ) -> Result<DHTResponse<DHTGetPeersResponse>, String> {
+        let mut arguments = vec![
+            ("info_hash", BencodeValue::Bytes(infohash.to_vec())),
+        ];
+
+        if let Some(want) = self.want_argument() {
+            arguments.push(("want", want));
+        }
+
+        self.send_query(node, "get_peers", arguments, Duration::from_secs(5)).await
+    }
+
+    pub async fn find_node(
+        &self,
+        node: &SocketAddr,
+        target: &[u8],
+    ) -> Result<DHTResponse<DHTFindNodeResponse>, String> {
+        let mut arguments = vec![
+            ("target", BencodeValue::Bytes(target.to_owned())),
+        ];
+
+        if let Some(want) = self.want_argument() {
+            arguments.push(("want", want));
+        }
+
+        self.send_query(node, "find_node", arguments, Duration::from_secs(3)).await
+    }
+
+    /// Iterative Kademlia lookup for `target` using get_peers. Starts from the root node, keeps
+    /// `ALPHA` queries in flight against the closest nodes we haven't asked yet and stops once the
+    /// `K` closest nodes we know of have all been queried.
+    pub async fn lookup(&self, target: &[u8; 20]) -> Result<LookupResult, String> {
+        let mut peers: HashSet<SocketAddr> = HashSet::new();
+        let mut tokens: HashMap<[u8; 20], Vec<u8>> = HashMap::new();
+
+        // Every node we've heard about, sorted by distance to the target
+        let mut shortlist: Vec<CompactNodeInfo> = vec![];
+        let mut queried: HashSet<[u8; 20]> = HashSet::new();
+        let mut failed: HashSet<[u8; 20]> = HashSet::new();
+        let mut responded: Vec<CompactNodeInfo> = vec![];
+
+        let add_nodes = |shortlist: &mut Vec<CompactNodeInfo>, nodes: Vec<CompactNodeInfo>| {
+            for node in nodes {
+                // No point keeping IPv6 nodes around on an IPv4 socket and vice versa
+                if !self.endpoint.supports(&node.socket_addr) {
+                    continue;
+                }
+
+                if node.node_id != self.node_id && !shortlist.iter().any(|x| x.node_id == node.node_id) {
+                    shortlist.push(node);
+                }
+            }
+
+            shortlist.sort_by(|a, b| compare_distance(target, &a.node_id, &b.node_id));
+        };
+
+        let known_nodes = self.routing_table.lock().unwrap().closest(target, Self::K);
+
+        if known_nodes.is_empty() {
+            let bootstrap = self.get_peers(&self.root_node, target)
+                .await?;
+
+            let DHTResponse::DHTResponse(bootstrap) = bootstrap else {
+                return Err("Root node returned an error".to_owned());
+            };
+
+            peers.extend(bootstrap.values);
+            add_nodes(&mut shortlist, bootstrap.nodes);
+        } else {
+            add_nodes(&mut shortlist, known_nodes);
+        }
+
+        let mut in_flight = FuturesUnordered::new();
+
+        loop {
+            while in_flight.len() < Self::ALPHA && queried.len() < Self::MAX_LOOKUP_QUERIES {
+                let next = shortlist
+                    .iter()
+                    .filter(|x| !failed.contains(&x.node_id))
+                    .take(Self::K)
+                    .find(|x| !queried.contains(&x.node_id))
+                    .cloned();
+
+                let Some(node) = next else {
+                    break;
+                };
+
+                queried.insert(node.node_id);
+
+                in_flight.push(async move {
+                    let result = self.get_peers(&node.socket_addr, target).await;
+
+                    (node, result)
+                });
+            }
+
+            let Some((node, result)) = in_flight.next().await else {
+                break;
+            };
+
+            match result {
+                Ok(DHTResponse::DHTResponse(response)) => {
+                    if let Some(token) = response.token {
+                        tokens.insert(node.node_id, token);
+                    }
+
+                    peers.extend(response.values);
+                    add_nodes(&mut shortlist, response.nodes);
+
+                    self.insert_node(node.clone());
+                    responded.push(node);
+                },
+                _ => {
+                    self.routing_table.lock().unwrap().mark_failed(&node.node_id);
+                    failed.insert(node.node_id);
+                },
+            }
+        }
+
+        responded.sort_by(|a, b| compare_distance(target, &a.node_id, &b.node_id));
+        responded.truncate(Self::K);
+        tokens.retain(|id, _| responded.iter().any(|x| x.node_id == *id));
+
+        Ok(LookupResult {
+            nodes: responded,
+            peers: peers.into_iter().collect(),
+            tokens,
+        })
+    }
+
+    /// Sends a single announce_peer with a token previously received from `node`
+    pub async fn announce_peer_to(
+        &self,
+        node: &SocketAddr,
+        infohash: &[u8; 20],
+        port: u16,
+        implied_port: bool,
+        token: &[u8],
+    ) -> Result<DHTResponse<DHTBaseResponse>, String> {
+        let mut arguments = vec![
+            ("info_hash", BencodeValue::Bytes(infohash.to_vec())),
+            ("port", BencodeValue::Integer(port as i64)),
+            ("token", BencodeValue::Bytes(token.to_vec())),
+        ];
+
+        if implied_port {
+            arguments.push(("implied_port", BencodeValue::Integer(1)));
+        }
+
+        self.send_query(node, "announce_peer", arguments, Duration::from_secs(3)).await
+    }
+
+    /// Tells the `K` closest nodes to `infohash` that we have it. With `implied_port` the nodes use
+    /// the source port of our packets instead of `port`, which helps behind NATs. Returns the
+    /// lookup along with the number of nodes that accepted the announce.
+    pub async fn announce_peer(
+        &self,
+        infohash: &[u8; 20],
+        port: u16,
+        implied_port: bool,
+    ) -> Result<(LookupResult, usize), String> {
+        let lookup = self.lookup(infohash).await?;
+
+        let announces = lookup.nodes
+            .iter()
+            .filter_map(|node| {
+                let token = lookup.tokens.get(&node.node_id)?;
+
+                Some(self.announce_peer_to(&node.socket_addr, infohash, port, implied_port, token))
+            })
+            .collect::<FuturesUnordered<_>>();
+
+        let accepted = announces
+            .filter(|x| std::future::ready(matches!(x, Ok(DHTResponse::DHTResponse(_)))))
+            .count()
+            .await;
+
+        Ok((lookup, accepted))
+    }
+
+    /// Announces `infohash` every `ANNOUNCE_INTERVAL` for as long as the future is polled
+    pub async fn announce_periodically(&self, infohash: &[u8; 20], port: u16, implied_port: bool) {
+        let mut interval = tokio::time::interval(Self::ANNOUNCE_INTERVAL);
+
+        loop {
+            interval.tick().await;
+
+            // A failed round (e.g. no nodes reachable) is simply retried on the next tick
+            let _ = self.announce_peer(infohash, port, implied_port).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, net::SocketAddr};
+
+    use tokio::net::UdpSocket;
+
+    use crate::{
+        dht_client::CompactNodeInfo,
+        kademlia::RoutingTable,
+        net::{compact::encode_compact_peer, udp::UdpEndpoint},
+        utils::bencode::{BencodeParser, BencodeValue},
+    };
+
+    use super::{DHTClient, DHTGetPeersResponse, DHTResponse};
+
+    fn node_id(n: u8) -> [u8; 20] {
+        let mut id = [0u8; 20];
+        id[19] = n;
+
+        id
+    }
+
+    fn compact_node(id: [u8; 20], addr: &SocketAddr) -> Vec<u8> {
+        let mut bytes = id.to_vec();
+        bytes.extend(encode_compact_peer(addr));
+
+        bytes
+    }
+
+    /// Fake DHT node answering every get_peers query with the same nodes and values
+    async fn spawn_node(id: [u8; 20], nodes: Vec<u8>, values: Vec<SocketAddr>) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1500];
+
+            loop {
+                let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+                let query = BencodeParser::new(&buf[..len]).parse_value().unwrap();
+                let t = query.dict().unwrap().get("t".as_bytes()).unwrap().bytes().unwrap().clone();
+
+                let mut r = HashMap::from([
+                    ("id".as_bytes().to_vec(), BencodeValue::Bytes(id.to_vec())),
+                    ("nodes".as_bytes().to_vec(), BencodeValue::Bytes(nodes.clone())),
+                ]);
+
+                if !values.is_empty() {
+                    let values = values
+                        .iter()
+                        .map(|x| BencodeValue::Bytes(encode_compact_peer(x)))
+                        .collect();
+
+                    r.insert("values".as_bytes().to_vec(), BencodeValue::List(values));
+                }
+
+                let response = BencodeValue::Dict(HashMap::from([
+                    ("t".as_bytes().to_vec(), BencodeValue::Bytes(t)),
+                    ("y".as_bytes().to_vec(), BencodeValue::Bytes("r".as_bytes().to_vec())),
+                    ("r".as_bytes().to_vec(), BencodeValue::Dict(r)),
+                ]));
+
+                socket.send_to(&response.serialize(), from).await.unwrap();
+            }
+        });
+
+        addr
+    }
+
+    #[test]
+    fn parses_nodes_and_nodes6() {
+        let node4: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let node6: SocketAddr = "[2001:db8::1]:6881".parse().unwrap();
+
+        let response = BencodeValue::Dict(HashMap::from([
+            ("t".as_bytes().to_vec(), BencodeValue::Bytes("aa".as_bytes().to_vec())),
+            ("y".as_bytes().to_vec(), BencodeValue::Bytes("r".as_bytes().to_vec())),
+            ("r".as_bytes().to_vec(), BencodeValue::Dict(HashMap::from([
+                ("id".as_bytes().to_vec(), BencodeValue::Bytes(node_id(1).to_vec())),
+                ("nodes".as_bytes().to_vec(), BencodeValue::Bytes(compact_node(node_id(2), &node4))),
+                ("nodes6".as_bytes().to_vec(), BencodeValue::Bytes(compact_node(node_id(3), &node6))),
+            ]))),
+        ]));
+
+        let response = DHTGetPeersResponse::try_from(&response).unwrap();
+        let addrs = response.nodes.iter().map(|x| x.socket_addr).collect::<Vec<SocketAddr>>();
+
+        assert_eq!(addrs, vec![node4, node6]);
+    }
+
+    #[tokio::test]
+    async fn lookup_converges_on_closest_nodes() {
+        let target = [0u8; 20];
+        let peer: SocketAddr = "1.2.3.4:5678".parse().unwrap();
+
+        // 1 is the closest to the target and is only reachable through 4
+        let node1 = spawn_node(node_id(1), vec![], vec![peer]).await;
+        let node2 = spawn_node(node_id(2), vec![], vec![]).await;
+        let node3 = spawn_node(node_id(3), compact_node(node_id(2), &node2), vec![]).await;
+        let node4 = spawn_node(node_id(4), compact_node(node_id(1), &node1), vec![]).await;
+
+        let mut root_nodes = compact_node(node_id(4), &node4);
+        root_nodes.extend(compact_node(node_id(3), &node3));
+
+        let root = spawn_node(node_id(200), root_nodes, vec![]).await;
+
+        let own_id = node_id(255);
+        let endpoint = UdpEndpoint::bind("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let client = DHTClient::new(own_id, root, endpoint);
+
+        let result = client.lookup(&target).await.unwrap();
+
+        let ids = result.nodes.iter().map(|x| x.node_id[19]).collect::<Vec<u8>>();
+
+        assert_eq!(ids, vec![1, 2, 3, 4]);
+        assert_eq!(result.peers, vec![peer]);
+        assert_eq!(client.routing_table.lock().unwrap().len(), 4);
+    }
+
+    #[tokio::test]
+    async fn serves_get_peers_and_announce_peer() {
+        let own_id = node_id(1);
+        let root: SocketAddr = "127.0.0.1:1".parse().unwrap();
+
+        let server_endpoint = UdpEndpoint::bind("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let server_addr = server_endpoint.local_addr().unwrap();
+        let server = DHTClient::new(own_id, root, server_endpoint);
+
+        let client_id = node_id(2);
+        let client_endpoint = UdpEndpoint::bind("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let client = DHTClient::new(client_id, root, client_endpoint);
+        let info_hash = [9u8; 20];
+
+        let (get_peers, announce, second_get_peers) = tokio::select! {
+            result = server.serve() => panic!("Server stopped: {:?}", result),
+            result = async {
+                let get_peers = client.get_peers(&server_addr, &info_hash).await.unwrap().unwrap();
+                let token = get_peers.token.clone().unwrap();
+
+                let bad_token = client
+                    .announce_peer_to(&server_addr, &info_hash, 6881, false, &[0; 8])
+                    .await
+                    .unwrap();
+                assert!(matches!(bad_token, DHTResponse::DHTError(_)));
+
+                let announce = client
+                    .announce_peer_to(&server_addr, &info_hash, 6881, false, &token)
+                    .await
+                    .unwrap();
+                let second_get_peers = client.get_peers(&server_addr, &info_hash).await.unwrap().unwrap();
+
+                (get_peers, announce, second_get_peers)
+            } => result,
+        };
+
+        assert_eq!(get_peers.base.node_id, own_id);
+        assert!(get_peers.values.is_empty());
+        assert!(matches!(announce, DHTResponse::DHTResponse(_)));
+        assert_eq!(second_get_peers.values, vec!["127.0.0.1:6881".parse::<SocketAddr>().unwrap()]);
+        assert!(server.routing_table.lock().unwrap().get(&client_id).is_some());
+    }
+
+    #[tokio::test]
+    async fn announces_using_lookup_tokens() {
+        let own_id = node_id(1);
+        let root: SocketAddr = "127.0.0.1:1".parse().unwrap();
+
+        let server_endpoint = UdpEndpoint::bind("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let server_addr = server_endpoint.local_addr().unwrap();
+        let server = DHTClient::new(own_id, root, server_endpoint);
+
+        let client_id = node_id(2);
+        let mut routing_table = RoutingTable::new(client_id);
+        routing_table.insert(CompactNodeInfo { node_id: own_id, socket_addr: server_addr });
+
+        let client_endpoint = UdpEndpoint::bind("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let client_port = client_endpoint.local_addr().unwrap().port();
+        let client = DHTClient::with_routing_table(client_id, root, client_endpoint, routing_table);
+        let info_hash = [9u8; 20];
+
+        let (lookup, accepted) = tokio::select! {
+            result = server.serve() => panic!("Server stopped: {:?}", result),
+            result = client.announce_peer(&info_hash, 6881, true) => result.unwrap(),
+        };
+
+        assert!(lookup.tokens.contains_key(&own_id));
+        assert_eq!(accepted, 1);
+        assert_eq!(
+            server.peer_store.lock().unwrap().get(&info_hash, 50, false),
+            vec![SocketAddr::from(([127, 0, 0, 1], client_port))],
+        );
     }
 }
@@ -0,0 +1,364 @@
+use std::{collections::HashMap, net::{IpAddr, SocketAddr}, time::{Duration, Instant}};
+
+use rand::Rng;
+use sha1::{Digest, Sha1};
+
+use crate::{dht_client::DHTErrorCode, utils::bencode::BencodeValue};
+
+/// Address families a node wants nodes for, from the `want` argument (BEP 32)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Want {
+    pub n4: bool,
+    pub n6: bool,
+}
+
+/// Queries other nodes can send us
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DHTQuery {
+    Ping,
+    FindNode { target: [u8; 20], want: Option<Want> },
+    GetPeers { info_hash: [u8; 20], want: Option<Want> },
+    AnnouncePeer {
+        info_hash: [u8; 20],
+        port: u16,
+        implied_port: bool,
+        token: Vec<u8>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DHTIncomingQuery {
+    pub tx_id: Vec<u8>,
+
+    /// Node id of the querying node
+    pub node_id: [u8; 20],
+    pub query: DHTQuery,
+}
+
+/// Why an incoming query couldn't be parsed. Holds whatever is needed to send an error reply
+#[derive(Debug)]
+pub struct DHTQueryError {
+    pub tx_id: Option<Vec<u8>>,
+    pub code: DHTErrorCode,
+    pub message: String,
+}
+
+impl TryFrom<&BencodeValue> for DHTIncomingQuery {
+    type Error = DHTQueryError;
+
+    fn try_from(value: &BencodeValue) -> Result<Self, Self::Error> {
+        let protocol_error = |tx_id: Option<Vec<u8>>, message: &str| DHTQueryError {
+            tx_id,
+            code: DHTErrorCode::ProtocolError,
+            message: message.to_owned(),
+        };
+
+        let root_dict = value
+            .dict()
+            .map_err(|_| protocol_error(None, "Message should be a dict"))?;
+
+        let tx_id = root_dict
+            .get("t".as_bytes())
+            .and_then(|x| x.bytes().ok())
+            .cloned()
+            .ok_or_else(|| protocol_error(None, "Missing transaction id"))?;
+
+        let method = root_dict
+            .get("q".as_bytes())
+            .and_then(|x| x.bytes().ok())
+            .ok_or_else(|| protocol_error(Some(tx_id.clone()), "Missing method name"))?;
+
+        let a = root_dict
+            .get("a".as_bytes())
+            .and_then(|x| x.dict().ok())
+            .ok_or_else(|| protocol_error(Some(tx_id.clone()), "Missing arguments"))?;
+
+        let get_id = |key: &str| -> Result<[u8; 20], DHTQueryError> {
+            a.get(key.as_bytes())
+                .and_then(|x| x.bytes().ok())
+                .and_then(|x| x.clone().try_into().ok())
+                .ok_or_else(|| protocol_error(Some(tx_id.clone()), &format!("Invalid {}", key)))
+        };
+
+        let node_id = get_id("id")?;
+
+        let want = a
+            .get("want".as_bytes())
+            .and_then(|x| x.list().ok())
+            .map(|list| {
+                let has = |family: &str| list.iter().any(|x| x.bytes().is_ok_and(|x| x == family.as_bytes()));
+
+                Want { n4: has("n4"), n6: has("n6") }
+            });
+
+        let query = match method.as_slice() {
+            b"ping" => DHTQuery::Ping,
+            b"find_node" => DHTQuery::FindNode { target: get_id("target")?, want },
+            b"get_peers" => DHTQuery::GetPeers { info_hash: get_id("info_hash")?, want },
+            b"announce_peer" => {
+                let port = a
+                    .get("port".as_bytes())
+                    .and_then(|x| x.integer().ok())
+                    .and_then(|x| u16::try_from(*x).ok())
+                    .ok_or_else(|| protocol_error(Some(tx_id.clone()), "Invalid port"))?;
+
+                let implied_port = a
+                    .get("implied_port".as_bytes())
+                    .and_then(|x| x.integer().ok())
+                    .is_some_and(|x| *x == 1);
+
+                let token = a
+                    .get("token".as_bytes())
+                    .and_then(|x| x.bytes().ok())
+                    .cloned()
+                    .ok_or_else(|| protocol_error(Some(tx_id.clone()), "Missing token"))?;
+
+                DHTQuery::AnnouncePeer {
+                    info_hash: get_id("info_hash")?,
+                    port,
+                    implied_port,
+                    token,
+                }
+            },
+            _ => return Err(DHTQueryError {
+                tx_id: Some(tx_id),
+                code: DHTErrorCode::MethodUnknown,
+                message: "Method Unknown".to_owned(),
+            }),
+        };
+
+        Ok(Self { tx_id, node_id, query })
+    }
+}
+
+pub fn build_response(tx_id: &[u8], r: HashMap<Vec<u8>, BencodeValue>) -> BencodeValue {
+    BencodeValue::Dict(HashMap::from([
+        ("t".as_bytes().to_vec(), BencodeValue::Bytes(tx_id.to_vec())),
+        ("y".as_bytes().to_vec(), BencodeValue::Bytes("r".as_bytes().to_vec())),
+        ("r".as_bytes().to_vec(), BencodeValue::Dict(r)),
+    ]))
+}
+
+pub fn build_error(tx_id: &[u8], code: DHTErrorCode, message: &str) -> BencodeValue {
+    BencodeValue::Dict(HashMap::from([
+        ("t".as_bytes().to_vec(), BencodeValue::Bytes(tx_id.to_vec())),
+        ("y".as_bytes().to_vec(), BencodeValue::Bytes("e".as_bytes().to_vec())),
+        (
+            "e".as_bytes().to_vec(),
+            BencodeValue::List(vec![
+                BencodeValue::Integer(code as i64),
+                BencodeValue::Bytes(message.as_bytes().to_vec()),
+            ]),
+        ),
+    ]))
+}
+
+/// Secrets for the get_peers/announce_peer tokens. A token is the SHA-1 of the requester's IP and
+/// a secret that changes every 5 minutes. Tokens made with the previous secret are still accepted
+/// so a token stays valid for 5 to 10 minutes (BEP 5).
+#[derive(Debug)]
+pub struct TokenSecrets {
+    current: [u8; 20],
+    previous: [u8; 20],
+    rotated_at: Instant,
+}
+
+impl Default for TokenSecrets {
+    fn default() -> Self {
+        let current = rand::thread_rng().gen::<[u8; 20]>();
+
+        Self {
+            current,
+            previous: current,
+            rotated_at: Instant::now(),
+        }
+    }
+}
+
+impl TokenSecrets {
+    const ROTATION_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+    fn rotate_if_due(&mut self) {
+        if self.rotated_at.elapsed() >= Self::ROTATION_INTERVAL {
+            self.rotate();
+        }
+    }
+
+    pub fn rotate(&mut self) {
+        self.previous = self.current;
+        self.current = rand::thread_rng().gen::<[u8; 20]>();
+        self.rotated_at = Instant::now();
+    }
+
+    pub fn generate(&mut self, ip: &IpAddr) -> Vec<u8> {
+        self.rotate_if_due();
+
+        Self::token(ip, &self.current)
+    }
+
+    pub fn validate(&mut self, ip: &IpAddr, token: &[u8]) -> bool {
+        self.rotate_if_due();
+
+        token == Self::token(ip, &self.current) || token == Self::token(ip, &self.previous)
+    }
+
+    fn token(ip: &IpAddr, secret: &[u8; 20]) -> Vec<u8> {
+        let mut hasher = Sha1::new();
+
+        match ip {
+            IpAddr::V4(ip) => hasher.update(ip.octets()),
+            IpAddr::V6(ip) => hasher.update(ip.octets()),
+        }
+        hasher.update(secret);
+
+        // 8 bytes are plenty, no need to send the whole digest
+        hasher.finalize()[..8].to_vec()
+    }
+}
+
+/// Peers that announced themselves to us through announce_peer
+#[derive(Debug, Default)]
+pub struct PeerStore {
+    peers: HashMap<[u8; 20], Vec<(SocketAddr, Instant)>>,
+}
+
+impl PeerStore {
+    /// Announces expire unless the peer re-announces
+    const PEER_TTL: Duration = Duration::from_secs(30 * 60);
+
+    const MAX_PEERS_PER_TORRENT: usize = 2000;
+    const MAX_TORRENTS: usize = 10_000;
+
+    pub fn announce(&mut self, info_hash: [u8; 20], addr: SocketAddr) {
+        if !self.peers.contains_key(&info_hash) && self.peers.len() >= Self::MAX_TORRENTS {
+            self.prune();
+
+            if self.peers.len() >= Self::MAX_TORRENTS {
+                return;
+            }
+        }
+
+        let peers = self.peers.entry(info_hash).or_default();
+
+        peers.retain(|(x, _)| *x != addr);
+
+        if peers.len() >= Self::MAX_PEERS_PER_TORRENT {
+            peers.remove(0);
+        }
+
+        peers.push((addr, Instant::now()));
+    }
+
+    /// Up to `count` random live peers for `info_hash`, IPv6 ones if `ipv6` is set and IPv4 ones
+    /// otherwise
+    pub fn get(&self, info_hash: &[u8; 20], count: usize, ipv6: bool) -> Vec<SocketAddr> {
+        let Some(peers) = self.peers.get(info_hash) else {
+            return vec![];
+        };
+
+        let live = peers
+            .iter()
+            .filter(|(addr, announced_at)| addr.is_ipv6() == ipv6 && announced_at.elapsed() < Self::PEER_TTL)
+            .map(|(addr, _)| *addr)
+            .collect::<Vec<SocketAddr>>();
+
+        rand::seq::index::sample(&mut rand::thread_rng(), live.len(), count.min(live.len()))
+            .into_iter()
+            .map(|i| live[i])
+            .collect()
+    }
+
+    /// Drops expired peers and torrents that have none left
+    pub fn prune(&mut self) {
+        for peers in self.peers.values_mut() {
+            peers.retain(|(_, announced_at)| announced_at.elapsed() < Self::PEER_TTL);
+        }
+
+        self.peers.retain(|_, peers| !peers.is_empty());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+    use crate::utils::bencode::BencodeParser;
+
+    use super::{DHTIncomingQuery, DHTQuery, PeerStore, TokenSecrets, Want};
+
+    #[test]
+    fn parses_announce_peer_query() {
+        let data = "d1:ad2:id20:abcdefghij01234567899:info_hash20:mnopqrstuvwxyz12345612:implied_porti1e\
+            4:porti6881e5:token8:aoeusnthe1:q13:announce_peer1:t2:aa1:y1:qe";
+
+        let value = BencodeParser::new(data.as_bytes()).parse_value().unwrap();
+        let query = DHTIncomingQuery::try_from(&value).unwrap();
+
+        assert_eq!(query.tx_id, "aa".as_bytes());
+        assert_eq!(&query.node_id, "abcdefghij0123456789".as_bytes());
+        assert_eq!(query.query, DHTQuery::AnnouncePeer {
+            info_hash: "mnopqrstuvwxyz123456".as_bytes().try_into().unwrap(),
+            port: 6881,
+            implied_port: true,
+            token: "aoeusnth".as_bytes().to_vec(),
+        });
+    }
+
+    #[test]
+    fn parses_want() {
+        let data = "d1:ad2:id20:abcdefghij01234567896:target20:mnopqrstuvwxyz1234564:wantl2:n62:n4ee\
+            1:q9:find_node1:t2:aa1:y1:qe";
+
+        let value = BencodeParser::new(data.as_bytes()).parse_value().unwrap();
+        let query = DHTIncomingQuery::try_from(&value).unwrap();
+
+        assert_eq!(query.query, DHTQuery::FindNode {
+            target: "mnopqrstuvwxyz123456".as_bytes().try_into().unwrap(),
+            want: Some(Want { n4: true, n6: true }),
+        });
+    }
+
+    #[test]
+    fn rejects_unknown_methods() {
+        let data = "d1:ad2:id20:abcdefghij0123456789e1:q4:vote1:t2:aa1:y1:qe";
+
+        let value = BencodeParser::new(data.as_bytes()).parse_value().unwrap();
+        let error = DHTIncomingQuery::try_from(&value).unwrap_err();
+
+        assert_eq!(error.tx_id.as_deref(), Some("aa".as_bytes()));
+        assert_eq!(error.code as i64, 204);
+    }
+
+    #[test]
+    fn validates_tokens_across_one_rotation() {
+        let mut secrets = TokenSecrets::default();
+        let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let other_ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+
+        let token = secrets.generate(&ip);
+
+        assert!(secrets.validate(&ip, &token));
+        assert!(!secrets.validate(&other_ip, &token));
+
+        secrets.rotate();
+        assert!(secrets.validate(&ip, &token));
+
+        secrets.rotate();
+        assert!(!secrets.validate(&ip, &token));
+    }
+
+    #[test]
+    fn stores_announced_peers() {
+        let mut store = PeerStore::default();
+        let peer: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let peer6: SocketAddr = "[2001:db8::1]:6881".parse().unwrap();
+
+        store.announce([1; 20], peer);
+        store.announce([1; 20], peer);
+        store.announce([1; 20], peer6);
+
+        assert_eq!(store.get(&[1; 20], 50, false), vec![peer]);
+        assert_eq!(store.get(&[1; 20], 50, true), vec![peer6]);
+        assert!(store.get(&[2; 20], 50, false).is_empty());
+    }
+}
@@ -1,3 +1,9 @@
+use std::{cmp::Ordering, collections::HashMap, fs, io, path::Path, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+use crate::{
+    dht_client::{encode_compact_nodes, parse_compact_nodes, CompactNodeInfo},
+    utils::bencode::{BencodeParser, BencodeValue},
+};
 
 pub fn get_distance(a: &[u8; 20], b: &[u8; 20]) -> [u8; 20] {
     let mut result: [u8; 20] = [0; 20];
@@ -9,13 +15,386 @@ pub fn get_distance(a: &[u8; 20], b: &[u8; 20]) -> [u8; 20] {
     result
 }
 
-pub fn distance_to_integer(distance: &[u8; 20]) -> u128 {
-    u128::from_be_bytes(distance[4..].try_into().unwrap())    
+/// Orders `a` and `b` by their XOR distance to `target`. Distances are compared as full 160-bit
+/// big endian integers, which is what comparing the byte arrays lexicographically does.
+pub fn compare_distance(target: &[u8; 20], a: &[u8; 20], b: &[u8; 20]) -> Ordering {
+    get_distance(target, a).cmp(&get_distance(target, b))
+}
+
+
+/// Number of leading bits `a` and `b` have in common
+pub fn shared_prefix_length(a: &[u8; 20], b: &[u8; 20]) -> usize {
+    let distance = get_distance(a, b);
+
+    distance
+        .iter()
+        .position(|x| *x != 0)
+        .map(|i| i * 8 + distance[i].leading_zeros() as usize)
+        .unwrap_or(160)
+}
+
+/// Nodes are good as long as they've responded to us or queried us recently (BEP 5)
+const QUESTIONABLE_AFTER: Duration = Duration::from_secs(15 * 60);
+
+/// Failed queries in a row after which a node is considered bad
+const MAX_FAILED_QUERIES: u32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeStatus {
+    Good,
+    Questionable,
+    Bad,
+}
+
+#[derive(Debug, Clone)]
+pub struct RoutingNode {
+    pub info: CompactNodeInfo,
+    pub last_seen: SystemTime,
+    pub failed_queries: u32,
+}
+
+impl RoutingNode {
+    fn node_id(&self) -> &[u8; 20] {
+        &self.info.node_id
+    }
+
+    pub fn status(&self) -> NodeStatus {
+        if self.failed_queries >= MAX_FAILED_QUERIES {
+            return NodeStatus::Bad;
+        }
+
+        let elapsed = self.last_seen.elapsed().unwrap_or_default();
+
+        if elapsed > QUESTIONABLE_AFTER {
+            NodeStatus::Questionable
+        } else {
+            NodeStatus::Good
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum InsertOutcome {
+    Inserted,
+
+    /// The node was already in the table, its last seen time got refreshed
+    Updated,
+
+    /// The bucket is full of good nodes
+    Rejected,
+
+    /// The bucket is full but has questionable nodes. They should be pinged and marked as seen or
+    /// failed before trying again.
+    PingRequired(Vec<CompactNodeInfo>),
+}
+
+#[derive(Debug, Clone, Default)]
+struct KBucket {
+    /// Least recently seen first
+    nodes: Vec<RoutingNode>,
+}
+
+/// Kademlia routing table (BEP 5). Starts with a single bucket covering the whole id space. The
+/// bucket that contains our own id gets split when it fills up, so the table ends up with up to
+/// 160 buckets, bucket `i` holding nodes that share exactly `i` leading bits with us.
+#[derive(Debug, Clone)]
+pub struct RoutingTable {
+    pub own_id: [u8; 20],
+    buckets: Vec<KBucket>,
+}
+
+impl RoutingTable {
+    pub const K: usize = 8;
+    const MAX_BUCKETS: usize = 160;
+
+    pub fn new(own_id: [u8; 20]) -> Self {
+        Self {
+            own_id,
+            buckets: vec![KBucket::default()],
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(|x| x.nodes.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn bucket_count(&self) -> usize {
+        self.buckets.len()
+    }
+
+    fn bucket_index(&self, node_id: &[u8; 20]) -> usize {
+        shared_prefix_length(&self.own_id, node_id).min(self.buckets.len() - 1)
+    }
+
+    pub fn insert(&mut self, info: CompactNodeInfo) -> InsertOutcome {
+        self.insert_seen_at(info, SystemTime::now())
+    }
+
+    fn insert_seen_at(&mut self, info: CompactNodeInfo, last_seen: SystemTime) -> InsertOutcome {
+        if info.node_id == self.own_id {
+            return InsertOutcome::Rejected;
+        }
+
+        loop {
+            let index = self.bucket_index(&info.node_id);
+            let bucket_count = self.buckets.len();
+            let bucket = &mut self.buckets[index];
+
+            if let Some(position) = bucket.nodes.iter().position(|x| x.node_id() == &info.node_id) {
+                let mut node = bucket.nodes.remove(position);
+
+                node.info = info;
+                node.last_seen = node.last_seen.max(last_seen);
+                node.failed_queries = 0;
+                bucket.nodes.push(node);
+
+                return InsertOutcome::Updated;
+            }
+
+            let node = RoutingNode { info: info.clone(), last_seen, failed_queries: 0 };
+
+            if bucket.nodes.len() < Self::K {
+                bucket.nodes.push(node);
+
+                return InsertOutcome::Inserted;
+            }
+
+            if let Some(position) = bucket.nodes.iter().position(|x| x.status() == NodeStatus::Bad) {
+                bucket.nodes.remove(position);
+                bucket.nodes.push(node);
+
+                return InsertOutcome::Inserted;
+            }
+
+            // Only the bucket covering our own id can be split
+            if index == bucket_count - 1 && bucket_count < Self::MAX_BUCKETS {
+                self.split_last_bucket();
+
+                continue;
+            }
+
+            let questionable = bucket.nodes
+                .iter()
+                .filter(|x| x.status() == NodeStatus::Questionable)
+                .map(|x| x.info.clone())
+                .collect::<Vec<CompactNodeInfo>>();
+
+            if questionable.is_empty() {
+                return InsertOutcome::Rejected;
+            }
+
+            return InsertOutcome::PingRequired(questionable);
+        }
+    }
+
+    fn split_last_bucket(&mut self) {
+        let index = self.buckets.len() - 1;
+        let nodes = std::mem::take(&mut self.buckets[index].nodes);
+
+        self.buckets.push(KBucket::default());
+
+        for node in nodes {
+            let new_index = self.bucket_index(node.node_id());
+
+            self.buckets[new_index].nodes.push(node);
+        }
+    }
+
+    /// Called when a node responds to one of our queries or sends us one
+    pub fn mark_seen(&mut self, node_id: &[u8; 20]) {
+        if let Some(node) = self.get_mut(node_id) {
+            node.last_seen = SystemTime::now();
+            node.failed_queries = 0;
+        }
+    }
+
+    pub fn mark_failed(&mut self, node_id: &[u8; 20]) {
+        if let Some(node) = self.get_mut(node_id) {
+            node.failed_queries += 1;
+        }
+    }
+
+    pub fn remove(&mut self, node_id: &[u8; 20]) {
+        let index = self.bucket_index(node_id);
+
+        self.buckets[index].nodes.retain(|x| x.node_id() != node_id);
+    }
+
+    pub fn get(&self, node_id: &[u8; 20]) -> Option<&RoutingNode> {
+        self.buckets[self.bucket_index(node_id)]
+            .nodes
+            .iter()
+            .find(|x| x.node_id() == node_id)
+    }
+
+    fn get_mut(&mut self, node_id: &[u8; 20]) -> Option<&mut RoutingNode> {
+        let index = self.bucket_index(node_id);
+
+        self.buckets[index]
+            .nodes
+            .iter_mut()
+            .find(|x| x.node_id() == node_id)
+    }
+
+    /// Up to `count` non-bad nodes closest to `target`, closest first
+    pub fn closest(&self, target: &[u8; 20], count: usize) -> Vec<CompactNodeInfo> {
+        self.closest_where(target, count, |_| true)
+    }
+
+    /// Same as `closest` but only considers nodes matching `filter`
+    pub fn closest_where(
+        &self,
+        target: &[u8; 20],
+        count: usize,
+        filter: impl Fn(&CompactNodeInfo) -> bool,
+    ) -> Vec<CompactNodeInfo> {
+        let mut nodes = self.buckets
+            .iter()
+            .flat_map(|x| x.nodes.iter())
+            .filter(|x| x.status() != NodeStatus::Bad && filter(&x.info))
+            .map(|x| x.info.clone())
+            .collect::<Vec<CompactNodeInfo>>();
+
+        nodes.sort_by(|a, b| compare_distance(target, &a.node_id, &b.node_id));
+        nodes.truncate(count);
+
+        nodes
+    }
+
+    /// Nodes that haven't been heard from in a while and should be pinged
+    pub fn questionable_nodes(&self) -> Vec<CompactNodeInfo> {
+        self.buckets
+            .iter()
+            .flat_map(|x| x.nodes.iter())
+            .filter(|x| x.status() == NodeStatus::Questionable)
+            .map(|x| x.info.clone())
+            .collect()
+    }
+
+    /// Bencoded form of the table: our id, the nodes in compact form and when each was last seen.
+    /// IPv6 nodes go in `nodes6`/`last_seen6`
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut dict = HashMap::from([
+            ("id".as_bytes().to_vec(), BencodeValue::Bytes(self.own_id.to_vec())),
+        ]);
+
+        for (nodes_key, last_seen_key, ipv6) in [("nodes", "last_seen", false), ("nodes6", "last_seen6", true)] {
+            let nodes = self.buckets
+                .iter()
+                .flat_map(|x| x.nodes.iter())
+                .filter(|x| x.status() != NodeStatus::Bad && x.info.socket_addr.is_ipv6() == ipv6)
+                .collect::<Vec<&RoutingNode>>();
+
+            let infos = nodes.iter().map(|x| x.info.clone()).collect::<Vec<CompactNodeInfo>>();
+
+            let last_seen = nodes
+                .iter()
+                .map(|node| {
+                    let timestamp = node.last_seen
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap_or_default()
+                        .as_secs();
+
+                    BencodeValue::Integer(timestamp as i64)
+                })
+                .collect();
+
+            dict.insert(nodes_key.as_bytes().to_vec(), BencodeValue::Bytes(encode_compact_nodes(&infos)));
+            dict.insert(last_seen_key.as_bytes().to_vec(), BencodeValue::List(last_seen));
+        }
+
+        BencodeValue::Dict(dict).serialize()
+    }
+
+    pub fn deserialize(data: &[u8]) -> Result<Self, String> {
+        let value = BencodeParser::new(data)
+            .parse_value()
+            .map_err(|_| "Failed to parse routing table")?;
+
+        let dict = value.dict().map_err(|_| "Routing table should be a dict")?;
+
+        let own_id: [u8; 20] = dict
+            .get("id".as_bytes())
+            .and_then(|x| x.bytes().ok())
+            .and_then(|x| x.clone().try_into().ok())
+            .ok_or("Failed to parse routing table id")?;
+
+        let nodes = dict
+            .get("nodes".as_bytes())
+            .and_then(|x| x.bytes().ok())
+            .ok_or("Failed to parse routing table nodes")?;
+
+        let last_seen = dict
+            .get("last_seen".as_bytes())
+            .and_then(|x| x.list().ok())
+            .ok_or("Failed to parse routing table last_seen")?;
+
+        // Tables saved before IPv6 support don't have these
+        let nodes6 = dict
+            .get("nodes6".as_bytes())
+            .and_then(|x| x.bytes().ok())
+            .map(|x| x.as_slice())
+            .unwrap_or_default();
+
+        let last_seen6 = dict
+            .get("last_seen6".as_bytes())
+            .and_then(|x| x.list().ok())
+            .map(|x| x.as_slice())
+            .unwrap_or_default();
+
+        let mut table = Self::new(own_id);
+
+        let entries = parse_compact_nodes(nodes, false)
+            .into_iter()
+            .zip(last_seen)
+            .chain(parse_compact_nodes(nodes6, true).into_iter().zip(last_seen6));
+
+        for (info, last_seen) in entries {
+            let timestamp = last_seen.integer().ok().and_then(|x| u64::try_from(*x).ok()).unwrap_or(0);
+
+            table.insert_seen_at(info, UNIX_EPOCH + Duration::from_secs(timestamp));
+        }
+
+        Ok(table)
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        // Write to a temporary file first so a crash mid-write doesn't lose the old table
+        let tmp_path = path.with_extension("tmp");
+
+        fs::write(&tmp_path, self.serialize())?;
+        fs::rename(tmp_path, path)
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let data = fs::read(path)?;
+
+        Self::deserialize(&data).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::kademlia::get_distance;
+    use std::{cmp::Ordering, net::SocketAddr, time::{Duration, SystemTime}};
+
+    use crate::{dht_client::CompactNodeInfo, kademlia::{compare_distance, get_distance, shared_prefix_length}};
+
+    use super::{InsertOutcome, RoutingTable};
+
+    fn node(first_byte: u8, last_byte: u8) -> CompactNodeInfo {
+        let mut node_id = [0u8; 20];
+        node_id[0] = first_byte;
+        node_id[19] = last_byte;
+
+        CompactNodeInfo {
+            node_id,
+            socket_addr: SocketAddr::from(([10, 0, first_byte, last_byte], 6881)),
+        }
+    }
 
     #[test]
     fn calculates_distance1() {
@@ -29,4 +408,101 @@ mod tests {
 
         assert_eq!(get_distance(&node1, &node2), result);
     }
+
+    #[test]
+    fn compares_distance_using_all_bytes() {
+        let target = [0u8; 20];
+
+        let mut near = [0u8; 20];
+        near[19] = 0xFF;
+
+        // Only differs from the target in the top byte, which used to be ignored
+        let mut far = [0u8; 20];
+        far[0] = 1;
+
+        assert_eq!(compare_distance(&target, &near, &far), Ordering::Less);
+        assert_eq!(compare_distance(&target, &far, &near), Ordering::Greater);
+        assert_eq!(compare_distance(&target, &far, &far), Ordering::Equal);
+    }
+
+    #[test]
+    fn calculates_shared_prefix_length() {
+        let a = [0u8; 20];
+        let mut b = [0u8; 20];
+
+        assert_eq!(shared_prefix_length(&a, &b), 160);
+
+        b[0] = 0b0001_0000;
+        assert_eq!(shared_prefix_length(&a, &b), 3);
+
+        b[0] = 0;
+        b[2] = 1;
+        assert_eq!(shared_prefix_length(&a, &b), 23);
+    }
+
+    #[test]
+    fn splits_bucket_containing_own_id() {
+        let mut table = RoutingTable::new([0u8; 20]);
+
+        // Far away nodes (top bit set) fill the first bucket
+        for i in 0..RoutingTable::K as u8 {
+            assert_eq!(table.insert(node(0x80, i)), InsertOutcome::Inserted);
+        }
+
+        assert_eq!(table.bucket_count(), 1);
+
+        // A closer node forces a split instead of being rejected
+        assert_eq!(table.insert(node(0x01, 0)), InsertOutcome::Inserted);
+        assert!(table.bucket_count() > 1);
+
+        // The far bucket can't be split anymore and all of its nodes are good
+        assert_eq!(table.insert(node(0x80, 100)), InsertOutcome::Rejected);
+
+        assert_eq!(table.insert(node(0x80, 3)), InsertOutcome::Updated);
+        assert_eq!(table.len(), RoutingTable::K + 1);
+        assert_eq!(table.closest(&[0u8; 20], 1), vec![node(0x01, 0)]);
+    }
+
+    #[test]
+    fn evicts_bad_and_reports_questionable_nodes() {
+        let mut table = RoutingTable::new([0u8; 20]);
+
+        for i in 0..RoutingTable::K as u8 {
+            table.insert(node(0x80, i));
+        }
+        table.insert(node(0x01, 0));
+
+        for _ in 0..3 {
+            table.mark_failed(&node(0x80, 0).node_id);
+        }
+
+        assert_eq!(table.insert(node(0x80, 100)), InsertOutcome::Inserted);
+        assert!(table.get(&node(0x80, 0).node_id).is_none());
+
+        let an_hour_ago = SystemTime::now() - Duration::from_secs(3600);
+        table.insert_seen_at(node(0x80, 1), an_hour_ago);
+        table.get_mut(&node(0x80, 1).node_id).unwrap().last_seen = an_hour_ago;
+
+        assert_eq!(table.insert(node(0x80, 101)), InsertOutcome::PingRequired(vec![node(0x80, 1)]));
+    }
+
+    #[test]
+    fn serializes_and_deserializes_table() {
+        let mut table = RoutingTable::new([7u8; 20]);
+
+        for i in 0..20 {
+            table.insert(node(i * 10, i));
+        }
+
+        let mut node6 = node(0x11, 1);
+        node6.socket_addr = "[2001:db8::1]:6881".parse().unwrap();
+        table.insert(node6.clone());
+
+        let restored = RoutingTable::deserialize(&table.serialize()).unwrap();
+
+        assert_eq!(restored.own_id, table.own_id);
+        assert_eq!(restored.len(), table.len());
+        assert_eq!(restored.closest(&[0u8; 20], 8), table.closest(&[0u8; 20], 8));
+        assert_eq!(restored.get(&node6.node_id).map(|x| x.info.socket_addr), Some(node6.socket_addr));
+    }
 }
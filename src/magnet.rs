@@ -1,9 +1,269 @@
+use std::ops::RangeInclusive;
 
-#[derive(Debug)]
-struct Magnet {
+use url::{form_urlencoded::byte_serialize, Url};
+
+use crate::utils::{base32::{decode_base32, encode_base32}, hex::{decode_hex, encode_hex}};
+
+#[derive(Debug, PartialEq)]
+pub enum MagnetError {
+    InvalidUri,
+    NotAMagnet,
+    MissingInfoHash,
+
+    /// The `xt` parameter could not be decoded into a v1 or v2 info-hash
+    InvalidInfoHash(String),
+
+    /// A known parameter had a value that couldn't be parsed (name, value)
+    InvalidParameter(String, String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Magnet {
+    /// v1 SHA-1 info-hash (`xt=urn:btih:`)
+    pub info_hash: Option<[u8; 20]>,
+
+    /// v2 SHA-256 info-hash (`xt=urn:btmh:1220...`). The multihash prefix is stripped
+    pub info_hash_v2: Option<[u8; 32]>,
+
+    /// `dn`
+    pub display_name: Option<String>,
+
+    /// `tr`, in the order they appear in the link
+    pub trackers: Vec<String>,
+
+    /// `x.pe` peer addresses. These can be `hostname:port`, `ipv4:port` or `[ipv6]:port` so they're
+    /// kept as strings and resolved when connecting
+    pub peers: Vec<String>,
+
+    /// `ws`
+    pub web_seeds: Vec<String>,
+
+    /// `xl`
+    pub exact_length: Option<u64>,
+
+    /// `so` file indices, e.g. `so=0,2,4-6` becomes `[0..=0, 2..=2, 4..=6]`
+    pub select_only: Vec<RangeInclusive<usize>>,
 }
 
 impl Magnet {
-    fn new() {
+    /// Multihash prefix for sha2-256 with a 32 byte digest
+    const SHA256_MULTIHASH_PREFIX: [u8; 2] = [0x12, 0x20];
+
+    pub fn parse(uri: &str) -> Result<Magnet, MagnetError> {
+        let url = Url::parse(uri.trim()).map_err(|_| MagnetError::InvalidUri)?;
+
+        if url.scheme() != "magnet" {
+            return Err(MagnetError::NotAMagnet);
+        }
+
+        let mut magnet = Magnet::default();
+
+        for (key, value) in url.query_pairs() {
+            match key.as_ref() {
+                // Hybrid links may number their topics (xt.1, xt.2, ...)
+                k if k == "xt" || k.starts_with("xt.") => magnet.parse_exact_topic(&value)?,
+                "dn" => magnet.display_name = Some(value.into_owned()),
+                "tr" => magnet.trackers.push(value.into_owned()),
+                "x.pe" => magnet.peers.push(value.into_owned()),
+                "ws" => magnet.web_seeds.push(value.into_owned()),
+                "xl" => {
+                    let length = value
+                        .parse::<u64>()
+                        .map_err(|_| MagnetError::InvalidParameter(key.to_string(), value.to_string()))?;
+
+                    magnet.exact_length = Some(length);
+                },
+                "so" => {
+                    magnet.select_only = parse_select_only(&value)
+                        .ok_or_else(|| MagnetError::InvalidParameter(key.to_string(), value.to_string()))?;
+                },
+                // Unknown parameters are ignored as recommended by BEP 9
+                _ => {},
+            }
+        }
+
+        if magnet.info_hash.is_none() && magnet.info_hash_v2.is_none() {
+            return Err(MagnetError::MissingInfoHash);
+        }
+
+        Ok(magnet)
+    }
+
+    fn parse_exact_topic(&mut self, topic: &str) -> Result<(), MagnetError> {
+        let invalid = || MagnetError::InvalidInfoHash(topic.to_owned());
+
+        if let Some(hash) = topic.strip_prefix("urn:btih:") {
+            let bytes = match hash.len() {
+                40 if hash.bytes().all(|c| c.is_ascii_hexdigit()) =>
+                    decode_hex(hash).map_err(|_| invalid())?,
+                32 => decode_base32(hash).ok_or_else(invalid)?,
+                _ => return Err(invalid()),
+            };
+
+            self.info_hash = Some(bytes.try_into().map_err(|_| invalid())?);
+        } else if let Some(hash) = topic.strip_prefix("urn:btmh:") {
+            if hash.len() != 68 || !hash.bytes().all(|c| c.is_ascii_hexdigit()) {
+                return Err(invalid());
+            }
+
+            let bytes = decode_hex(hash).map_err(|_| invalid())?;
+
+            if bytes[0..2] != Self::SHA256_MULTIHASH_PREFIX {
+                return Err(invalid());
+            }
+
+            self.info_hash_v2 = Some(bytes[2..].try_into().map_err(|_| invalid())?);
+        }
+
+        // Other urns (ed2k, sha1, ...) aren't useful to us
+
+        Ok(())
+    }
+
+    pub fn to_uri(&self) -> String {
+        let mut params: Vec<String> = vec![];
+
+        if let Some(info_hash) = self.info_hash {
+            params.push(format!("xt=urn:btih:{}", encode_hex(&info_hash)));
+        }
+
+        if let Some(info_hash_v2) = self.info_hash_v2 {
+            params.push(format!(
+                "xt=urn:btmh:{}{}",
+                encode_hex(&Self::SHA256_MULTIHASH_PREFIX),
+                encode_hex(&info_hash_v2),
+            ));
+        }
+
+        if let Some(display_name) = &self.display_name {
+            params.push(format!("dn={}", encode_component(display_name)));
+        }
+
+        if let Some(exact_length) = self.exact_length {
+            params.push(format!("xl={}", exact_length));
+        }
+
+        for tracker in &self.trackers {
+            params.push(format!("tr={}", encode_component(tracker)));
+        }
+
+        for web_seed in &self.web_seeds {
+            params.push(format!("ws={}", encode_component(web_seed)));
+        }
+
+        for peer in &self.peers {
+            params.push(format!("x.pe={}", encode_component(peer)));
+        }
+
+        if !self.select_only.is_empty() {
+            let ranges = self.select_only
+                .iter()
+                .map(|range| {
+                    if range.start() == range.end() {
+                        range.start().to_string()
+                    } else {
+                        format!("{}-{}", range.start(), range.end())
+                    }
+                })
+                .collect::<Vec<String>>();
+
+            params.push(format!("so={}", ranges.join(",")));
+        }
+
+        format!("magnet:?{}", params.join("&"))
+    }
+
+    /// Base32 form of the v1 info-hash, used by some older clients
+    pub fn info_hash_base32(&self) -> Option<String> {
+        self.info_hash.map(|hash| encode_base32(&hash))
+    }
+}
+
+fn encode_component(value: &str) -> String {
+    byte_serialize(value.as_bytes()).collect()
+}
+
+fn parse_select_only(value: &str) -> Option<Vec<RangeInclusive<usize>>> {
+    value
+        .split(',')
+        .filter(|x| !x.is_empty())
+        .map(|item| {
+            match item.split_once('-') {
+                Some((start, end)) => {
+                    let start = start.parse::<usize>().ok()?;
+                    let end = end.parse::<usize>().ok()?;
+
+                    (start <= end).then_some(start..=end)
+                },
+                None => {
+                    let index = item.parse::<usize>().ok()?;
+
+                    Some(index..=index)
+                },
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::utils::hex::decode_hex;
+
+    use super::{Magnet, MagnetError};
+
+    const REPO_MAGNET: &str = include_str!("../magnet");
+
+    #[test]
+    fn parses_repo_magnet() {
+        let magnet = Magnet::parse(REPO_MAGNET).unwrap();
+
+        let info_hash: [u8; 20] = decode_hex("290ec4b1b490c762f8c79162d1cd81f2a6c9bff4")
+            .unwrap()
+            .try_into()
+            .unwrap();
+
+        assert_eq!(magnet.info_hash, Some(info_hash));
+        assert_eq!(magnet.display_name.as_deref(), Some("Beautiful Birds Wallpapers {Pack-37}"));
+        assert_eq!(magnet.trackers.len(), 9);
+        assert_eq!(magnet.trackers[0], "udp://tracker.opentrackr.org:1337");
+    }
+
+    #[test]
+    fn round_trips_through_uri() {
+        let uri = "magnet:?xt=urn:btih:290EC4B1B490C762F8C79162D1CD81F2A6C9BFF4\
+            &xt=urn:btmh:1220caf1e1c30e81cb361b9ee167c4aa64228a7fa4fa9f6105232b28ad099f3a302e\
+            &dn=Some%20Name&xl=1024&tr=udp%3A%2F%2Fa%3A1&tr=http%3A%2F%2Fb%2Fannounce\
+            &ws=http%3A%2F%2Fseed&x.pe=10.0.0.1%3A6881&x.pe=%5B%3A%3A1%5D%3A6881&so=0,2,4-6";
+
+        let magnet = Magnet::parse(uri).unwrap();
+
+        assert_eq!(magnet.exact_length, Some(1024));
+        assert_eq!(magnet.peers, vec!["10.0.0.1:6881", "[::1]:6881"]);
+        assert_eq!(magnet.select_only, vec![0..=0, 2..=2, 4..=6]);
+        assert!(magnet.info_hash_v2.is_some());
+
+        assert_eq!(Magnet::parse(&magnet.to_uri()).unwrap(), magnet);
+    }
+
+    #[test]
+    fn parses_base32_info_hash() {
+        let magnet = Magnet::parse(REPO_MAGNET).unwrap();
+        let base32 = magnet.info_hash_base32().unwrap();
+
+        let parsed = Magnet::parse(&format!("magnet:?xt=urn:btih:{}", base32)).unwrap();
+
+        assert_eq!(parsed.info_hash, magnet.info_hash);
+    }
+
+    #[test]
+    fn rejects_malformed_magnets() {
+        assert_eq!(Magnet::parse("magnet:?dn=nothing"), Err(MagnetError::MissingInfoHash));
+        assert_eq!(Magnet::parse("http://example.com/?xt=urn:btih:00"), Err(MagnetError::NotAMagnet));
+        assert!(matches!(Magnet::parse("magnet:?xt=urn:btih:290EC4"), Err(MagnetError::InvalidInfoHash(_))));
+        assert!(matches!(Magnet::parse("magnet:?xt=urn:btih:ééééééééééééééééééééé"), Err(MagnetError::InvalidInfoHash(_))));
+        assert!(matches!(
+            Magnet::parse("magnet:?xt=urn:btih:290EC4B1B490C762F8C79162D1CD81F2A6C9BFF4&xl=abc"),
+            Err(MagnetError::InvalidParameter(_, _)),
+        ));
     }
 }
@@ -1,111 +1,125 @@
-use std::{io, net::{Ipv4Addr, SocketAddr, SocketAddrV4, ToSocketAddrs}};
+// The modules are a library for embedding the client, the binary only uses part of it
+#![allow(dead_code)]
 
-use rand::Rng;
-use url::Url;
-use utils::hex::decode_hex;
+use std::{io, path::Path, time::Duration};
 
-use net::udp::send_udp_packet;
-use bittorrent::peer_client::PeerClient;
+use metainfo::Metainfo;
+use session::{Session, SessionConfig};
+use storage::{Allocation, Storage};
 
 mod net;
 mod utils;
 mod dht_client;
+mod dht_server;
 mod bittorrent;
 mod kademlia;
 mod tracker;
+mod magnet;
+mod metainfo;
+mod storage;
+mod resume;
+mod recheck;
+mod torrent_session;
+mod session;
+
+/// `verify <torrent file> <download dir>` hashes the data on disk and prints how much of it is
+/// complete
+async fn verify(torrent_path: &str, download_dir: &str) -> io::Result<()> {
+    let data = std::fs::read(torrent_path)?;
+    let metainfo = Metainfo::from_bytes(&data).expect("Should parse torrent file");
+    let storage = Storage::new(&metainfo.info, Path::new(download_dir));
+
+    let result = recheck::recheck(&storage, |progress| {
+        if progress.checked % 100 == 0 || progress.checked == progress.total {
+            println!("Checked {}/{} pieces, {} valid", progress.checked, progress.total, progress.valid);
+        }
+    })
+    .await?;
+
+    for (path, completion) in storage.file_paths().iter().zip(result.file_completion) {
+        println!("{:6.2}% {}", completion * 100.0, path.display());
+    }
 
+    Ok(())
+}
+
+/// Downloads and seeds every magnet link or torrent file given on the command line into the
+/// current directory until interrupted. `--preallocate` writes out the files before downloading
 #[tokio::main]
 async fn main() -> io::Result<()> {
-    let magnet = "magnet:?xt=urn:btih:6853ab2b86b2cb6a3c778b8aafe3dffd94242321&dn=archlinux-2024.04.01-x86_64.iso";
-
-    let url = Url::parse(magnet).expect("Should parse magnet link");
-    let query_pairs: Vec<(String, String)> = url
-        .query_pairs()
-        .map(|(k, v)| (k.to_string(), v.to_string()))
-        .collect();
-
-    let hash = query_pairs
-        .iter()
-        .find(|x| x.0 == "xt")
-        .map(|x| &x.1)
-        .expect("Magnet link should contain infohash");
-
-    let node_id = rand::thread_rng().gen::<[u8; 20]>();
-
-    let infohash_bytes: [u8; 20] = decode_hex(hash.split(":").last().unwrap())
-        .unwrap()
-        .try_into()
-        .unwrap();
-
-    let tracker = "open.stealth.si:80".to_socket_addrs().unwrap().nth(0).unwrap();
-
-    let connect_packet_data = [
-        0x00, 0x00, 0x04, 0x17, 0x27, 0x10, 0x19, 0x80, // protocol_id: Magic constant
-        0x00, 0x00, 0x00, 0x00,                         // action: 0 for connect
-        0x00, 0x00, 0x00, 0x01,                         // transaction_id: arbitrary number
-    ];
-    
-    let connect_response = send_udp_packet(&tracker, &connect_packet_data).await?;
-    let connection_id = &connect_response[8..16];
-
-    let mut announce_packet_data = [0u8; 98];
-    // connection_id
-    announce_packet_data[0..8].copy_from_slice(connection_id);
-    // action
-    announce_packet_data[8..12].copy_from_slice(&[0x00, 0x00, 0x00, 0x01]);
-    // transaction_id
-    announce_packet_data[12..16].copy_from_slice(&[0x00, 0x00, 0x00, 0x15]);
-    // info_hash
-    announce_packet_data[16..36].copy_from_slice(&infohash_bytes);
-    // peer_id
-    announce_packet_data[36..56].copy_from_slice(&node_id);
-    // downloaded
-    announce_packet_data[56..64].copy_from_slice(&[0x00; 8]);
-    // left
-    announce_packet_data[64..72].copy_from_slice(&[0x00; 8]);
-    // uploaded
-    announce_packet_data[72..80].copy_from_slice(&[0x00; 8]);
-    // event
-    announce_packet_data[80..84].copy_from_slice(&[0x00; 4]);
-    // ip_address
-    announce_packet_data[84..88].copy_from_slice(&[0x00; 4]);
-    // key
-    announce_packet_data[88..92].copy_from_slice(&[0x00; 4]);
-    // num_wait
-    announce_packet_data[92..96].copy_from_slice(&[0x00, 0x00, 0x00, 0x32]);
-    // port
-    announce_packet_data[96..98].copy_from_slice(&6969u16.to_be_bytes());
-
-    let announce_response = send_udp_packet(&tracker, &announce_packet_data).await?;
-
-    println!("Announce data: {:?}", announce_response);
-
-    let peers = announce_response[20..]
-        .chunks(6)
-        .map(|bytes| SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]), u16::from_be_bytes([bytes[4], bytes[5]]))))
-        .collect::<Vec<SocketAddr>>();
-
-    println!("---------------------------------------");
-    for peer_addr in peers {
-        println!("Trying to connect to {:?}", peer_addr);
-
-        let peer_connection = PeerClient::connect(&node_id, &peer_addr, &infohash_bytes).await;
-
-        if let Err(err) = peer_connection {
-            println!("Error connecting to {:?}: {:?}", peer_addr, err);
-
-            continue;
-        }
+    let mut args = std::env::args().collect::<Vec<String>>();
 
-        println!("Connected successfully to {:?}: {:?}", peer_addr, peer_connection);
+    if args.len() == 4 && args[1] == "verify" {
+        return verify(&args[2], &args[3]).await;
+    }
 
-        let handshake_result = peer_connection.unwrap().send_handshake().await;
+    let preallocate = args.iter().any(|x| x == "--preallocate");
+    args.retain(|x| x != "--preallocate");
 
-        println!("Handshake result {:?}: {:?}", peer_addr, handshake_result);
+    let mut sources = args[1..].to_vec();
 
-        break;
+    if sources.is_empty() {
+        sources.push("magnet:?xt=urn:btih:6853ab2b86b2cb6a3c778b8aafe3dffd94242321&dn=archlinux-2024.04.01-x86_64.iso".to_owned());
     }
 
+    let config = SessionConfig {
+        state_dir: Some(".".into()),
+        allocation: if preallocate { Allocation::Full } else { Allocation::Sparse },
+        ..SessionConfig::default()
+    };
+
+    // Fall back to IPv4 only on systems without IPv6, and to a random port if the default one is
+    // taken
+    let session = match Session::new(config.clone()).await {
+        Ok(session) => session,
+        Err(_) => match Session::new(SessionConfig { listen_addr: ([0, 0, 0, 0], 6881).into(), ..config.clone() }).await {
+            Ok(session) => session,
+            Err(_) => Session::new(SessionConfig { listen_addr: ([0, 0, 0, 0], 0).into(), ..config }).await?,
+        },
+    };
+
+    for source in sources {
+        let added = if source.starts_with("magnet:") {
+            session.add_magnet(&source).await
+        } else {
+            session.add_torrent_file(Path::new(&source)).await
+        };
+
+        if let Err(err) = added {
+            println!("Failed to add {}: {:?}", source, err);
+        }
+    }
 
-    Ok(())
+    let mut interval = tokio::time::interval(Duration::from_secs(5));
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {
+                for status in session.torrents() {
+                    println!(
+                        "{} {:?} {:.2}% down {} KiB/s up {} KiB/s, {} peers ({} seeds), eta {:?}",
+                        status.name.as_deref().unwrap_or("?"),
+                        status.state,
+                        status.progress * 100.0,
+                        status.download_rate / 1024,
+                        status.upload_rate / 1024,
+                        status.peers,
+                        status.seeds,
+                        status.eta,
+                    );
+
+                    for (url, issue) in &status.tracker_issues {
+                        println!("    {}: {:?}", url, issue);
+                    }
+                }
+
+                if let Some(err) = session.dht_error() {
+                    println!("{}", err);
+                }
+            },
+            _ = tokio::signal::ctrl_c() => break,
+        }
+    }
+
+    session.shutdown().await
 }
@@ -0,0 +1,402 @@
+use std::{collections::HashMap, path::PathBuf};
+
+use sha1::{Digest, Sha1};
+
+use crate::utils::bencode::{BencodeParser, BencodeParserError, BencodeValue};
+
+#[derive(Debug)]
+pub enum MetainfoError {
+    Bencode(BencodeParserError),
+
+    /// A required key is missing or has the wrong type
+    InvalidField(&'static str),
+}
+
+impl From<BencodeParserError> for MetainfoError {
+    fn from(err: BencodeParserError) -> Self {
+        Self::Bencode(err)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileEntry {
+    pub length: u64,
+
+    /// Path components relative to the torrent's root directory
+    pub path: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FileLayout {
+    Single { length: u64 },
+    Multi { files: Vec<FileEntry> },
+}
+
+/// Largest piece length we accept. Real torrents stay well below it, and every piece gets read
+/// into memory whole when it's checked
+pub const MAX_PIECE_LENGTH: u64 = 1 << 26;
+
+#[derive(Debug, Clone)]
+pub struct Info {
+    pub name: String,
+    pub piece_length: u64,
+    pub pieces: Vec<[u8; 20]>,
+    pub private: bool,
+    pub layout: FileLayout,
+}
+
+impl TryFrom<&BencodeValue> for Info {
+    type Error = MetainfoError;
+
+    fn try_from(value: &BencodeValue) -> Result<Self, Self::Error> {
+        let dict = value.dict().map_err(|_| MetainfoError::InvalidField("info"))?;
+
+        // The name is the first component of every file's path, so it gets the same checks
+        let name = get_string(dict, "name")?
+            .filter(|x| is_safe_component(x))
+            .ok_or(MetainfoError::InvalidField("name"))?;
+
+        let piece_length = get_length(dict, "piece length")?
+            .filter(|x| *x > 0 && *x <= MAX_PIECE_LENGTH)
+            .ok_or(MetainfoError::InvalidField("piece length"))?;
+
+        let pieces_bytes = dict
+            .get("pieces".as_bytes())
+            .and_then(|x| x.bytes().ok())
+            .filter(|x| x.len() % 20 == 0)
+            .ok_or(MetainfoError::InvalidField("pieces"))?;
+
+        let pieces: Vec<[u8; 20]> = pieces_bytes
+            .chunks_exact(20)
+            .map(|x| x.try_into().unwrap())
+            .collect();
+
+        let private = dict
+            .get("private".as_bytes())
+            .and_then(|x| x.integer().ok())
+            .is_some_and(|x| *x == 1);
+
+        let layout = match get_length(dict, "length")? {
+            Some(length) => FileLayout::Single { length },
+            None => {
+                let files = dict
+                    .get("files".as_bytes())
+                    .and_then(|x| x.list().ok())
+                    .ok_or(MetainfoError::InvalidField("files"))?
+                    .iter()
+                    .map(parse_file_entry)
+                    .collect::<Result<Vec<FileEntry>, MetainfoError>>()?;
+
+                // `total_length` can then just add them up
+                files
+                    .iter()
+                    .try_fold(0u64, |total, x| total.checked_add(x.length))
+                    .ok_or(MetainfoError::InvalidField("length"))?;
+
+                FileLayout::Multi { files }
+            },
+        };
+
+        let info = Self {
+            name,
+            piece_length,
+            pieces,
+            private,
+            layout,
+        };
+
+        // Every piece index gets checked and written against these hashes, a mismatch would have
+        // us either never finish or index past them
+        if info.pieces.len() as u64 != info.total_length().div_ceil(info.piece_length) {
+            return Err(MetainfoError::InvalidField("pieces"));
+        }
+
+        Ok(info)
+    }
+}
+
+impl Info {
+    pub fn total_length(&self) -> u64 {
+        match &self.layout {
+            FileLayout::Single { length } => *length,
+            FileLayout::Multi { files } => files.iter().map(|x| x.length).sum(),
+        }
+    }
+
+    pub fn piece_count(&self) -> usize {
+        self.pieces.len()
+    }
+
+    /// Size of the piece at `index`. Every piece has `piece_length` bytes except the last one
+    pub fn piece_size(&self, index: usize) -> u64 {
+        let start = index as u64 * self.piece_length;
+
+        self.piece_length.min(self.total_length().saturating_sub(start))
+    }
+
+    /// Files along with their path relative to the download directory. Multi-file torrents are
+    /// nested in a directory named after the torrent.
+    pub fn files(&self) -> Vec<FileEntry> {
+        match &self.layout {
+            FileLayout::Single { length } => vec![FileEntry {
+                length: *length,
+                path: vec![self.name.clone()],
+            }],
+            FileLayout::Multi { files } => files
+                .iter()
+                .map(|file| {
+                    let mut path = vec![self.name.clone()];
+                    path.extend(file.path.iter().cloned());
+
+                    FileEntry { length: file.length, path }
+                })
+                .collect(),
+        }
+    }
+}
+
+impl FileEntry {
+    pub fn relative_path(&self) -> PathBuf {
+        self.path.iter().collect()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Metainfo {
+    /// SHA-1 of the bencoded info dict exactly as it appeared in the source
+    pub info_hash: [u8; 20],
+    pub info: Info,
+
+    /// The raw bencoded info dict. Kept around so it can be served to peers through ut_metadata
+    pub info_bytes: Vec<u8>,
+
+    pub announce: Option<String>,
+
+    /// BEP 12 tiers
+    pub announce_list: Vec<Vec<String>>,
+
+    pub creation_date: Option<i64>,
+    pub comment: Option<String>,
+    pub created_by: Option<String>,
+}
+
+impl Metainfo {
+    pub fn from_bytes(data: &[u8]) -> Result<Self, MetainfoError> {
+        let root = BencodeParser::new(data).parse_value()?;
+        let dict = root.dict().map_err(|_| MetainfoError::InvalidField("root"))?;
+
+        let info_bytes = BencodeParser::new(data)
+            .parse_raw_dict_value("info".as_bytes())?
+            .ok_or(MetainfoError::InvalidField("info"))?;
+
+        let info = Info::try_from(
+            dict.get("info".as_bytes()).ok_or(MetainfoError::InvalidField("info"))?
+        )?;
+
+        let announce_list = dict
+            .get("announce-list".as_bytes())
+            .and_then(|x| x.list().ok())
+            .map(|tiers| {
+                tiers
+                    .iter()
+                    .filter_map(|tier| tier.list().ok())
+                    .map(|tier| {
+                        tier
+                            .iter()
+                            .filter_map(|x| x.bytes().ok())
+                            .map(|x| String::from_utf8_lossy(x).into_owned())
+                            .collect::<Vec<String>>()
+                    })
+                    .filter(|tier| !tier.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Ok(Self {
+            info_hash: Sha1::digest(info_bytes).into(),
+            info,
+            info_bytes: info_bytes.to_vec(),
+            announce: get_string(dict, "announce")?,
+            announce_list,
+            creation_date: dict
+                .get("creation date".as_bytes())
+                .and_then(|x| x.integer().ok())
+                .copied(),
+            comment: get_string(dict, "comment")?,
+            created_by: get_string(dict, "created by")?,
+        })
+    }
+
+    /// Builds a metainfo out of a bare info dict, e.g. one fetched through ut_metadata for a
+    /// magnet link. Trackers have to be filled in by the caller.
+    pub fn from_info_bytes(info_bytes: &[u8]) -> Result<Self, MetainfoError> {
+        let info = Info::try_from(&BencodeParser::new(info_bytes).parse_value()?)?;
+
+        Ok(Self {
+            info_hash: Sha1::digest(info_bytes).into(),
+            info,
+            info_bytes: info_bytes.to_vec(),
+            announce: None,
+            announce_list: vec![],
+            creation_date: None,
+            comment: None,
+            created_by: None,
+        })
+    }
+
+    /// Trackers grouped in tiers. Falls back to a single tier containing `announce` when the
+    /// torrent doesn't have an announce-list.
+    pub fn tracker_tiers(&self) -> Vec<Vec<String>> {
+        if !self.announce_list.is_empty() {
+            return self.announce_list.clone();
+        }
+
+        self.announce
+            .iter()
+            .map(|x| vec![x.clone()])
+            .collect()
+    }
+}
+
+fn get_string(
+    dict: &HashMap<Vec<u8>, BencodeValue>,
+    key: &'static str,
+) -> Result<Option<String>, MetainfoError> {
+    dict.get(key.as_bytes())
+        .map(|x| {
+            x.bytes()
+                .map(|x| String::from_utf8_lossy(x).into_owned())
+                .map_err(|_| MetainfoError::InvalidField(key))
+        })
+        .transpose()
+}
+
+fn get_length(
+    dict: &HashMap<Vec<u8>, BencodeValue>,
+    key: &'static str,
+) -> Result<Option<u64>, MetainfoError> {
+    dict.get(key.as_bytes())
+        .map(|x| {
+            x.integer()
+                .ok()
+                .and_then(|x| u64::try_from(*x).ok())
+                .ok_or(MetainfoError::InvalidField(key))
+        })
+        .transpose()
+}
+
+fn parse_file_entry(value: &BencodeValue) -> Result<FileEntry, MetainfoError> {
+    let dict = value.dict().map_err(|_| MetainfoError::InvalidField("files"))?;
+
+    let length = get_length(dict, "length")?.ok_or(MetainfoError::InvalidField("length"))?;
+
+    let path = dict
+        .get("path".as_bytes())
+        .and_then(|x| x.list().ok())
+        .ok_or(MetainfoError::InvalidField("path"))?
+        .iter()
+        .map(|x| {
+            x.bytes()
+                .map(|x| String::from_utf8_lossy(x).into_owned())
+                .map_err(|_| MetainfoError::InvalidField("path"))
+        })
+        .collect::<Result<Vec<String>, MetainfoError>>()?;
+
+    if path.is_empty() || !path.iter().all(|x| is_safe_component(x)) {
+        return Err(MetainfoError::InvalidField("path"));
+    }
+
+    Ok(FileEntry { length, path })
+}
+
+/// Rejects empty components and anything that could escape the download directory
+fn is_safe_component(component: &str) -> bool {
+    !component.is_empty() && component != "." && component != ".." && !component.contains(['/', '\\'])
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::utils::hex::encode_hex;
+
+    use super::{FileLayout, Metainfo, MetainfoError, MAX_PIECE_LENGTH};
+
+    const TEST_TORRENT: &[u8] = include_bytes!("../test-torrent.torrent");
+
+    #[test]
+    fn parses_test_torrent() {
+        let metainfo = Metainfo::from_bytes(TEST_TORRENT).unwrap();
+
+        assert_eq!(encode_hex(&metainfo.info_hash), "d984f67af9917b214cd8b6048ab5624c7df6a07a");
+        assert_eq!(metainfo.announce.as_deref(), Some("https://academictorrents.com/announce.php"));
+        assert_eq!(metainfo.announce_list.len(), 4);
+        assert_eq!(metainfo.created_by.as_deref(), Some("Transmission/2.92 (14714)"));
+        assert_eq!(metainfo.creation_date, Some(1495908054));
+
+        let info = &metainfo.info;
+
+        assert_eq!(info.name, "test_folder");
+        assert_eq!(info.piece_length, 32768);
+        assert_eq!(info.piece_count(), 589);
+        assert!(!info.private);
+        assert!(matches!(info.layout, FileLayout::Multi { ref files } if files.len() == 3));
+        assert_eq!(info.total_length(), 17614527 + 1682177 + 20);
+        assert_eq!(info.piece_size(588), info.total_length() - 588 * 32768);
+        assert_eq!(info.files()[2].path, vec!["test_folder", "README"]);
+    }
+
+    #[test]
+    fn builds_metainfo_from_info_dict() {
+        let metainfo = Metainfo::from_bytes(TEST_TORRENT).unwrap();
+        let from_info = Metainfo::from_info_bytes(&metainfo.info_bytes).unwrap();
+
+        assert_eq!(from_info.info_hash, metainfo.info_hash);
+        assert_eq!(from_info.info.files(), metainfo.info.files());
+        assert!(from_info.tracker_tiers().is_empty());
+    }
+
+    #[test]
+    fn rejects_truncated_torrent() {
+        assert!(Metainfo::from_bytes(&TEST_TORRENT[..1000]).is_err());
+    }
+
+    #[test]
+    fn rejects_names_escaping_download_dir() {
+        for name in ["", ".", "..", "../../.bashrc", "/etc/cron.d/x", "a\\b"] {
+            let mut info_bytes = format!("d6:lengthi1e4:name{}:{}12:piece lengthi1e6:pieces20:", name.len(), name).into_bytes();
+            info_bytes.extend_from_slice(&[0; 20]);
+            info_bytes.push(b'e');
+
+            assert!(matches!(Metainfo::from_info_bytes(&info_bytes), Err(MetainfoError::InvalidField("name"))), "{}", name);
+        }
+    }
+
+    #[test]
+    fn rejects_piece_count_not_matching_length() {
+        for (length, count) in [(1, 0), (1, 2), (20, 1), (33, 2)] {
+            let mut info_bytes = format!("d6:lengthi{}e4:name1:a12:piece lengthi16e6:pieces{}:", length, count * 20).into_bytes();
+            info_bytes.extend_from_slice(&vec![0; count * 20]);
+            info_bytes.push(b'e');
+
+            assert!(matches!(Metainfo::from_info_bytes(&info_bytes), Err(MetainfoError::InvalidField("pieces"))), "{} {}", length, count);
+        }
+    }
+
+    #[test]
+    fn rejects_oversized_piece_length() {
+        let piece_length = MAX_PIECE_LENGTH + 1;
+        let mut info_bytes = format!("d6:lengthi{}e4:name1:a12:piece lengthi{}e6:pieces20:", piece_length, piece_length).into_bytes();
+        info_bytes.extend_from_slice(&[0; 20]);
+        info_bytes.push(b'e');
+
+        assert!(matches!(Metainfo::from_info_bytes(&info_bytes), Err(MetainfoError::InvalidField("piece length"))));
+    }
+
+    #[test]
+    fn rejects_total_length_overflow() {
+        // Three of the largest lengths bencode allows add up to more than a u64 holds
+        let file = format!("d6:lengthi{}e4:pathl1:aee", i64::MAX);
+        let info_bytes = format!("d5:filesl{}{}{}e4:name1:a12:piece lengthi16e6:pieces0:e", file, file, file);
+
+        assert!(matches!(Metainfo::from_info_bytes(info_bytes.as_bytes()), Err(MetainfoError::InvalidField("length"))));
+    }
+}
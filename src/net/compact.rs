@@ -0,0 +1,61 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+/// Compact peer formats: IP followed by the port, both big endian
+pub const COMPACT_PEER_V4_LENGTH: usize = 6;
+pub const COMPACT_PEER_V6_LENGTH: usize = 18;
+
+pub fn encode_compact_peer(addr: &SocketAddr) -> Vec<u8> {
+    let mut bytes = match addr.ip() {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    };
+    bytes.extend_from_slice(&addr.port().to_be_bytes());
+
+    bytes
+}
+
+/// The family is picked from the length, anything but 6 or 18 bytes is rejected
+pub fn parse_compact_peer(bytes: &[u8]) -> Option<SocketAddr> {
+    let ip = match bytes.len() {
+        COMPACT_PEER_V4_LENGTH => IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(&bytes[0..4]).ok()?)),
+        COMPACT_PEER_V6_LENGTH => IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(&bytes[0..16]).ok()?)),
+        _ => return None,
+    };
+
+    let port = u16::from_be_bytes([bytes[bytes.len() - 2], bytes[bytes.len() - 1]]);
+
+    Some(SocketAddr::new(ip, port))
+}
+
+/// Parses a string of concatenated compact peers of one family. A trailing partial entry is ignored
+pub fn parse_compact_peers(bytes: &[u8], ipv6: bool) -> Vec<SocketAddr> {
+    let length = if ipv6 { COMPACT_PEER_V6_LENGTH } else { COMPACT_PEER_V4_LENGTH };
+
+    bytes
+        .chunks_exact(length)
+        .filter_map(parse_compact_peer)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::{encode_compact_peer, parse_compact_peer, parse_compact_peers};
+
+    #[test]
+    fn round_trips_both_families() {
+        for addr in ["10.0.0.1:6881", "[2001:db8::1]:51413"] {
+            let addr: SocketAddr = addr.parse().unwrap();
+
+            assert_eq!(parse_compact_peer(&encode_compact_peer(&addr)), Some(addr));
+        }
+    }
+
+    #[test]
+    fn ignores_trailing_partial_peer() {
+        let peers = parse_compact_peers(&[10, 0, 0, 1, 0x1a, 0xe1, 10, 0], false);
+
+        assert_eq!(peers, vec!["10.0.0.1:6881".parse::<SocketAddr>().unwrap()]);
+    }
+}
@@ -1 +1,3 @@
 pub mod udp;
+pub mod compact;
+pub mod rate_limit;
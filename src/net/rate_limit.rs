@@ -0,0 +1,195 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::time::Instant;
+
+#[derive(Debug)]
+struct Bucket {
+    /// Bytes per second, `None` is unlimited
+    rate: Option<u64>,
+
+    /// Goes negative when a transfer is bigger than what's available. The debt is paid off by
+    /// whoever comes next waiting a bit longer
+    tokens: f64,
+    last_refill: Instant,
+}
+
+/// Token bucket limiting the bytes per second passing through it. The bucket holds at most one
+/// second worth of tokens, so short bursts go through at full speed.
+///
+/// Limiters are shared (`Arc`) between every connection they apply to and the rate can be
+/// changed at any time, the new rate is picked up by the next transfer.
+#[derive(Debug)]
+pub struct RateLimiter {
+    bucket: Mutex<Bucket>,
+}
+
+impl RateLimiter {
+    pub fn new(rate: Option<u64>) -> Arc<Self> {
+        Arc::new(Self {
+            bucket: Mutex::new(Bucket {
+                rate,
+                tokens: rate.unwrap_or(0) as f64,
+                last_refill: Instant::now(),
+            }),
+        })
+    }
+
+    pub fn unlimited() -> Arc<Self> {
+        Self::new(None)
+    }
+
+    pub fn rate(&self) -> Option<u64> {
+        self.bucket.lock().unwrap().rate
+    }
+
+    pub fn set_rate(&self, rate: Option<u64>) {
+        let mut bucket = self.bucket.lock().unwrap();
+
+        Self::refill(&mut bucket, Instant::now());
+        bucket.rate = rate;
+
+        if let Some(rate) = rate {
+            bucket.tokens = bucket.tokens.min(rate as f64);
+        }
+    }
+
+    fn refill(bucket: &mut Bucket, now: Instant) {
+        if let Some(rate) = bucket.rate {
+            let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+
+            bucket.tokens = (bucket.tokens + elapsed * rate as f64).min(rate as f64);
+        }
+
+        bucket.last_refill = now;
+    }
+
+    /// Takes `bytes` tokens and returns how long to wait before using them
+    fn reserve(&self, bytes: usize, now: Instant) -> Duration {
+        let mut bucket = self.bucket.lock().unwrap();
+
+        Self::refill(&mut bucket, now);
+
+        let Some(rate) = bucket.rate else {
+            return Duration::ZERO;
+        };
+
+        bucket.tokens -= bytes as f64;
+
+        if bucket.tokens >= 0.0 || rate == 0 {
+            // A rate of 0 would wait forever, treat it like unlimited instead
+            return Duration::ZERO;
+        }
+
+        Duration::from_secs_f64(-bucket.tokens / rate as f64)
+    }
+
+    /// Waits until `bytes` can be transferred
+    pub async fn acquire(&self, bytes: usize) {
+        let wait = self.reserve(bytes, Instant::now());
+
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// Every limiter a transfer has to pass, e.g. the global, torrent and peer limiter. The slowest
+/// one decides
+#[derive(Debug, Clone, Default)]
+pub struct RateLimits {
+    limiters: Vec<Arc<RateLimiter>>,
+}
+
+impl RateLimits {
+    pub fn new(limiters: Vec<Arc<RateLimiter>>) -> Self {
+        Self { limiters }
+    }
+
+    /// The same limits with one more limiter
+    pub fn with(&self, limiter: Arc<RateLimiter>) -> Self {
+        let mut limiters = self.limiters.clone();
+        limiters.push(limiter);
+
+        Self { limiters }
+    }
+
+    pub async fn acquire(&self, bytes: usize) {
+        let now = Instant::now();
+
+        // Reserving from all of them up front means waiting for the slowest instead of the sum
+        let wait = self.limiters
+            .iter()
+            .map(|x| x.reserve(bytes, now))
+            .max()
+            .unwrap_or_default();
+
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// Limits for both directions
+#[derive(Debug, Clone, Default)]
+pub struct Bandwidth {
+    pub upload: RateLimits,
+    pub download: RateLimits,
+}
+
+impl Bandwidth {
+    /// The same limits with one more upload and download limiter
+    pub fn with(&self, upload: Arc<RateLimiter>, download: Arc<RateLimiter>) -> Self {
+        Self {
+            upload: self.upload.with(upload),
+            download: self.download.with(download),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::time::Instant;
+
+    use super::{RateLimiter, RateLimits};
+
+    #[test]
+    fn waits_off_debt() {
+        let limiter = RateLimiter::new(Some(1000));
+        let now = Instant::now();
+
+        // The first second worth is free, the rest has to be waited for
+        assert_eq!(limiter.reserve(1000, now), Duration::ZERO);
+        assert_eq!(limiter.reserve(500, now), Duration::from_millis(500));
+        assert_eq!(limiter.reserve(500, now + Duration::from_millis(500)), Duration::from_millis(500));
+
+        limiter.set_rate(None);
+        assert_eq!(limiter.reserve(1_000_000, now + Duration::from_millis(500)), Duration::ZERO);
+    }
+
+    #[tokio::test]
+    async fn slowest_limiter_decides() {
+        let global = RateLimiter::new(Some(1_000_000));
+        let peer = RateLimiter::new(Some(100_000));
+        let limits = RateLimits::new(vec![global.clone(), peer.clone()]);
+
+        let start = Instant::now();
+
+        limits.acquire(100_000).await;
+        limits.acquire(20_000).await;
+
+        assert!(start.elapsed() >= Duration::from_millis(190));
+
+        // Raising the peer limit applies to the next transfer right away
+        peer.set_rate(Some(10_000_000));
+
+        let start = Instant::now();
+        limits.acquire(50_000).await;
+
+        assert!(start.elapsed() < Duration::from_millis(100));
+    }
+}
@@ -1,21 +1,359 @@
-use tokio::net::{UdpSocket};
-use url::Url;
-use std::{io, net::SocketAddr};
-
-
-pub async fn send_udp_packet(
-    address: &SocketAddr,
-    data: &[u8],
-) -> io::Result<Vec<u8>> {
-    // Specifying a port of 0 will make the os pick a random port for us
-    let socket = UdpSocket::bind("0.0.0.0:0").await?;
-    
-    socket.connect(address).await?;
-    socket.send(data).await?;
-
-    println!("Waiting for response");
-    let mut resp_buf = [0u8; 65_535];
-    let bytes_count = socket.recv(&mut resp_buf).await?;
-
-    Ok(resp_buf[0..bytes_count].to_vec())
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    sync::{atomic::{AtomicU32, Ordering}, Arc, Mutex},
+    time::Duration,
+};
+
+use rand::Rng;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::{net::UdpSocket, sync::{mpsc, oneshot}, task::JoinHandle, time::timeout};
+
+use crate::{net::rate_limit::Bandwidth, utils::bencode::BencodeParser};
+
+type PendingRequests = HashMap<(SocketAddr, Vec<u8>), oneshot::Sender<Vec<u8>>>;
+type IncomingSender = mpsc::Sender<(Vec<u8>, SocketAddr)>;
+
+/// How long to wait for a response and how many times to resend the request
+#[derive(Debug, Clone, Copy)]
+pub struct RequestOptions {
+    pub timeout: Duration,
+    pub retries: u32,
+}
+
+impl Default for RequestOptions {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(5),
+            retries: 0,
+        }
+    }
+}
+
+/// Extracts the transaction id a response should be matched on. KRPC messages (DHT) are bencoded
+/// dicts carrying it in `t`, UDP tracker messages have it in bytes 4..8.
+pub fn transaction_id(data: &[u8]) -> Option<Vec<u8>> {
+    if data.first() == Some(&b'd') {
+        let value = BencodeParser::new(data).parse_value().ok()?;
+
+        return value
+            .dict()
+            .ok()?
+            .get("t".as_bytes())
+            .and_then(|x| x.bytes().ok())
+            .cloned();
+    }
+
+    data.get(4..8).map(|x| x.to_vec())
+}
+
+/// A single UDP socket shared by any number of concurrent requests. Responses are routed back to
+/// the request waiting on the same remote address and transaction id. Datagrams that don't
+/// answer one of our requests (like incoming DHT queries) go to the `incoming` subscriber or are
+/// dropped if there's none.
+///
+/// Addresses are always handed out in their canonical form, so IPv4 peers reaching a dual-stack
+/// socket show up as plain IPv4 addresses rather than IPv4-mapped IPv6 ones.
+#[derive(Debug)]
+pub struct UdpEndpoint {
+    socket: Arc<UdpSocket>,
+    dual_stack: bool,
+    pending: Arc<Mutex<PendingRequests>>,
+    incoming: Arc<Mutex<Option<IncomingSender>>>,
+    bandwidth: Arc<Mutex<Bandwidth>>,
+    next_transaction_id: AtomicU32,
+    receiver_task: JoinHandle<()>,
+}
+
+impl Drop for UdpEndpoint {
+    fn drop(&mut self) {
+        self.receiver_task.abort();
+    }
+}
+
+/// Forgets a pending request however it ends, including when the caller gives up on it through
+/// a timeout or an aborted task
+struct PendingGuard<'a> {
+    pending: &'a Mutex<PendingRequests>,
+    key: (SocketAddr, Vec<u8>),
+}
+
+impl Drop for PendingGuard<'_> {
+    fn drop(&mut self) {
+        self.pending.lock().unwrap().remove(&self.key);
+    }
+}
+
+impl UdpEndpoint {
+    /// Queued incoming datagrams. Anything beyond this is dropped until the subscriber catches up
+    const INCOMING_QUEUE_SIZE: usize = 1024;
+
+    pub async fn bind(addr: SocketAddr) -> io::Result<Arc<Self>> {
+        let socket = UdpSocket::bind(addr).await?;
+
+        Ok(Self::from_socket(socket, false))
+    }
+
+    /// Binds `[::]:port` with IPV6_V6ONLY turned off so the socket handles both families
+    pub async fn bind_dual_stack(port: u16) -> io::Result<Arc<Self>> {
+        let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
+
+        socket.set_only_v6(false)?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), port).into())?;
+
+        Ok(Self::from_socket(UdpSocket::from_std(socket.into())?, true))
+    }
+
+    fn from_socket(socket: UdpSocket, dual_stack: bool) -> Arc<Self> {
+        let socket = Arc::new(socket);
+        let pending: Arc<Mutex<PendingRequests>> = Arc::new(Mutex::new(HashMap::new()));
+        let incoming: Arc<Mutex<Option<IncomingSender>>> = Arc::new(Mutex::new(None));
+        let bandwidth = Arc::new(Mutex::new(Bandwidth::default()));
+
+        let receiver_task = tokio::spawn(Self::receive_loop(
+            socket.clone(),
+            pending.clone(),
+            incoming.clone(),
+            bandwidth.clone(),
+        ));
+
+        Arc::new(Self {
+            socket,
+            dual_stack,
+            pending,
+            incoming,
+            bandwidth,
+            next_transaction_id: AtomicU32::new(rand::thread_rng().gen()),
+            receiver_task,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Whether `addr` can be reached from this socket's address family
+    pub fn supports(&self, addr: &SocketAddr) -> bool {
+        match self.socket.local_addr() {
+            Ok(local) if local.is_ipv6() => self.dual_stack || addr.is_ipv6(),
+            Ok(_) => addr.is_ipv4(),
+            Err(_) => false,
+        }
+    }
+
+    /// IPv6 sockets can only send to IPv4 peers through IPv4-mapped addresses
+    fn to_socket_family(&self, addr: &SocketAddr) -> SocketAddr {
+        match addr {
+            SocketAddr::V4(v4) if self.dual_stack => SocketAddr::new(IpAddr::V6(v4.ip().to_ipv6_mapped()), v4.port()),
+            _ => *addr,
+        }
+    }
+
+    async fn receive_loop(
+        socket: Arc<UdpSocket>,
+        pending: Arc<Mutex<PendingRequests>>,
+        incoming: Arc<Mutex<Option<IncomingSender>>>,
+        bandwidth: Arc<Mutex<Bandwidth>>,
+    ) {
+        let mut buf = [0u8; 65_535];
+
+        loop {
+            let Ok((len, from)) = socket.recv_from(&mut buf).await else {
+                // Errors like ICMP port unreachable are reported on the next recv, keep going
+                continue;
+            };
+
+            // The datagram is already here, but holding off on the next one still caps the rate
+            let download = bandwidth.lock().unwrap().download.clone();
+            download.acquire(len).await;
+
+            let data = buf[..len].to_vec();
+            let from = SocketAddr::new(from.ip().to_canonical(), from.port());
+
+            let waiter = transaction_id(&data)
+                .and_then(|tx_id| pending.lock().unwrap().remove(&(from, tx_id)));
+
+            match waiter {
+                Some(waiter) => {
+                    let _ = waiter.send(data);
+                },
+                None => {
+                    if let Some(sender) = incoming.lock().unwrap().as_ref() {
+                        let _ = sender.try_send((data, from));
+                    }
+                },
+            }
+        }
+    }
+
+    /// Datagrams that aren't responses to our requests. Only the latest subscriber receives them
+    pub fn subscribe_incoming(&self) -> mpsc::Receiver<(Vec<u8>, SocketAddr)> {
+        let (sender, receiver) = mpsc::channel(Self::INCOMING_QUEUE_SIZE);
+
+        *self.incoming.lock().unwrap() = Some(sender);
+
+        receiver
+    }
+
+    pub fn next_transaction_id(&self) -> [u8; 4] {
+        self.next_transaction_id
+            .fetch_add(1, Ordering::Relaxed)
+            .to_be_bytes()
+    }
+
+    /// Limits tracker and DHT traffic on this socket. Unlimited by default
+    pub fn set_bandwidth(&self, bandwidth: Bandwidth) {
+        *self.bandwidth.lock().unwrap() = bandwidth;
+    }
+
+    async fn acquire_upload(&self, bytes: usize) {
+        let upload = self.bandwidth.lock().unwrap().upload.clone();
+
+        upload.acquire(bytes).await;
+    }
+
+    /// Sends a datagram without expecting anything back
+    pub async fn send_to(&self, addr: &SocketAddr, data: &[u8]) -> io::Result<()> {
+        self.acquire_upload(data.len()).await;
+        self.socket.send_to(data, self.to_socket_family(addr)).await?;
+
+        Ok(())
+    }
+
+    /// Sends `data` and waits for the datagram from `addr` carrying `transaction_id`, resending
+    /// the request on every timeout until the retries run out.
+    pub async fn request(
+        &self,
+        addr: &SocketAddr,
+        transaction_id: &[u8],
+        data: &[u8],
+        options: RequestOptions,
+    ) -> io::Result<Vec<u8>> {
+        let key = (SocketAddr::new(addr.ip().to_canonical(), addr.port()), transaction_id.to_vec());
+        let target = self.to_socket_family(addr);
+        let (sender, mut receiver) = oneshot::channel();
+
+        self.pending.lock().unwrap().insert(key.clone(), sender);
+        let _guard = PendingGuard { pending: &self.pending, key };
+
+        for _ in 0..=options.retries {
+            self.acquire_upload(data.len()).await;
+            self.socket.send_to(data, target).await?;
+
+            if let Ok(response) = timeout(options.timeout, &mut receiver).await {
+                return response.map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "UDP endpoint closed"));
+            }
+        }
+
+        Err(io::Error::new(io::ErrorKind::TimedOut, "UDP request timed out"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::net::UdpSocket;
+
+    use super::{RequestOptions, UdpEndpoint};
+
+    #[tokio::test]
+    async fn routes_concurrent_responses_by_transaction_id() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = server.local_addr().unwrap();
+
+        let endpoint = UdpEndpoint::bind("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let mut incoming = endpoint.subscribe_incoming();
+
+        let options = RequestOptions { timeout: Duration::from_secs(2), retries: 0 };
+        let first = endpoint.request(&server_addr, &[0, 0, 0, 1], &[0, 0, 0, 0, 0, 0, 0, 1], options);
+        let second = endpoint.request(&server_addr, &[0, 0, 0, 2], &[0, 0, 0, 0, 0, 0, 0, 2], options);
+
+        let serve = async {
+            let mut buf = [0u8; 64];
+            let mut requests = vec![];
+
+            for _ in 0..2 {
+                let (len, from) = server.recv_from(&mut buf).await.unwrap();
+                requests.push((buf[..len].to_vec(), from));
+            }
+
+            // Unsolicited packet first, then the responses in reverse order
+            server.send_to(&[9, 9, 9, 9, 0, 0, 0, 7], requests[0].1).await.unwrap();
+
+            for (request, from) in requests.iter().rev() {
+                let mut response = vec![1, 1, 1, 1];
+                response.extend_from_slice(&request[4..8]);
+
+                server.send_to(&response, from).await.unwrap();
+            }
+        };
+
+        let (first, second, _) = tokio::join!(first, second, serve);
+
+        assert_eq!(first.unwrap(), vec![1, 1, 1, 1, 0, 0, 0, 1]);
+        assert_eq!(second.unwrap(), vec![1, 1, 1, 1, 0, 0, 0, 2]);
+        assert_eq!(incoming.recv().await.unwrap().0, vec![9, 9, 9, 9, 0, 0, 0, 7]);
+    }
+
+    #[tokio::test]
+    async fn retries_and_times_out() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = server.local_addr().unwrap();
+
+        let endpoint = UdpEndpoint::bind("127.0.0.1:0".parse().unwrap()).await.unwrap();
+
+        let options = RequestOptions { timeout: Duration::from_millis(50), retries: 2 };
+        let result = endpoint.request(&server_addr, &[0, 0, 0, 1], &[0, 0, 0, 0, 0, 0, 0, 1], options).await;
+
+        assert_eq!(result.unwrap_err().kind(), std::io::ErrorKind::TimedOut);
+
+        let mut buf = [0u8; 64];
+        for _ in 0..3 {
+            server.recv_from(&mut buf).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn forgets_cancelled_requests() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = server.local_addr().unwrap();
+
+        let endpoint = UdpEndpoint::bind("127.0.0.1:0".parse().unwrap()).await.unwrap();
+
+        let options = RequestOptions { timeout: Duration::from_secs(5), retries: 0 };
+        let request = endpoint.request(&server_addr, &[0, 0, 0, 1], &[0, 0, 0, 0, 0, 0, 0, 1], options);
+
+        assert!(tokio::time::timeout(Duration::from_millis(50), request).await.is_err());
+        assert!(endpoint.pending.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn dual_stack_reaches_ipv4_peers() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = server.local_addr().unwrap();
+
+        let Ok(endpoint) = UdpEndpoint::bind_dual_stack(0).await else {
+            // No IPv6 in this environment
+            return;
+        };
+
+        assert!(endpoint.supports(&server_addr));
+
+        let options = RequestOptions { timeout: Duration::from_secs(2), retries: 0 };
+        let request = endpoint.request(&server_addr, &[0, 0, 0, 1], &[0, 0, 0, 0, 0, 0, 0, 1], options);
+
+        let serve = async {
+            let mut buf = [0u8; 64];
+            let (len, from) = server.recv_from(&mut buf).await.unwrap();
+
+            server.send_to(&buf[..len], from).await.unwrap();
+        };
+
+        let (response, _) = tokio::join!(request, serve);
+
+        assert_eq!(response.unwrap(), vec![0, 0, 0, 0, 0, 0, 0, 1]);
+    }
 }
@@ -0,0 +1,162 @@
+use std::{
+    io,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use futures::future::try_join_all;
+
+use crate::{bittorrent::bitfield::Bitfield, storage::Storage};
+
+/// Reported after every checked piece
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecheckProgress {
+    pub checked: usize,
+    pub total: usize,
+    pub valid: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecheckResult {
+    pub have: Bitfield,
+
+    /// Fraction of every file's bytes that are covered by valid pieces, from 0 to 1
+    pub file_completion: Vec<f64>,
+}
+
+/// Hashes every piece on disk against the metainfo, e.g. to seed data that was downloaded by
+/// something else. Pieces are checked by as many workers as there are CPU cores.
+pub async fn recheck(
+    storage: &Storage,
+    progress: impl Fn(RecheckProgress) + Send + Sync + 'static,
+) -> io::Result<RecheckResult> {
+    let total = storage.piece_count();
+    let workers = std::thread::available_parallelism().map(|x| x.get()).unwrap_or(1).min(total.max(1));
+
+    let next = Arc::new(AtomicUsize::new(0));
+    let state = Arc::new(Mutex::new((Bitfield::new(total), 0usize)));
+    let progress = Arc::new(progress);
+
+    let tasks = (0..workers).map(|_| {
+        let storage = storage.clone();
+        let next = next.clone();
+        let state = state.clone();
+        let progress = progress.clone();
+
+        async move {
+            loop {
+                let index = next.fetch_add(1, Ordering::Relaxed);
+
+                if index >= total {
+                    return Ok::<(), io::Error>(());
+                }
+
+                let valid = storage.verify_piece(index as u32).await?;
+
+                // Reported under the lock so callbacks see `checked` go up one at a time
+                let mut state = state.lock().unwrap();
+                let (have, checked) = &mut *state;
+
+                if valid {
+                    have.set(index);
+                }
+
+                *checked += 1;
+
+                progress(RecheckProgress { checked: *checked, total, valid: have.count() });
+            }
+        }
+    });
+
+    try_join_all(tasks).await?;
+
+    let have = state.lock().unwrap().0.clone();
+    let file_completion = file_completion(storage, &have);
+
+    Ok(RecheckResult { have, file_completion })
+}
+
+/// Fraction of every file that's covered by pieces in `have`. Empty files count as complete
+pub fn file_completion(storage: &Storage, have: &Bitfield) -> Vec<f64> {
+    storage
+        .file_ranges()
+        .into_iter()
+        .enumerate()
+        .map(|(file_index, (offset, length))| {
+            if length == 0 {
+                return 1.0;
+            }
+
+            let complete: u64 = storage
+                .file_pieces(file_index)
+                .filter(|index| have.has(*index as usize))
+                .map(|index| {
+                    let start = storage.piece_offset(index);
+                    let end = start + storage.piece_size(index);
+
+                    end.min(offset + length) - start.max(offset)
+                })
+                .sum();
+
+            complete as f64 / length as f64
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use sha1::{Digest, Sha1};
+
+    use crate::{
+        metainfo::{FileEntry, FileLayout, Info},
+        storage::Storage,
+        utils::testing::TempDir,
+    };
+
+    use super::{recheck, RecheckProgress};
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn finds_valid_pieces_and_file_completion() {
+        let data = (0..20).collect::<Vec<u8>>();
+        let dir = TempDir::new("recheck-check");
+
+        let info = Info {
+            name: "test".to_owned(),
+            piece_length: 4,
+            pieces: data.chunks(4).map(|x| Sha1::digest(x).into()).collect(),
+            private: false,
+            layout: FileLayout::Multi {
+                files: vec![
+                    FileEntry { length: 6, path: vec!["a".to_owned()] },
+                    FileEntry { length: 14, path: vec!["b".to_owned()] },
+                ],
+            },
+        };
+
+        // The first file is intact, the second one is cut short and has a corrupt byte
+        let mut b = data[6..18].to_vec();
+        b[5] ^= 0xff;
+
+        std::fs::create_dir_all(dir.join("test")).unwrap();
+        std::fs::write(dir.join("test/a"), &data[0..6]).unwrap();
+        std::fs::write(dir.join("test/b"), &b).unwrap();
+
+        let reports = Arc::new(Mutex::new(vec![]));
+        let reported = reports.clone();
+
+        let storage = Storage::new(&info, &dir);
+        let result = recheck(&storage, move |x| reported.lock().unwrap().push(x)).await.unwrap();
+
+        // Piece 2 has the corrupt byte and piece 4 is past the end of the second file
+        assert_eq!(result.have.iter_set().collect::<Vec<usize>>(), vec![0, 1, 3]);
+        assert_eq!(result.file_completion, vec![1.0, 6.0 / 14.0]);
+
+        let reports = reports.lock().unwrap();
+        assert_eq!(reports.len(), 5);
+        assert_eq!(reports.last(), Some(&RecheckProgress { checked: 5, total: 5, valid: 3 }));
+    }
+}
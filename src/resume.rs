@@ -0,0 +1,336 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io,
+    net::SocketAddr,
+    path::Path,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use crate::{
+    bittorrent::{bitfield::Bitfield, piece_picker::PiecePicker},
+    net::compact::{encode_compact_peer, parse_compact_peers},
+    storage::Storage,
+    utils::bencode::{BencodeParser, BencodeValue},
+};
+
+/// Size and modification time of a file when the resume data was written. A missing file has
+/// both set to 0
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct FileState {
+    pub length: u64,
+    pub mtime: u64,
+}
+
+impl FileState {
+    pub fn read(path: &Path) -> Self {
+        let Ok(metadata) = fs::metadata(path) else {
+            return Self::default();
+        };
+
+        let mtime = metadata
+            .modified()
+            .ok()
+            .and_then(|x| x.duration_since(UNIX_EPOCH).ok())
+            .map(|x| x.as_secs())
+            .unwrap_or(0);
+
+        Self { length: metadata.len(), mtime }
+    }
+}
+
+/// Everything needed to pick a download back up without hashing all of it again.
+///
+/// The file states are checked against the files on disk when loading. Pieces in files that
+/// changed since (different size or mtime) are dropped and will be downloaded or checked again.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResumeData {
+    pub info_hash: [u8; 20],
+    pub have: Bitfield,
+    pub files: Vec<FileState>,
+
+    /// Blocks received for incomplete pieces
+    pub partial: HashMap<u32, Vec<u32>>,
+    pub peers: Vec<SocketAddr>,
+    pub uploaded: u64,
+    pub downloaded: u64,
+}
+
+impl ResumeData {
+    pub const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+    /// Takes the current state of a download. The file states are read from disk so this should
+    /// happen after any pending writes went through
+    pub fn capture(
+        info_hash: [u8; 20],
+        picker: &PiecePicker,
+        storage: &Storage,
+        peers: Vec<SocketAddr>,
+        uploaded: u64,
+        downloaded: u64,
+    ) -> Self {
+        Self {
+            info_hash,
+            have: picker.have().clone(),
+            files: storage.file_paths().iter().map(|x| FileState::read(x)).collect(),
+            partial: picker.received_blocks(),
+            peers,
+            uploaded,
+            downloaded,
+        }
+    }
+
+    /// Hands the pieces and blocks back to a fresh picker
+    pub fn restore(&self, picker: &mut PiecePicker) {
+        picker.set_have(self.have.clone());
+
+        for (index, blocks) in &self.partial {
+            picker.restore_blocks(*index, blocks);
+        }
+    }
+
+    /// Drops everything that can't be trusted anymore given the files currently on disk. Fails if
+    /// the data belongs to a different torrent
+    pub fn validate(&mut self, info_hash: &[u8; 20], storage: &Storage) -> Result<(), String> {
+        if self.info_hash != *info_hash {
+            return Err("Resume data is for another torrent".to_owned());
+        }
+
+        if self.have.len() != storage.piece_count() || self.files.len() != storage.file_paths().len() {
+            return Err("Resume data doesn't match the torrent's layout".to_owned());
+        }
+
+        for (file_index, path) in storage.file_paths().iter().enumerate() {
+            if FileState::read(path) == self.files[file_index] {
+                continue;
+            }
+
+            for index in storage.file_pieces(file_index) {
+                self.have.unset(index as usize);
+                self.partial.remove(&index);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reads and validates the resume data at `path`
+    pub fn load(path: &Path, info_hash: &[u8; 20], storage: &Storage) -> Result<Self, String> {
+        let data = fs::read(path).map_err(|x| format!("Failed to read resume data: {}", x))?;
+        let mut resume = Self::deserialize(&data)?;
+
+        resume.validate(info_hash, storage)?;
+
+        Ok(resume)
+    }
+
+    /// Written to a temporary file first so a crash halfway leaves the old data intact
+    pub async fn save(&self, path: &Path) -> io::Result<()> {
+        let temp_path = path.with_extension("tmp");
+
+        tokio::fs::write(&temp_path, self.serialize()).await?;
+        tokio::fs::rename(&temp_path, path).await
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let files = self.files
+            .iter()
+            .map(|file| {
+                BencodeValue::Dict(HashMap::from([
+                    ("length".as_bytes().to_vec(), BencodeValue::Integer(file.length as i64)),
+                    ("mtime".as_bytes().to_vec(), BencodeValue::Integer(file.mtime as i64)),
+                ]))
+            })
+            .collect();
+
+        let partial = self.partial
+            .iter()
+            .map(|(index, blocks)| {
+                BencodeValue::Dict(HashMap::from([
+                    ("piece".as_bytes().to_vec(), BencodeValue::Integer(*index as i64)),
+                    (
+                        "blocks".as_bytes().to_vec(),
+                        BencodeValue::List(blocks.iter().map(|x| BencodeValue::Integer(*x as i64)).collect()),
+                    ),
+                ]))
+            })
+            .collect();
+
+        let compact_peers = |ipv6: bool| {
+            self.peers
+                .iter()
+                .filter(|x| x.is_ipv6() == ipv6)
+                .flat_map(encode_compact_peer)
+                .collect::<Vec<u8>>()
+        };
+
+        BencodeValue::Dict(HashMap::from([
+            ("info-hash".as_bytes().to_vec(), BencodeValue::Bytes(self.info_hash.to_vec())),
+            ("pieces".as_bytes().to_vec(), BencodeValue::Bytes(self.have.as_bytes().to_vec())),
+            ("piece count".as_bytes().to_vec(), BencodeValue::Integer(self.have.len() as i64)),
+            ("files".as_bytes().to_vec(), BencodeValue::List(files)),
+            ("partial".as_bytes().to_vec(), BencodeValue::List(partial)),
+            ("peers".as_bytes().to_vec(), BencodeValue::Bytes(compact_peers(false))),
+            ("peers6".as_bytes().to_vec(), BencodeValue::Bytes(compact_peers(true))),
+            ("uploaded".as_bytes().to_vec(), BencodeValue::Integer(self.uploaded as i64)),
+            ("downloaded".as_bytes().to_vec(), BencodeValue::Integer(self.downloaded as i64)),
+        ]))
+        .serialize()
+    }
+
+    pub fn deserialize(data: &[u8]) -> Result<Self, String> {
+        let value = BencodeParser::new(data)
+            .parse_value()
+            .map_err(|_| "Failed to parse resume data")?;
+
+        let dict = value.dict().map_err(|_| "Resume data should be a dict")?;
+
+        let get_integer = |dict: &HashMap<Vec<u8>, BencodeValue>, key: &str| {
+            dict.get(key.as_bytes())
+                .and_then(|x| x.integer().ok())
+                .and_then(|x| u64::try_from(*x).ok())
+                .ok_or(format!("Failed to parse resume data {}", key))
+        };
+
+        let get_bytes = |key: &str| {
+            dict.get(key.as_bytes())
+                .and_then(|x| x.bytes().ok())
+                .ok_or(format!("Failed to parse resume data {}", key))
+        };
+
+        let get_list = |key: &str| {
+            dict.get(key.as_bytes())
+                .and_then(|x| x.list().ok())
+                .ok_or(format!("Failed to parse resume data {}", key))
+        };
+
+        let info_hash: [u8; 20] = get_bytes("info-hash")?
+            .clone()
+            .try_into()
+            .map_err(|_| "Resume data info-hash should be 20 bytes")?;
+
+        let have = Bitfield::from_bytes(get_bytes("pieces")?, get_integer(dict, "piece count")? as usize)
+            .ok_or("Resume data pieces don't match the piece count")?;
+
+        let files = get_list("files")?
+            .iter()
+            .map(|file| {
+                let file = file.dict().map_err(|_| "Resume data files should be dicts")?;
+
+                Ok(FileState {
+                    length: get_integer(file, "length")?,
+                    mtime: get_integer(file, "mtime")?,
+                })
+            })
+            .collect::<Result<Vec<FileState>, String>>()?;
+
+        let mut partial = HashMap::new();
+
+        for piece in get_list("partial")? {
+            let piece = piece.dict().map_err(|_| "Resume data partial pieces should be dicts")?;
+
+            let blocks = piece
+                .get("blocks".as_bytes())
+                .and_then(|x| x.list().ok())
+                .ok_or("Failed to parse resume data blocks")?
+                .iter()
+                .filter_map(|x| x.integer().ok().and_then(|x| u32::try_from(*x).ok()))
+                .collect();
+
+            partial.insert(get_integer(piece, "piece")? as u32, blocks);
+        }
+
+        let mut peers = parse_compact_peers(get_bytes("peers")?, false);
+        peers.extend(parse_compact_peers(get_bytes("peers6")?, true));
+
+        Ok(Self {
+            info_hash,
+            have,
+            files,
+            partial,
+            peers,
+            uploaded: get_integer(dict, "uploaded")?,
+            downloaded: get_integer(dict, "downloaded")?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, time::{Duration, SystemTime}};
+
+    use sha1::{Digest, Sha1};
+
+    use crate::{
+        bittorrent::{bitfield::Bitfield, peer_client::BLOCK_SIZE, piece_picker::PiecePicker},
+        metainfo::{FileEntry, FileLayout, Info},
+        storage::Storage,
+        utils::testing::TempDir,
+    };
+
+    use super::{FileState, ResumeData};
+
+    /// 2 files of 8 bytes with 4 byte pieces
+    fn test_info() -> Info {
+        Info {
+            name: "test".to_owned(),
+            piece_length: 4,
+            pieces: [0u8; 16].chunks(4).map(|x| Sha1::digest(x).into()).collect(),
+            private: false,
+            layout: FileLayout::Multi {
+                files: vec![
+                    FileEntry { length: 8, path: vec!["a".to_owned()] },
+                    FileEntry { length: 8, path: vec!["b".to_owned()] },
+                ],
+            },
+        }
+    }
+
+    #[test]
+    fn round_trips() {
+        let mut have = Bitfield::new(10);
+        have.set(0);
+        have.set(9);
+
+        let resume = ResumeData {
+            info_hash: [3; 20],
+            have,
+            files: vec![FileState { length: 5, mtime: 1_700_000_000 }, FileState::default()],
+            partial: HashMap::from([(4, vec![0, 2])]),
+            peers: vec!["10.0.0.1:6881".parse().unwrap(), "[2001:db8::1]:6881".parse().unwrap()],
+            uploaded: 100,
+            downloaded: 200,
+        };
+
+        assert_eq!(ResumeData::deserialize(&resume.serialize()).unwrap(), resume);
+    }
+
+    #[tokio::test]
+    async fn drops_pieces_of_changed_files() {
+        let dir = TempDir::new("resume-validate");
+        let info = test_info();
+        let storage = Storage::new(&info, &dir);
+
+        storage.write_block(0, 0, vec![0; 4]).await.unwrap();
+        storage.write_block(3, 0, vec![0; 4]).await.unwrap();
+
+        let mut picker = PiecePicker::new(BLOCK_SIZE as u64, 4 * BLOCK_SIZE as u64);
+        picker.piece_verified(0);
+        picker.piece_verified(3);
+
+        let resume = ResumeData::capture([1; 20], &picker, &storage, vec![], 0, 16);
+        let path = dir.join("test.resume");
+        resume.save(&path).await.unwrap();
+
+        let loaded = ResumeData::load(&path, &[1; 20], &storage).unwrap();
+        assert_eq!(loaded.have.iter_set().collect::<Vec<usize>>(), vec![0, 3]);
+        assert!(ResumeData::load(&path, &[2; 20], &storage).is_err());
+
+        // Touching the second file invalidates its pieces only
+        let file = std::fs::File::options().write(true).open(dir.join("test/b")).unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(10)).unwrap();
+
+        let loaded = ResumeData::load(&path, &[1; 20], &storage).unwrap();
+        assert_eq!(loaded.have.iter_set().collect::<Vec<usize>>(), vec![0]);
+    }
+}
@@ -0,0 +1,691 @@
+use std::{
+    io,
+    net::{Ipv6Addr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::{sync::Mutex as AsyncMutex, task::JoinHandle};
+
+use crate::{
+    bittorrent::{
+        choker::ChokerConfig,
+        listener::{ConnectionLimits, PeerListener},
+    },
+    dht_client::DHTClient,
+    kademlia::RoutingTable,
+    magnet::{Magnet, MagnetError},
+    metainfo::{Metainfo, MetainfoError},
+    net::{
+        rate_limit::{Bandwidth, RateLimiter},
+        udp::UdpEndpoint,
+    },
+    storage::{Allocation, Storage},
+    torrent_session::{SessionContext, TorrentConfig, TorrentSession, TorrentState, TorrentStatus},
+    utils::hex::encode_hex,
+};
+
+#[derive(Debug)]
+pub enum SessionError {
+    InvalidMagnet(MagnetError),
+    InvalidTorrent(MetainfoError),
+
+    /// Only v1 torrents are supported
+    MissingInfoHash,
+    AlreadyAdded,
+    UnknownTorrent,
+    Io(io::Error),
+}
+
+impl From<io::Error> for SessionError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+/// How many torrents may run at once. The rest wait in the order they were added
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueLimits {
+    pub active_downloads: usize,
+    pub active_seeds: usize,
+}
+
+impl Default for QueueLimits {
+    fn default() -> Self {
+        Self {
+            active_downloads: 5,
+            active_seeds: 10,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SessionConfig {
+    pub download_dir: PathBuf,
+
+    /// Resume data (`<info-hash>.resume`) and the DHT routing table are kept here. Without it
+    /// every start checks the files and bootstraps the DHT from scratch
+    pub state_dir: Option<PathBuf>,
+
+    /// TCP for peers, and UDP for trackers and the DHT. `[::]` takes both IPv4 and IPv6
+    pub listen_addr: SocketAddr,
+
+    /// Where to join the DHT, `None` to not use it
+    pub dht_router: Option<String>,
+
+    /// Peer connections over all torrents
+    pub max_connections: usize,
+    pub max_connections_per_torrent: usize,
+    pub queue: QueueLimits,
+    pub choker: ChokerConfig,
+    pub allocation: Allocation,
+
+    /// Bytes per second over all torrents, `None` for unlimited
+    pub upload_rate: Option<u64>,
+    pub download_rate: Option<u64>,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            download_dir: PathBuf::from("."),
+            state_dir: None,
+            listen_addr: (Ipv6Addr::UNSPECIFIED, 6881).into(),
+            dht_router: Some("router.bittorrent.com:6881".to_owned()),
+            max_connections: 500,
+            max_connections_per_torrent: 50,
+            queue: QueueLimits::default(),
+            choker: ChokerConfig::default(),
+            allocation: Allocation::default(),
+            upload_rate: None,
+            download_rate: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Inner {
+    context: SessionContext,
+    config: SessionConfig,
+    queue_limits: Mutex<QueueLimits>,
+
+    /// In queue order, the first ones get the active slots
+    torrents: Mutex<Vec<Arc<TorrentSession>>>,
+
+    /// Only one pass over the queue at a time, otherwise two could start the same slot's torrent
+    queue_lock: AsyncMutex<()>,
+
+    /// Latest thing that went wrong with the DHT, see `Session::dht_error`
+    dht_error: Arc<Mutex<Option<String>>>,
+
+    upload_limiter: Arc<RateLimiter>,
+    download_limiter: Arc<RateLimiter>,
+}
+
+/// Runs many torrents in one process.
+///
+/// Every torrent shares the same listen port, UDP socket (trackers and DHT), DHT node, global
+/// rate limits and connection caps. Which torrents run is decided by the queue: the first
+/// `QueueLimits::active_downloads` downloading and `QueueLimits::active_seeds` seeding torrents
+/// in the order they were added get to run, the rest are `TorrentState::Queued`. Torrents paused
+/// by the user don't take a slot.
+#[derive(Debug)]
+pub struct Session {
+    inner: Arc<Inner>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+impl Session {
+    /// How often the queue is checked for finished downloads and freed slots
+    const QUEUE_INTERVAL: Duration = Duration::from_secs(1);
+
+    /// How often the DHT routing table is saved, besides on shutdown
+    const DHT_SAVE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+    /// Routing table file in the state dir
+    const DHT_STATE_FILE: &'static str = "dht.dat";
+
+    pub async fn new(config: SessionConfig) -> io::Result<Self> {
+        let peer_id = rand::random::<[u8; 20]>();
+
+        if let Some(dir) = &config.state_dir {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+
+        let endpoint = match config.listen_addr {
+            SocketAddr::V6(addr) if addr.ip().is_unspecified() => UdpEndpoint::bind_dual_stack(addr.port()).await?,
+            addr => UdpEndpoint::bind(addr).await?,
+        };
+        let listener = PeerListener::bind(config.listen_addr, ConnectionLimits::new(config.max_connections)).await?;
+
+        let upload_limiter = RateLimiter::new(config.upload_rate);
+        let download_limiter = RateLimiter::new(config.download_rate);
+
+        // Tracker and DHT traffic count towards the global limits too
+        let bandwidth = Bandwidth::default().with(upload_limiter.clone(), download_limiter.clone());
+        endpoint.set_bandwidth(bandwidth.clone());
+
+        let mut tasks = vec![];
+        let dht_error = Arc::new(Mutex::new(None));
+
+        let dht = match &config.dht_router {
+            Some(router) => {
+                Self::start_dht(peer_id, router, endpoint.clone(), config.state_dir.as_deref(), &dht_error, &mut tasks).await
+            },
+            None => None,
+        };
+
+        let context = SessionContext {
+            peer_id,
+            port: listener.local_addr().port(),
+            endpoint,
+            dht,
+            listener: Arc::new(listener),
+            bandwidth,
+        };
+
+        let inner = Arc::new(Inner {
+            context,
+            queue_limits: Mutex::new(config.queue),
+            config,
+            torrents: Mutex::new(vec![]),
+            queue_lock: AsyncMutex::new(()),
+            dht_error,
+            upload_limiter,
+            download_limiter,
+        });
+
+        tasks.push(tokio::spawn(Self::queue_loop(inner.clone())));
+
+        if inner.context.dht.is_some() && inner.config.state_dir.is_some() {
+            tasks.push(tokio::spawn(Self::save_dht_periodically(inner.clone())));
+        }
+
+        Ok(Self { inner, tasks })
+    }
+
+    /// Joins the DHT through `router`, starting from the routing table of the previous run if
+    /// there is one. `None` if the router can't be resolved
+    async fn start_dht(
+        node_id: [u8; 20],
+        router: &str,
+        endpoint: Arc<UdpEndpoint>,
+        state_dir: Option<&Path>,
+        dht_error: &Arc<Mutex<Option<String>>>,
+        tasks: &mut Vec<JoinHandle<()>>,
+    ) -> Option<Arc<DHTClient>> {
+        let root_node = tokio::net::lookup_host(router)
+            .await
+            .ok()?
+            .find(|x| endpoint.supports(x))?;
+
+        let saved = state_dir.and_then(|x| RoutingTable::load(&x.join(Self::DHT_STATE_FILE)).ok());
+
+        // The table only makes sense around the node id it was built for, so that one is kept
+        let dht = match saved {
+            Some(table) => DHTClient::with_routing_table(table.own_id, root_node, endpoint, table),
+            None => DHTClient::new(node_id, root_node, endpoint),
+        };
+
+        let dht = Arc::new(dht);
+
+        let server = dht.clone();
+        let error = dht_error.clone();
+        tasks.push(tokio::spawn(async move {
+            if let Err(err) = server.serve().await {
+                *error.lock().unwrap() = Some(format!("DHT stopped: {}", err));
+            }
+        }));
+
+        // Lookups can take a while, torrents are added meanwhile and use the DHT once it's ready
+        let bootstrap = dht.clone();
+        let error = dht_error.clone();
+        tasks.push(tokio::spawn(async move {
+            if let Err(err) = bootstrap.bootstrap().await {
+                *error.lock().unwrap() = Some(format!("Failed to bootstrap the DHT: {}", err));
+            }
+        }));
+
+        Some(dht)
+    }
+
+    /// Why the DHT stopped, failed to bootstrap or couldn't be saved, if it did
+    pub fn dht_error(&self) -> Option<String> {
+        self.inner.dht_error.lock().unwrap().clone()
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.inner.context.listener.local_addr()
+    }
+
+    fn torrent_config(&self, info_hash: &[u8; 20]) -> TorrentConfig {
+        let config = &self.inner.config;
+
+        TorrentConfig {
+            download_dir: config.download_dir.clone(),
+            resume_path: config.state_dir.as_ref().map(|x| Self::resume_path(x, info_hash)),
+            allocation: config.allocation,
+            max_connections: config.max_connections_per_torrent,
+            choker: config.choker,
+            upload_rate: None,
+            download_rate: None,
+        }
+    }
+
+    fn resume_path(dir: &Path, info_hash: &[u8; 20]) -> PathBuf {
+        dir.join(format!("{}.resume", encode_hex(info_hash)))
+    }
+
+    /// Queues a torrent from a magnet link, its metadata is fetched from peers once it gets to run
+    pub async fn add_magnet(&self, uri: &str) -> Result<[u8; 20], SessionError> {
+        let magnet = Magnet::parse(uri).map_err(SessionError::InvalidMagnet)?;
+        let info_hash = magnet.info_hash.ok_or(SessionError::MissingInfoHash)?;
+
+        let torrent = TorrentSession::from_magnet(&magnet, self.inner.context.clone(), self.torrent_config(&info_hash))
+            .ok_or(SessionError::MissingInfoHash)?;
+
+        self.add(torrent).await
+    }
+
+    pub async fn add_torrent_file(&self, path: &Path) -> Result<[u8; 20], SessionError> {
+        let data = tokio::fs::read(path).await?;
+        let metainfo = Metainfo::from_bytes(&data).map_err(SessionError::InvalidTorrent)?;
+
+        self.add_metainfo(metainfo).await
+    }
+
+    pub async fn add_metainfo(&self, metainfo: Metainfo) -> Result<[u8; 20], SessionError> {
+        let config = self.torrent_config(&metainfo.info_hash);
+        let torrent = TorrentSession::from_metainfo(metainfo, self.inner.context.clone(), config);
+
+        self.add(torrent).await
+    }
+
+    async fn add(&self, torrent: TorrentSession) -> Result<[u8; 20], SessionError> {
+        let info_hash = torrent.info_hash();
+
+        {
+            let mut torrents = self.inner.torrents.lock().unwrap();
+
+            if torrents.iter().any(|x| x.info_hash() == info_hash) {
+                return Err(SessionError::AlreadyAdded);
+            }
+
+            torrents.push(Arc::new(torrent));
+        }
+
+        Inner::apply_queue(&self.inner).await;
+
+        Ok(info_hash)
+    }
+
+    /// Stops the torrent and forgets about it, along with its resume data. The downloaded files
+    /// are only removed with `delete_files`
+    pub async fn remove(&self, info_hash: &[u8; 20], delete_files: bool) -> Result<(), SessionError> {
+        let torrent = {
+            let mut torrents = self.inner.torrents.lock().unwrap();
+
+            let position = torrents
+                .iter()
+                .position(|x| x.info_hash() == *info_hash)
+                .ok_or(SessionError::UnknownTorrent)?;
+
+            torrents.remove(position)
+        };
+
+        torrent.stop().await;
+
+        if let Some(dir) = &self.inner.config.state_dir {
+            match tokio::fs::remove_file(Self::resume_path(dir, info_hash)).await {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err.into()),
+                _ => {},
+            }
+        }
+
+        if let (true, Some(metainfo)) = (delete_files, torrent.metainfo()) {
+            let storage = Storage::new(&metainfo.info, &self.inner.config.download_dir);
+
+            let root = self.inner.config.download_dir.join(&metainfo.info.name);
+            let mut dirs = vec![];
+
+            for path in storage.file_paths() {
+                match tokio::fs::remove_file(&path).await {
+                    Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err.into()),
+                    _ => {},
+                }
+
+                dirs.extend(path.ancestors().skip(1).take_while(|x| x.starts_with(&root)).map(|x| x.to_path_buf()));
+            }
+
+            // Deepest first so parents are empty by the time we get to them. Directories that
+            // still hold something else are left alone
+            dirs.sort();
+            dirs.dedup();
+            dirs.sort_by_key(|x| std::cmp::Reverse(x.components().count()));
+
+            for dir in dirs {
+                let _ = tokio::fs::remove_dir(dir).await;
+            }
+        }
+
+        Inner::apply_queue(&self.inner).await;
+
+        Ok(())
+    }
+
+    pub fn torrent(&self, info_hash: &[u8; 20]) -> Option<Arc<TorrentSession>> {
+        self.inner.torrents
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|x| x.info_hash() == *info_hash)
+            .cloned()
+    }
+
+    /// Status of every torrent in queue order
+    pub fn torrents(&self) -> Vec<TorrentStatus> {
+        self.inner.torrents.lock().unwrap().iter().map(|x| x.status()).collect()
+    }
+
+    /// Paused torrents stay paused until resumed and don't count towards the queue limits
+    pub async fn pause(&self, info_hash: &[u8; 20]) -> Result<(), SessionError> {
+        let torrent = self.torrent(info_hash).ok_or(SessionError::UnknownTorrent)?;
+
+        torrent.pause().await;
+        Inner::apply_queue(&self.inner).await;
+
+        Ok(())
+    }
+
+    /// Puts the torrent back in the queue, it runs once there's a slot for it
+    pub async fn resume(&self, info_hash: &[u8; 20]) -> Result<(), SessionError> {
+        let torrent = self.torrent(info_hash).ok_or(SessionError::UnknownTorrent)?;
+
+        if torrent.state() == TorrentState::Paused {
+            torrent.queue().await;
+        }
+
+        Inner::apply_queue(&self.inner).await;
+
+        Ok(())
+    }
+
+    /// Bytes per second over all torrents, `None` for unlimited
+    pub fn set_rate_limits(&self, upload: Option<u64>, download: Option<u64>) {
+        self.inner.upload_limiter.set_rate(upload);
+        self.inner.download_limiter.set_rate(download);
+    }
+
+    pub async fn set_queue_limits(&self, limits: QueueLimits) {
+        *self.inner.queue_limits.lock().unwrap() = limits;
+
+        Inner::apply_queue(&self.inner).await;
+    }
+
+    /// Stops every torrent, saving their resume data and the DHT routing table. The session can't
+    /// be used afterwards. Fails if the routing table couldn't be saved
+    pub async fn shutdown(&self) -> io::Result<()> {
+        // Otherwise the queue would start them right back up
+        for task in &self.tasks {
+            task.abort();
+        }
+
+        let torrents = self.inner.torrents.lock().unwrap().clone();
+
+        for torrent in torrents {
+            torrent.stop().await;
+        }
+
+        self.inner.save_dht()
+    }
+
+    async fn save_dht_periodically(inner: Arc<Inner>) {
+        let mut interval = tokio::time::interval(Self::DHT_SAVE_INTERVAL);
+
+        // Nothing new to save right after starting
+        interval.tick().await;
+
+        loop {
+            interval.tick().await;
+
+            if let Err(err) = inner.save_dht() {
+                *inner.dht_error.lock().unwrap() = Some(format!("Failed to save the DHT routing table: {}", err));
+            }
+        }
+    }
+
+    async fn queue_loop(inner: Arc<Inner>) {
+        let mut interval = tokio::time::interval(Self::QUEUE_INTERVAL);
+
+        loop {
+            interval.tick().await;
+
+            Inner::apply_queue(&inner).await;
+        }
+    }
+}
+
+impl Inner {
+    fn save_dht(&self) -> io::Result<()> {
+        match (&self.context.dht, &self.config.state_dir) {
+            (Some(dht), Some(dir)) => dht.save_routing_table(&dir.join(Session::DHT_STATE_FILE)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Starts the torrents that got a slot and queues the ones that lost theirs, e.g. because a
+    /// download finished and now needs a seeding slot
+    async fn apply_queue(inner: &Arc<Self>) {
+        let _guard = inner.queue_lock.lock().await;
+
+        let limits = *inner.queue_limits.lock().unwrap();
+        let torrents = inner.torrents.lock().unwrap().clone();
+
+        let mut downloads = 0;
+        let mut seeds = 0;
+
+        for torrent in torrents {
+            let status = torrent.status();
+
+            if status.state == TorrentState::Paused {
+                continue;
+            }
+
+            // Torrents that never ran don't know what they have yet, they count as downloads
+            let (active, limit) = if status.state == TorrentState::Seeding || status.left == Some(0) {
+                (&mut seeds, limits.active_seeds)
+            } else {
+                (&mut downloads, limits.active_downloads)
+            };
+
+            if *active < limit {
+                *active += 1;
+                torrent.start();
+            } else if status.state != TorrentState::Queued {
+                torrent.queue().await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+    use crate::{
+        dht_client::{CompactNodeInfo, DHTClient},
+        metainfo::Metainfo,
+        net::udp::UdpEndpoint,
+        torrent_session::TorrentState,
+        utils::testing::{multi_file_info, single_file_info, TempDir},
+    };
+
+    use super::{QueueLimits, Session, SessionConfig};
+
+    fn metainfo(name: &str) -> Metainfo {
+        Metainfo::from_info_bytes(&single_file_info(name, 16, &[0; 16])).unwrap()
+    }
+
+    fn multi_file_metainfo() -> Metainfo {
+        let info_bytes = multi_file_info("dir", 16, &[("sub/a", &[0; 8]), ("b", &[0; 8])]);
+
+        Metainfo::from_info_bytes(&info_bytes).unwrap()
+    }
+
+    fn states(session: &Session) -> Vec<TorrentState> {
+        session.torrents().iter().map(|x| x.state).collect()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn queues_beyond_active_limits() {
+        let dir = TempDir::new("session-queue");
+
+        let config = SessionConfig {
+            download_dir: dir.to_path_buf(),
+            listen_addr: "127.0.0.1:0".parse().unwrap(),
+            dht_router: None,
+            queue: QueueLimits { active_downloads: 1, active_seeds: 1 },
+            ..SessionConfig::default()
+        };
+
+        let session = Session::new(config).await.unwrap();
+
+        let first = session.add_metainfo(metainfo("a")).await.unwrap();
+        session.add_metainfo(metainfo("b")).await.unwrap();
+
+        assert!(session.add_metainfo(metainfo("a")).await.is_err());
+        assert_eq!(states(&session)[1], TorrentState::Queued);
+
+        // Pausing frees the slot for the next one in line
+        session.pause(&first).await.unwrap();
+        assert_eq!(states(&session)[0], TorrentState::Paused);
+        assert_ne!(states(&session)[1], TorrentState::Queued);
+
+        // Resuming keeps the queue position, so it takes the slot back
+        session.resume(&first).await.unwrap();
+        assert_ne!(states(&session)[0], TorrentState::Queued);
+        assert_eq!(states(&session)[1], TorrentState::Queued);
+
+        session.remove(&first, true).await.unwrap();
+        assert_eq!(session.torrents().len(), 1);
+        assert_ne!(states(&session)[0], TorrentState::Queued);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn removes_files_and_empty_directories() {
+        let dir = TempDir::new("session-remove");
+
+        let config = SessionConfig {
+            download_dir: dir.to_path_buf(),
+            listen_addr: "127.0.0.1:0".parse().unwrap(),
+            dht_router: None,
+            ..SessionConfig::default()
+        };
+
+        let session = Session::new(config).await.unwrap();
+        let info_hash = session.add_metainfo(multi_file_metainfo()).await.unwrap();
+
+        while !dir.join("dir/sub/a").exists() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        session.remove(&info_hash, true).await.unwrap();
+
+        assert!(!dir.join("dir").exists());
+        assert!(dir.exists());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn finished_torrents_move_to_seed_slots() {
+        let dir = TempDir::new("session-seed");
+
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("complete"), [0u8; 16]).unwrap();
+
+        let config = SessionConfig {
+            download_dir: dir.to_path_buf(),
+            listen_addr: "127.0.0.1:0".parse().unwrap(),
+            dht_router: None,
+            queue: QueueLimits { active_downloads: 1, active_seeds: 1 },
+            ..SessionConfig::default()
+        };
+
+        let session = Session::new(config).await.unwrap();
+
+        session.add_metainfo(metainfo("complete")).await.unwrap();
+        session.add_metainfo(metainfo("missing")).await.unwrap();
+
+        // Once checked the first turns out to be complete, which frees the download slot
+        let settled = tokio::time::timeout(Duration::from_secs(10), async {
+            while states(&session) != [TorrentState::Seeding, TorrentState::Downloading] {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        });
+
+        assert!(settled.await.is_ok(), "{:?}", states(&session));
+
+        session.shutdown().await.unwrap();
+        assert_eq!(states(&session), [TorrentState::Stopped, TorrentState::Stopped]);
+    }
+
+    /// DHT node answering queries on localhost, optionally knowing about another node
+    async fn dht_node(id: [u8; 20], known: Option<CompactNodeInfo>) -> SocketAddr {
+        let endpoint = UdpEndpoint::bind("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let addr = endpoint.local_addr().unwrap();
+        let node = Arc::new(DHTClient::new(id, addr, endpoint));
+
+        if let Some(known) = known {
+            node.routing_table.lock().unwrap().insert(known);
+        }
+
+        tokio::spawn(async move { node.serve().await });
+
+        addr
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn keeps_dht_routing_table_across_restarts() {
+        let dir = TempDir::new("session-dht");
+
+        let node = dht_node([1; 20], None).await;
+        let router = dht_node([2; 20], Some(CompactNodeInfo { node_id: [1; 20], socket_addr: node })).await;
+
+        let config = SessionConfig {
+            state_dir: Some(dir.to_path_buf()),
+            listen_addr: "127.0.0.1:0".parse().unwrap(),
+            dht_router: Some(router.to_string()),
+            ..SessionConfig::default()
+        };
+
+        let session = Session::new(config.clone()).await.unwrap();
+        let dht = session.inner.context.dht.clone().unwrap();
+        let node_id = dht.node_id;
+
+        // Bootstrapping learns about the node through the router
+        let bootstrapped = tokio::time::timeout(Duration::from_secs(10), async {
+            while dht.routing_table.lock().unwrap().get(&[1; 20]).is_none() {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        });
+
+        assert!(bootstrapped.await.is_ok());
+
+        session.shutdown().await.unwrap();
+        drop(session);
+
+        let restarted = Session::new(config).await.unwrap();
+        let dht = restarted.inner.context.dht.clone().unwrap();
+
+        assert_eq!(dht.node_id, node_id);
+        assert!(dht.routing_table.lock().unwrap().get(&[1; 20]).is_some());
+    }
+}
@@ -0,0 +1,376 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use sha1::{Digest, Sha1};
+
+use crate::metainfo::Info;
+
+/// How files are created before downloading
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Allocation {
+    /// Files get their final size right away but disk space is only used as data is written
+    #[default]
+    Sparse,
+
+    /// Every byte is written up front so the download can't run out of disk space halfway
+    Full,
+}
+
+/// Part of a block that falls within a single file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileSlice {
+    pub file_index: usize,
+
+    /// Offset within the file
+    pub offset: u64,
+    pub length: u64,
+}
+
+#[derive(Debug)]
+struct StorageFile {
+    path: PathBuf,
+
+    /// Offset of the file's first byte in the torrent's concatenated data
+    offset: u64,
+    length: u64,
+
+    /// Opened on first use, and whether it was opened for writing
+    handle: Mutex<Option<(File, bool)>>,
+}
+
+impl StorageFile {
+    /// Runs `f` on the file handle, opening the file first if needed. Writing creates the file
+    /// and its directories, files we can't write to (e.g. seeding from read-only media) are
+    /// opened read-only. Reading only opens files that already exist.
+    fn with_handle<T>(&self, write: bool, f: impl FnOnce(&mut File) -> io::Result<T>) -> io::Result<T> {
+        let mut handle = self.handle.lock().unwrap();
+
+        if handle.as_ref().is_none_or(|(_, writable)| write && !writable) {
+            *handle = Some(if write { self.open_for_writing()? } else { (File::open(&self.path)?, false) });
+        }
+
+        f(&mut handle.as_mut().unwrap().0)
+    }
+
+    fn open_for_writing(&self) -> io::Result<(File, bool)> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        match OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&self.path) {
+            Ok(file) => Ok((file, true)),
+            Err(err) if err.kind() == io::ErrorKind::PermissionDenied => Ok((File::open(&self.path)?, false)),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+/// Maps pieces onto the torrent's files and does the disk IO. Blocking IO runs on tokio's blocking
+/// thread pool. Cloning is cheap and clones share open file handles.
+#[derive(Debug, Clone)]
+pub struct Storage {
+    files: Arc<Vec<StorageFile>>,
+    piece_length: u64,
+    total_length: u64,
+    piece_hashes: Arc<Vec<[u8; 20]>>,
+}
+
+impl Storage {
+    /// Files are placed under `download_dir` following `Info::files`
+    pub fn new(info: &Info, download_dir: &Path) -> Self {
+        let mut offset = 0;
+
+        let files = info
+            .files()
+            .into_iter()
+            .map(|file| {
+                let storage_file = StorageFile {
+                    path: download_dir.join(file.relative_path()),
+                    offset,
+                    length: file.length,
+                    handle: Mutex::new(None),
+                };
+
+                offset += file.length;
+
+                storage_file
+            })
+            .collect();
+
+        Self {
+            files: Arc::new(files),
+            piece_length: info.piece_length,
+            total_length: info.total_length(),
+            piece_hashes: Arc::new(info.pieces.clone()),
+        }
+    }
+
+    pub fn piece_count(&self) -> usize {
+        self.piece_hashes.len()
+    }
+
+    /// Offset of the piece's first byte in the torrent's concatenated data
+    pub fn piece_offset(&self, index: u32) -> u64 {
+        index as u64 * self.piece_length
+    }
+
+    pub fn piece_size(&self, index: u32) -> u64 {
+        let start = self.piece_offset(index);
+
+        self.piece_length.min(self.total_length.saturating_sub(start))
+    }
+
+    /// Pieces are read whole, which only works for sizes a block length can hold
+    fn piece_size_u32(&self, index: u32) -> io::Result<u32> {
+        u32::try_from(self.piece_size(index)).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Piece too large"))
+    }
+
+    pub fn file_paths(&self) -> Vec<PathBuf> {
+        self.files.iter().map(|x| x.path.clone()).collect()
+    }
+
+    /// Byte range of every file within the torrent's data, in file order
+    pub fn file_ranges(&self) -> Vec<(u64, u64)> {
+        self.files.iter().map(|x| (x.offset, x.length)).collect()
+    }
+
+    /// Pieces that overlap the file at `file_index`. Empty for empty files
+    pub fn file_pieces(&self, file_index: usize) -> Range<u32> {
+        match self.files.get(file_index) {
+            Some(file) if file.length > 0 => {
+                let first = file.offset / self.piece_length;
+                let end = (file.offset + file.length).div_ceil(self.piece_length);
+
+                first as u32..end as u32
+            },
+            _ => 0..0,
+        }
+    }
+
+    /// Splits `length` bytes at `begin` within piece `index` into per-file slices
+    pub fn file_slices(&self, index: u32, begin: u32, length: u32) -> io::Result<Vec<FileSlice>> {
+        if begin as u64 + length as u64 > self.piece_size(index) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Range is outside of the piece"));
+        }
+
+        let start = index as u64 * self.piece_length + begin as u64;
+        let end = start + length as u64;
+
+        let slices = self.files
+            .iter()
+            .enumerate()
+            .filter(|(_, file)| file.length > 0 && file.offset < end && file.offset + file.length > start)
+            .map(|(file_index, file)| {
+                let slice_start = start.max(file.offset);
+                let slice_end = end.min(file.offset + file.length);
+
+                FileSlice {
+                    file_index,
+                    offset: slice_start - file.offset,
+                    length: slice_end - slice_start,
+                }
+            })
+            .collect();
+
+        Ok(slices)
+    }
+
+    /// Creates every file with its final size
+    pub async fn allocate(&self, allocation: Allocation) -> io::Result<()> {
+        let files = self.files.clone();
+
+        tokio::task::spawn_blocking(move || {
+            for file in files.iter() {
+                file.with_handle(true, |handle| {
+                    let current_length = handle.metadata()?.len();
+
+                    if current_length >= file.length {
+                        return Ok(());
+                    }
+
+                    match allocation {
+                        Allocation::Sparse => handle.set_len(file.length),
+                        Allocation::Full => {
+                            let zeros = vec![0u8; 1 << 20];
+                            let mut remaining = file.length - current_length;
+
+                            handle.seek(SeekFrom::Start(current_length))?;
+
+                            while remaining > 0 {
+                                let length = remaining.min(zeros.len() as u64) as usize;
+
+                                handle.write_all(&zeros[..length])?;
+                                remaining -= length as u64;
+                            }
+
+                            Ok(())
+                        },
+                    }
+                })?;
+            }
+
+            Ok(())
+        })
+        .await?
+    }
+
+    pub async fn write_block(&self, index: u32, begin: u32, data: Vec<u8>) -> io::Result<()> {
+        let slices = self.file_slices(index, begin, data.len() as u32)?;
+        let files = self.files.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let mut written = 0;
+
+            for slice in slices {
+                let length = slice.length as usize;
+
+                files[slice.file_index].with_handle(true, |handle| {
+                    handle.seek(SeekFrom::Start(slice.offset))?;
+                    handle.write_all(&data[written..written + length])
+                })?;
+
+                written += length;
+            }
+
+            Ok(())
+        })
+        .await?
+    }
+
+    /// Reads a block back, e.g. to upload it. Missing files or data past the end of a file read
+    /// as an error
+    pub async fn read_block(&self, index: u32, begin: u32, length: u32) -> io::Result<Vec<u8>> {
+        let slices = self.file_slices(index, begin, length)?;
+        let files = self.files.clone();
+
+        tokio::task::spawn_blocking(move || Self::read_slices(&files, &slices, length as usize)).await?
+    }
+
+    pub async fn read_piece(&self, index: u32) -> io::Result<Vec<u8>> {
+        self.read_block(index, 0, self.piece_size_u32(index)?).await
+    }
+
+    /// Whether the piece on disk matches its hash from the metainfo. Data that can't be read
+    /// (missing file, not written yet, file too short) just doesn't match. Hashing happens on the
+    /// blocking thread pool too, so checking many pieces at once spreads over the CPU cores
+    pub async fn verify_piece(&self, index: u32) -> io::Result<bool> {
+        let expected = *self.piece_hashes
+            .get(index as usize)
+            .ok_or(io::Error::new(io::ErrorKind::InvalidInput, "Piece index out of range"))?;
+
+        let length = self.piece_size_u32(index)?;
+        let slices = self.file_slices(index, 0, length)?;
+        let files = self.files.clone();
+
+        tokio::task::spawn_blocking(move || {
+            match Self::read_slices(&files, &slices, length as usize) {
+                Ok(data) => Ok(Sha1::digest(&data).as_slice() == expected),
+                Err(err) if matches!(err.kind(), io::ErrorKind::UnexpectedEof | io::ErrorKind::NotFound) => Ok(false),
+                Err(err) => Err(err),
+            }
+        })
+        .await?
+    }
+
+    fn read_slices(files: &[StorageFile], slices: &[FileSlice], length: usize) -> io::Result<Vec<u8>> {
+        let mut data = vec![0u8; length];
+        let mut read = 0;
+
+        for slice in slices {
+            let length = slice.length as usize;
+
+            files[slice.file_index].with_handle(false, |handle| {
+                handle.seek(SeekFrom::Start(slice.offset))?;
+                handle.read_exact(&mut data[read..read + length])
+            })?;
+
+            read += length;
+        }
+
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sha1::{Digest, Sha1};
+
+    use crate::{
+        metainfo::{FileEntry, FileLayout, Info},
+        utils::testing::TempDir,
+    };
+
+    use super::{Allocation, FileSlice, Storage};
+
+    /// 3 files of 5, 0 and 12 bytes with 8 byte pieces
+    fn test_info(data: &[u8]) -> Info {
+        Info {
+            name: "test".to_owned(),
+            piece_length: 8,
+            pieces: data.chunks(8).map(|x| Sha1::digest(x).into()).collect(),
+            private: false,
+            layout: FileLayout::Multi {
+                files: vec![
+                    FileEntry { length: 5, path: vec!["a".to_owned()] },
+                    FileEntry { length: 0, path: vec!["empty".to_owned()] },
+                    FileEntry { length: 12, path: vec!["dir".to_owned(), "b".to_owned()] },
+                ],
+            },
+        }
+    }
+
+    #[test]
+    fn maps_blocks_across_files() {
+        let storage = Storage::new(&test_info(&[0; 17]), &TempDir::new("storage-map"));
+
+        assert_eq!(storage.file_slices(0, 3, 5).unwrap(), vec![
+            FileSlice { file_index: 0, offset: 3, length: 2 },
+            FileSlice { file_index: 2, offset: 0, length: 3 },
+        ]);
+        assert_eq!(storage.file_slices(2, 0, 1).unwrap(), vec![
+            FileSlice { file_index: 2, offset: 11, length: 1 },
+        ]);
+        assert!(storage.file_slices(2, 0, 2).is_err());
+        assert_eq!(storage.file_pieces(0), 0..1);
+        assert_eq!(storage.file_pieces(1), 0..0);
+        assert_eq!(storage.file_pieces(2), 0..3);
+    }
+
+    #[tokio::test]
+    async fn writes_reads_and_verifies_pieces() {
+        let data = (0..17).collect::<Vec<u8>>();
+        let dir = TempDir::new("storage-write");
+        let storage = Storage::new(&test_info(&data), &dir);
+
+        storage.allocate(Allocation::Sparse).await.unwrap();
+        assert_eq!(std::fs::metadata(dir.join("test/dir/b")).unwrap().len(), 12);
+        assert!(dir.join("test/empty").exists());
+
+        storage.write_block(0, 0, data[0..8].to_vec()).await.unwrap();
+        storage.write_block(1, 4, data[12..16].to_vec()).await.unwrap();
+
+        assert_eq!(storage.read_block(0, 2, 6).await.unwrap(), data[2..8]);
+        assert!(storage.verify_piece(0).await.unwrap());
+        assert!(!storage.verify_piece(1).await.unwrap());
+
+        storage.write_block(1, 0, data[8..12].to_vec()).await.unwrap();
+        assert!(storage.verify_piece(1).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn verifying_missing_files_creates_nothing() {
+        let data = (0..17).collect::<Vec<u8>>();
+        let dir = TempDir::new("storage-missing");
+        let storage = Storage::new(&test_info(&data), &dir);
+
+        assert!(!storage.verify_piece(0).await.unwrap());
+        assert!(!storage.verify_piece(2).await.unwrap());
+        assert!(storage.read_block(0, 0, 4).await.is_err());
+        assert!(!dir.exists());
+    }
+}
@@ -0,0 +1,1438 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tokio::{
+    net::TcpStream,
+    sync::{mpsc, Mutex as AsyncMutex, OwnedSemaphorePermit, Semaphore},
+    task::JoinHandle,
+};
+
+use crate::{
+    bittorrent::{
+        bitfield::Bitfield,
+        choker::{Choker, ChokerConfig, PeerStats},
+        handshake::Handshake,
+        listener::{ConnectionPermit, IncomingConnection, PeerListener},
+        message::PeerMessage,
+        peer_client::PeerClient,
+        piece_picker::{BlockRequest, PiecePicker, Priority},
+    },
+    dht_client::DHTClient,
+    magnet::Magnet,
+    metainfo::Metainfo,
+    net::{
+        rate_limit::{Bandwidth, RateLimiter},
+        udp::UdpEndpoint,
+    },
+    recheck::recheck,
+    resume::ResumeData,
+    storage::{Allocation, Storage},
+    tracker::{AnnounceProgress, TrackerIssue, TrackerManager},
+};
+
+/// Everything a torrent shares with the other torrents of the process
+#[derive(Debug, Clone)]
+pub struct SessionContext {
+    pub peer_id: [u8; 20],
+
+    /// Port we accept peers on, announced to trackers and the DHT
+    pub port: u16,
+    pub endpoint: Arc<UdpEndpoint>,
+    pub dht: Option<Arc<DHTClient>>,
+    pub listener: Arc<PeerListener>,
+
+    /// Global limits every peer connection goes through
+    pub bandwidth: Bandwidth,
+}
+
+#[derive(Debug, Clone)]
+pub struct TorrentConfig {
+    pub download_dir: PathBuf,
+
+    /// Where to keep resume data. Without it every start checks the files on disk
+    pub resume_path: Option<PathBuf>,
+    pub allocation: Allocation,
+    pub max_connections: usize,
+    pub choker: ChokerConfig,
+
+    /// Bytes per second, `None` for unlimited
+    pub upload_rate: Option<u64>,
+    pub download_rate: Option<u64>,
+}
+
+impl Default for TorrentConfig {
+    fn default() -> Self {
+        Self {
+            download_dir: PathBuf::from("."),
+            resume_path: None,
+            allocation: Allocation::default(),
+            max_connections: 50,
+            choker: ChokerConfig::default(),
+            upload_rate: None,
+            download_rate: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TorrentState {
+    Stopped,
+    Paused,
+
+    /// Held back by the session's queueing rules until a slot frees up
+    Queued,
+
+    /// Waiting for a peer to send us the info dict of a magnet link
+    FetchingMetadata,
+
+    /// Loading resume data or hashing the files on disk
+    Checking,
+    Downloading,
+    Seeding,
+}
+
+/// Point in time view of a torrent for displaying it
+#[derive(Debug, Clone, PartialEq)]
+pub struct TorrentStatus {
+    pub info_hash: [u8; 20],
+    pub name: Option<String>,
+    pub state: TorrentState,
+
+    /// Fraction of the pieces we have, from 0 to 1
+    pub progress: f64,
+    pub total_length: Option<u64>,
+    pub left: Option<u64>,
+
+    /// Payload bytes, including what earlier runs transferred
+    pub downloaded: u64,
+    pub uploaded: u64,
+
+    /// Bytes per second
+    pub download_rate: u64,
+    pub upload_rate: u64,
+    pub peers: usize,
+    pub seeds: usize,
+
+    /// `None` while nothing is coming in
+    pub eta: Option<Duration>,
+
+    /// Trackers that failed or warned us on their latest announce
+    pub tracker_issues: Vec<(String, TrackerIssue)>,
+}
+
+/// Sent from the torrent to a peer's task
+#[derive(Debug, Clone, Copy)]
+enum PeerCommand {
+    Choke,
+    Unchoke,
+    Have(u32),
+
+    /// Another peer delivered the block first
+    Cancel(BlockRequest),
+}
+
+/// How a peer connection came about
+enum Connection {
+    Outgoing,
+    Incoming(TcpStream, Handshake),
+}
+
+#[derive(Debug)]
+struct PeerEntry {
+    commands: mpsc::UnboundedSender<PeerCommand>,
+    task: Option<JoinHandle<()>>,
+    connected_at: Instant,
+
+    /// Payload totals and what they were at the last choking round, to get the rates from
+    downloaded: u64,
+    uploaded: u64,
+    round_downloaded: u64,
+    round_uploaded: u64,
+    download_rate: u64,
+    upload_rate: u64,
+
+    last_block_at: Option<Instant>,
+    am_interested: bool,
+    peer_interested: bool,
+    is_seed: bool,
+
+    /// Per-peer limits, unlimited by default
+    upload_limiter: Arc<RateLimiter>,
+    download_limiter: Arc<RateLimiter>,
+}
+
+#[derive(Debug)]
+struct Torrent {
+    state: TorrentState,
+    metainfo: Option<Arc<Metainfo>>,
+    storage: Option<Storage>,
+    picker: Option<PiecePicker>,
+    peers: HashMap<SocketAddr, PeerEntry>,
+    choker: Choker,
+
+    /// One per file, empty until set. Kept here so they survive the picker being rebuilt
+    file_priorities: Vec<Priority>,
+
+    /// Addresses from resume data, handed to the connector on start
+    known_peers: Vec<SocketAddr>,
+
+    /// Feeds the connector while running
+    discovered: Option<mpsc::Sender<SocketAddr>>,
+
+    /// Taken out when halting so the trackers can be told we're leaving
+    trackers: Option<TrackerManager>,
+
+    downloaded: u64,
+    uploaded: u64,
+    last_downloaded: u64,
+    last_uploaded: u64,
+    download_rate: u64,
+    upload_rate: u64,
+
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Torrent {
+    fn is_running(&self) -> bool {
+        !matches!(self.state, TorrentState::Stopped | TorrentState::Paused | TorrentState::Queued)
+    }
+
+    fn announce_progress(&self) -> AnnounceProgress {
+        let left = match (&self.picker, &self.metainfo) {
+            (Some(picker), Some(metainfo)) => (0..picker.piece_count())
+                .filter(|i| !picker.have().has(*i))
+                .map(|i| metainfo.info.piece_size(i))
+                .sum(),
+            // Trackers treat 0 as seeding, anything else will do while we don't know the size
+            _ => 1,
+        };
+
+        AnnounceProgress { downloaded: self.downloaded, uploaded: self.uploaded, left }
+    }
+}
+
+#[derive(Debug)]
+struct Shared {
+    info_hash: [u8; 20],
+    context: SessionContext,
+    config: TorrentConfig,
+
+    /// Trackers from the magnet link, used until the metadata comes in
+    magnet_trackers: Vec<Vec<String>>,
+    display_name: Option<String>,
+
+    torrent: Mutex<Torrent>,
+
+    /// Serializes setting up storage, which several peers may trigger at once for magnets
+    prepare_lock: AsyncMutex<()>,
+
+    upload_limiter: Arc<RateLimiter>,
+    download_limiter: Arc<RateLimiter>,
+}
+
+/// A single torrent: finds peers through the trackers and the DHT, downloads the pieces it's
+/// missing, uploads to whoever is interested and keeps its resume data up to date.
+///
+/// Every part runs as its own tokio task. Pausing or stopping aborts them and drops all peer
+/// connections, the pieces we have are kept.
+#[derive(Debug)]
+pub struct TorrentSession {
+    shared: Arc<Shared>,
+}
+
+impl Drop for TorrentSession {
+    fn drop(&mut self) {
+        self.shared.abort_tasks();
+        self.shared.context.listener.unregister(&self.shared.info_hash);
+    }
+}
+
+impl TorrentSession {
+    /// Blocks kept in flight per peer
+    const MAX_REQUESTS: usize = 16;
+
+    /// Peers are expected to send at least a keep-alive every two minutes
+    const IDLE_TIMEOUT: Duration = Duration::from_secs(180);
+    const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(90);
+
+    /// How often rates, tracker progress and the connector are updated
+    const TICK_INTERVAL: Duration = Duration::from_secs(1);
+
+    /// Concurrent connection attempts
+    const MAX_CONNECTING: usize = 8;
+
+    /// Least time between two attempts at the same address
+    const RECONNECT_DELAY: Duration = Duration::from_secs(60);
+
+    pub fn from_metainfo(metainfo: Metainfo, context: SessionContext, config: TorrentConfig) -> Self {
+        let display_name = Some(metainfo.info.name.clone());
+        let shared = Shared::new(metainfo.info_hash, Some(metainfo), vec![], display_name, context, config);
+
+        Self { shared: Arc::new(shared) }
+    }
+
+    /// The metadata is fetched from peers once started. `None` if the magnet has no v1 info-hash
+    pub fn from_magnet(magnet: &Magnet, context: SessionContext, config: TorrentConfig) -> Option<Self> {
+        let info_hash = magnet.info_hash?;
+        let trackers = magnet.trackers.iter().map(|x| vec![x.clone()]).collect();
+
+        let shared = Shared::new(info_hash, None, trackers, magnet.display_name.clone(), context, config);
+
+        shared.torrent.lock().unwrap().known_peers = magnet.peers
+            .iter()
+            .filter_map(|x| x.parse().ok())
+            .collect();
+
+        Some(Self { shared: Arc::new(shared) })
+    }
+
+    pub fn info_hash(&self) -> [u8; 20] {
+        self.shared.info_hash
+    }
+
+    pub fn metainfo(&self) -> Option<Arc<Metainfo>> {
+        self.shared.torrent.lock().unwrap().metainfo.clone()
+    }
+
+    /// Starts or restarts the torrent. Does nothing if it's running already
+    pub fn start(&self) {
+        {
+            let mut torrent = self.shared.torrent.lock().unwrap();
+
+            if torrent.is_running() {
+                return;
+            }
+
+            torrent.state = if torrent.metainfo.is_some() { TorrentState::Checking } else { TorrentState::FetchingMetadata };
+        }
+
+        let shared = self.shared.clone();
+        let task = tokio::spawn(async move { Shared::run(shared).await });
+
+        self.shared.torrent.lock().unwrap().tasks.push(task);
+    }
+
+    /// Drops every connection but keeps what we have in memory so resuming is quick
+    pub async fn pause(&self) {
+        self.halt(TorrentState::Paused).await;
+    }
+
+    pub fn resume(&self) {
+        self.start();
+    }
+
+    /// Like pausing, but marks the torrent as waiting for a slot rather than held by the user
+    pub async fn queue(&self) {
+        self.halt(TorrentState::Queued).await;
+    }
+
+    /// Like pausing, but also closes the files
+    pub async fn stop(&self) {
+        self.halt(TorrentState::Stopped).await;
+
+        let mut torrent = self.shared.torrent.lock().unwrap();
+
+        if let Some(metainfo) = &torrent.metainfo {
+            torrent.storage = Some(Storage::new(&metainfo.info, &self.shared.config.download_dir));
+        }
+    }
+
+    async fn halt(&self, state: TorrentState) {
+        let trackers = self.shared.torrent.lock().unwrap().trackers.take();
+
+        self.shared.abort_tasks();
+        self.shared.context.listener.unregister(&self.shared.info_hash);
+
+        let was_running = {
+            let mut torrent = self.shared.torrent.lock().unwrap();
+            let was_running = torrent.is_running();
+
+            torrent.state = state;
+
+            was_running
+        };
+
+        if let Some(mut trackers) = trackers {
+            let progress = self.shared.torrent.lock().unwrap().announce_progress();
+            trackers.set_progress(progress);
+            trackers.stop().await;
+        }
+
+        if was_running {
+            // Not being able to save just means checking the files again next time
+            let _ = self.shared.save_resume_data().await;
+        }
+    }
+
+    pub fn state(&self) -> TorrentState {
+        self.shared.torrent.lock().unwrap().state
+    }
+
+    /// Bytes per second, `None` for unlimited. Applies to connected peers right away
+    pub fn set_rate_limits(&self, upload: Option<u64>, download: Option<u64>) {
+        self.shared.upload_limiter.set_rate(upload);
+        self.shared.download_limiter.set_rate(download);
+    }
+
+    pub fn set_peer_rate_limits(&self, peer: &SocketAddr, upload: Option<u64>, download: Option<u64>) {
+        if let Some(entry) = self.shared.torrent.lock().unwrap().peers.get(peer) {
+            entry.upload_limiter.set_rate(upload);
+            entry.download_limiter.set_rate(download);
+        }
+    }
+
+    pub fn set_choker_config(&self, config: ChokerConfig) {
+        self.shared.torrent.lock().unwrap().choker.set_config(config);
+    }
+
+    /// One priority per file, in metainfo order. Files left out are skipped
+    pub fn set_file_priorities(&self, priorities: Vec<Priority>) {
+        {
+            let mut torrent = self.shared.torrent.lock().unwrap();
+            let torrent = &mut *torrent;
+
+            if let (Some(picker), Some(metainfo)) = (torrent.picker.as_mut(), &torrent.metainfo) {
+                picker.set_file_priorities(&metainfo.info.files(), &priorities);
+            }
+
+            torrent.file_priorities = priorities;
+        }
+
+        // Skipping the missing files can finish the torrent
+        Shared::update_state(&self.shared.torrent);
+    }
+
+    pub fn status(&self) -> TorrentStatus {
+        let torrent = self.shared.torrent.lock().unwrap();
+
+        let total_length = torrent.metainfo.as_ref().map(|x| x.info.total_length());
+
+        let (progress, left) = match (&torrent.picker, &torrent.metainfo) {
+            (Some(picker), Some(metainfo)) => {
+                let have = picker.have();
+                let left = (0..have.len())
+                    .filter(|i| !have.has(*i))
+                    .map(|i| metainfo.info.piece_size(i))
+                    .sum::<u64>();
+
+                (have.count() as f64 / have.len().max(1) as f64, Some(left))
+            },
+            _ => (0.0, None),
+        };
+
+        let eta = match left {
+            Some(0) => Some(Duration::ZERO),
+            Some(left) if torrent.download_rate > 0 => Some(Duration::from_secs(left / torrent.download_rate)),
+            _ => None,
+        };
+
+        TorrentStatus {
+            info_hash: self.shared.info_hash,
+            name: torrent.metainfo.as_ref().map(|x| x.info.name.clone()).or(self.shared.display_name.clone()),
+            state: torrent.state,
+            progress,
+            total_length,
+            left,
+            downloaded: torrent.downloaded,
+            uploaded: torrent.uploaded,
+            download_rate: torrent.download_rate,
+            upload_rate: torrent.upload_rate,
+            peers: torrent.peers.len(),
+            seeds: torrent.peers.values().filter(|x| x.is_seed).count(),
+            eta,
+            tracker_issues: torrent.trackers.as_ref().map(|x| x.issues()).unwrap_or_default(),
+        }
+    }
+
+    /// Connects to a peer we learned about some other way
+    pub fn add_peer(&self, addr: SocketAddr) {
+        let mut torrent = self.shared.torrent.lock().unwrap();
+
+        match &torrent.discovered {
+            Some(discovered) => {
+                let _ = discovered.try_send(addr);
+            },
+            None => torrent.known_peers.push(addr),
+        }
+    }
+}
+
+impl Shared {
+    fn new(
+        info_hash: [u8; 20],
+        metainfo: Option<Metainfo>,
+        magnet_trackers: Vec<Vec<String>>,
+        display_name: Option<String>,
+        context: SessionContext,
+        config: TorrentConfig,
+    ) -> Self {
+        let storage = metainfo.as_ref().map(|x| Storage::new(&x.info, &config.download_dir));
+
+        let torrent = Torrent {
+            state: TorrentState::Stopped,
+            metainfo: metainfo.map(Arc::new),
+            storage,
+            picker: None,
+            peers: HashMap::new(),
+            choker: Choker::new(config.choker),
+            file_priorities: vec![],
+            known_peers: vec![],
+            discovered: None,
+            trackers: None,
+            downloaded: 0,
+            uploaded: 0,
+            last_downloaded: 0,
+            last_uploaded: 0,
+            download_rate: 0,
+            upload_rate: 0,
+            tasks: vec![],
+        };
+
+        Self {
+            info_hash,
+            upload_limiter: RateLimiter::new(config.upload_rate),
+            download_limiter: RateLimiter::new(config.download_rate),
+            context,
+            config,
+            magnet_trackers,
+            display_name,
+            torrent: Mutex::new(torrent),
+            prepare_lock: AsyncMutex::new(()),
+        }
+    }
+
+    fn abort_tasks(&self) {
+        let mut torrent = self.torrent.lock().unwrap();
+
+        torrent.discovered = None;
+        torrent.trackers = None;
+
+        for task in torrent.tasks.drain(..) {
+            task.abort();
+        }
+
+        for (_, mut entry) in torrent.peers.drain() {
+            if let Some(task) = entry.task.take() {
+                task.abort();
+            }
+        }
+
+        let torrent = &mut *torrent;
+
+        if let (Some(picker), Some(metainfo)) = (torrent.picker.as_mut(), &torrent.metainfo) {
+            // Recreating the picker forgets every peer and pending request
+            let mut fresh = PiecePicker::from_info(&metainfo.info);
+            fresh.set_have(picker.have().clone());
+
+            if !torrent.file_priorities.is_empty() {
+                fresh.set_file_priorities(&metainfo.info.files(), &torrent.file_priorities);
+            }
+
+            for (index, blocks) in picker.received_blocks() {
+                fresh.restore_blocks(index, &blocks);
+            }
+
+            *picker = fresh;
+        }
+    }
+
+    fn spawn(&self, task: JoinHandle<()>) {
+        self.torrent.lock().unwrap().tasks.push(task);
+    }
+
+    /// Only while running. A task that's being aborted may still get here and mustn't undo a pause
+    fn set_state(&self, state: TorrentState) {
+        let mut torrent = self.torrent.lock().unwrap();
+
+        if torrent.is_running() {
+            torrent.state = state;
+        }
+    }
+
+    /// Main task of a running torrent, spawns everything else
+    async fn run(shared: Arc<Self>) {
+        let (discovered_sender, discovered) = mpsc::channel(1024);
+        let incoming = shared.context.listener.register(shared.info_hash, shared.config.max_connections);
+
+        let metainfo = shared.torrent.lock().unwrap().metainfo.clone();
+
+        if metainfo.is_some() {
+            // Failing to touch the files shouldn't stop us from connecting, requests will fail
+            // and get logged later on
+            if let Err(err) = Self::prepare(&shared).await {
+                println!("Failed to prepare storage: {}", err);
+            }
+        }
+
+        let known_peers = {
+            let mut torrent = shared.torrent.lock().unwrap();
+            torrent.discovered = Some(discovered_sender.clone());
+
+            std::mem::take(&mut torrent.known_peers)
+        };
+
+        for peer in known_peers {
+            let _ = discovered_sender.try_send(peer);
+        }
+
+        shared.spawn(tokio::spawn(Self::connect_peers(shared.clone(), discovered)));
+        shared.spawn(tokio::spawn(Self::accept_peers(shared.clone(), incoming)));
+        shared.spawn(tokio::spawn(Self::announce_to_trackers(shared.clone(), discovered_sender.clone())));
+        shared.spawn(tokio::spawn(Self::announce_to_dht(shared.clone(), discovered_sender)));
+        shared.spawn(tokio::spawn(Self::tick(shared.clone())));
+        shared.spawn(tokio::spawn(Self::save_periodically(shared.clone())));
+    }
+
+    /// Sets up storage and the piece picker, restoring from resume data or checking the files
+    async fn prepare(shared: &Arc<Self>) -> io::Result<()> {
+        let _guard = shared.prepare_lock.lock().await;
+
+        let (metainfo, storage) = {
+            let torrent = shared.torrent.lock().unwrap();
+
+            if torrent.picker.is_some() {
+                Self::update_state(&shared.torrent);
+
+                return Ok(());
+            }
+
+            match (&torrent.metainfo, &torrent.storage) {
+                (Some(metainfo), Some(storage)) => (metainfo.clone(), storage.clone()),
+                _ => return Ok(()),
+            }
+        };
+
+        shared.set_state(TorrentState::Checking);
+
+        let mut picker = PiecePicker::from_info(&metainfo.info);
+
+        let resume = shared.config.resume_path
+            .as_ref()
+            .and_then(|path| ResumeData::load(path, &shared.info_hash, &storage).ok());
+
+        match resume {
+            Some(resume) => {
+                resume.restore(&mut picker);
+
+                let mut torrent = shared.torrent.lock().unwrap();
+                torrent.downloaded = resume.downloaded;
+                torrent.uploaded = resume.uploaded;
+                torrent.last_downloaded = resume.downloaded;
+                torrent.last_uploaded = resume.uploaded;
+                torrent.known_peers.extend(resume.peers);
+            },
+            None => {
+                let has_data = storage.file_paths().iter().any(|x| x.metadata().is_ok_and(|x| x.len() > 0));
+
+                if has_data {
+                    picker.set_have(recheck(&storage, |_| {}).await?.have);
+                }
+            },
+        }
+
+        storage.allocate(shared.config.allocation).await?;
+
+        let mut torrent = shared.torrent.lock().unwrap();
+
+        if !torrent.file_priorities.is_empty() {
+            picker.set_file_priorities(&metainfo.info.files(), &torrent.file_priorities);
+        }
+
+        torrent.picker = Some(picker);
+        drop(torrent);
+
+        Self::update_state(&shared.torrent);
+
+        Ok(())
+    }
+
+    fn update_state(torrent: &Mutex<Torrent>) {
+        let mut torrent = torrent.lock().unwrap();
+
+        if let Some(picker) = torrent.picker.as_ref().filter(|_| torrent.is_running()) {
+            torrent.state = if picker.is_finished() { TorrentState::Seeding } else { TorrentState::Downloading };
+        }
+    }
+
+    /// Takes metadata fetched from a peer, the first one wins
+    async fn set_metadata(shared: &Arc<Self>, info_bytes: &[u8]) -> io::Result<()> {
+        {
+            let mut torrent = shared.torrent.lock().unwrap();
+
+            if torrent.metainfo.is_none() {
+                let mut metainfo = Metainfo::from_info_bytes(info_bytes)
+                    .map_err(|x| io::Error::new(io::ErrorKind::InvalidData, format!("{:?}", x)))?;
+
+                metainfo.announce_list = shared.magnet_trackers.clone();
+
+                torrent.storage = Some(Storage::new(&metainfo.info, &shared.config.download_dir));
+                torrent.metainfo = Some(Arc::new(metainfo));
+            }
+        }
+
+        Self::prepare(shared).await
+    }
+
+    async fn save_resume_data(&self) -> io::Result<()> {
+        let Some(path) = &self.config.resume_path else {
+            return Ok(());
+        };
+
+        let resume = {
+            let torrent = self.torrent.lock().unwrap();
+
+            let (Some(picker), Some(storage)) = (&torrent.picker, &torrent.storage) else {
+                return Ok(());
+            };
+
+            ResumeData::capture(
+                self.info_hash,
+                picker,
+                storage,
+                torrent.peers.keys().copied().collect(),
+                torrent.uploaded,
+                torrent.downloaded,
+            )
+        };
+
+        resume.save(path).await
+    }
+
+    async fn save_periodically(shared: Arc<Self>) {
+        let mut interval = tokio::time::interval(ResumeData::SAVE_INTERVAL);
+
+        // The first tick completes right away and there's nothing new to save yet
+        interval.tick().await;
+
+        loop {
+            interval.tick().await;
+
+            if let Err(err) = shared.save_resume_data().await {
+                println!("Failed to save resume data: {}", err);
+            }
+        }
+    }
+
+    fn tracker_tiers(&self) -> Vec<Vec<String>> {
+        let torrent = self.torrent.lock().unwrap();
+
+        match &torrent.metainfo {
+            Some(metainfo) => metainfo.tracker_tiers(),
+            None => self.magnet_trackers.clone(),
+        }
+    }
+
+    async fn announce_to_trackers(shared: Arc<Self>, discovered: mpsc::Sender<SocketAddr>) {
+        let tiers = shared.tracker_tiers();
+
+        if tiers.is_empty() {
+            return;
+        }
+
+        let mut peers = {
+            let mut torrent = shared.torrent.lock().unwrap();
+
+            if !torrent.is_running() {
+                return;
+            }
+
+            let mut manager = TrackerManager::new(shared.info_hash, shared.context.peer_id, tiers, shared.context.endpoint.clone(), shared.context.port);
+            manager.set_progress(torrent.announce_progress());
+
+            let peers = manager.start();
+            torrent.trackers = Some(manager);
+
+            peers
+        };
+
+        let mut progress = tokio::time::interval(Duration::from_secs(30));
+
+        loop {
+            tokio::select! {
+                peer = peers.recv() => match peer {
+                    Some(peer) => if discovered.send(peer).await.is_err() {
+                        return;
+                    },
+                    None => return,
+                },
+                _ = progress.tick() => {
+                    let torrent = shared.torrent.lock().unwrap();
+
+                    if let Some(trackers) = &torrent.trackers {
+                        trackers.set_progress(torrent.announce_progress());
+                    }
+                },
+            }
+        }
+    }
+
+    async fn announce_to_dht(shared: Arc<Self>, discovered: mpsc::Sender<SocketAddr>) {
+        let Some(dht) = shared.context.dht.clone() else {
+            return;
+        };
+
+        let mut interval = tokio::time::interval(DHTClient::ANNOUNCE_INTERVAL);
+
+        loop {
+            interval.tick().await;
+
+            // Private torrents must only get peers from their trackers
+            let private = shared.torrent.lock().unwrap().metainfo.as_ref().is_some_and(|x| x.info.private);
+
+            if private {
+                return;
+            }
+
+            if let Ok((lookup, _)) = dht.announce_peer(&shared.info_hash, shared.context.port, false).await {
+                for peer in lookup.peers {
+                    if discovered.send(peer).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Dials discovered peers while there are connection slots. Peers we lost are dialed again
+    /// when they're rediscovered, but not more often than `RECONNECT_DELAY`
+    async fn connect_peers(shared: Arc<Self>, mut discovered: mpsc::Receiver<SocketAddr>) {
+        let mut candidates: VecDeque<SocketAddr> = VecDeque::new();
+        let mut tried: HashMap<SocketAddr, Instant> = HashMap::new();
+        let connecting = Arc::new(Semaphore::new(TorrentSession::MAX_CONNECTING));
+        let mut interval = tokio::time::interval(TorrentSession::TICK_INTERVAL);
+
+        loop {
+            tokio::select! {
+                peer = discovered.recv() => match peer {
+                    Some(peer) => {
+                        let due = tried.get(&peer).is_none_or(|x| x.elapsed() >= TorrentSession::RECONNECT_DELAY);
+
+                        if due && !candidates.contains(&peer) {
+                            candidates.push_back(peer);
+                        }
+                    },
+                    None => return,
+                },
+                _ = interval.tick() => tried.retain(|_, x| x.elapsed() < TorrentSession::RECONNECT_DELAY),
+            }
+
+            while !candidates.is_empty() {
+                let Ok(slot) = connecting.clone().try_acquire_owned() else {
+                    break;
+                };
+
+                let Some(permit) = shared.context.listener.limits().try_acquire(&shared.info_hash) else {
+                    break;
+                };
+
+                let addr = candidates.pop_front().unwrap();
+                tried.insert(addr, Instant::now());
+
+                Self::spawn_peer(&shared, addr, Connection::Outgoing, permit, Some(slot));
+            }
+        }
+    }
+
+    async fn accept_peers(shared: Arc<Self>, mut incoming: mpsc::Receiver<IncomingConnection>) {
+        while let Some(connection) = incoming.recv().await {
+            let incoming = Connection::Incoming(connection.stream, connection.handshake);
+
+            Self::spawn_peer(&shared, connection.addr, incoming, connection.permit, None);
+        }
+    }
+
+    fn spawn_peer(
+        shared: &Arc<Self>,
+        addr: SocketAddr,
+        connection: Connection,
+        permit: ConnectionPermit,
+        connecting_slot: Option<OwnedSemaphorePermit>,
+    ) {
+        let mut torrent = shared.torrent.lock().unwrap();
+
+        if torrent.peers.contains_key(&addr) {
+            return;
+        }
+
+        let (commands, receiver) = mpsc::unbounded_channel();
+        let upload_limiter = RateLimiter::unlimited();
+        let download_limiter = RateLimiter::unlimited();
+
+        let bandwidth = shared.context.bandwidth
+            .with(shared.upload_limiter.clone(), shared.download_limiter.clone())
+            .with(upload_limiter.clone(), download_limiter.clone());
+
+        let task_shared = shared.clone();
+
+        let task = tokio::spawn(async move {
+            let _permit = permit;
+
+            // Peers come and go all the time, why one left doesn't change what we do next
+            let _ = Self::run_peer(&task_shared, addr, connection, bandwidth, receiver, connecting_slot).await;
+
+            task_shared.peer_disconnected(&addr);
+        });
+
+        torrent.peers.insert(addr, PeerEntry {
+            commands,
+            task: Some(task),
+            connected_at: Instant::now(),
+            downloaded: 0,
+            uploaded: 0,
+            round_downloaded: 0,
+            round_uploaded: 0,
+            download_rate: 0,
+            upload_rate: 0,
+            last_block_at: None,
+            am_interested: false,
+            peer_interested: false,
+            is_seed: false,
+            upload_limiter,
+            download_limiter,
+        });
+    }
+
+    fn peer_disconnected(&self, addr: &SocketAddr) {
+        let mut torrent = self.torrent.lock().unwrap();
+
+        torrent.peers.remove(addr);
+
+        if let Some(picker) = torrent.picker.as_mut() {
+            picker.release_requests(addr);
+            picker.remove_peer(addr);
+        }
+    }
+
+    async fn run_peer(
+        shared: &Arc<Self>,
+        addr: SocketAddr,
+        connection: Connection,
+        bandwidth: Bandwidth,
+        mut commands: mpsc::UnboundedReceiver<PeerCommand>,
+        connecting_slot: Option<OwnedSemaphorePermit>,
+    ) -> io::Result<()> {
+        let peer_id = shared.context.peer_id;
+
+        let mut client = match connection {
+            Connection::Outgoing => {
+                let mut client = PeerClient::connect(&peer_id, &addr, &shared.info_hash).await?;
+                client.send_handshake().await?;
+
+                client
+            },
+            Connection::Incoming(stream, handshake) => PeerClient::accept(&peer_id, &addr, stream, handshake).await?,
+        };
+
+        drop(connecting_slot);
+
+        client.bandwidth = bandwidth;
+
+        let (have, metadata_size) = {
+            let torrent = shared.torrent.lock().unwrap();
+
+            let have = torrent.picker.as_ref().map(|x| x.have().clone()).unwrap_or(Bitfield::new(0));
+            let metadata_size = torrent.metainfo.as_ref().map(|x| x.info_bytes.len());
+
+            (have, metadata_size)
+        };
+
+        client.send_pieces(&have).await?;
+
+        if client.extensions().supports_extension_protocol() {
+            client.send_extended_handshake(metadata_size).await?;
+        }
+
+        // Lets the peer add us to its routing table (BEP 5)
+        if let (true, Some(_), Ok(dht_addr)) = (client.extensions().supports_dht(), &shared.context.dht, shared.context.endpoint.local_addr()) {
+            client.send_message(&PeerMessage::Port(dht_addr.port())).await?;
+        }
+
+        if metadata_size.is_none() {
+            let info_bytes = client.fetch_metadata().await?;
+
+            Self::set_metadata(shared, &info_bytes).await?;
+
+            // Our bitfield went out empty, anything found on disk since has to be announced
+            let have = shared.torrent.lock().unwrap().picker.as_ref().map(|x| x.have().clone());
+
+            for index in have.iter().flat_map(|x| x.iter_set()) {
+                client.send_have(index as u32).await?;
+            }
+        }
+
+        let metainfo = shared.torrent.lock().unwrap().metainfo.clone();
+
+        if let Some(metainfo) = metainfo {
+            client.set_piece_count(metainfo.info.piece_count())?;
+            client.set_local_metadata(metainfo.info_bytes.clone());
+        }
+
+        let mut peer = PeerConnection {
+            addr,
+            registered: false,
+            outstanding: vec![],
+            last_message_at: Instant::now(),
+        };
+
+        let mut keep_alive = tokio::time::interval(TorrentSession::KEEP_ALIVE_INTERVAL);
+
+        loop {
+            shared.sync_peer(&mut client, &mut peer).await?;
+
+            let storage = shared.torrent.lock().unwrap().storage.clone();
+
+            tokio::select! {
+                biased;
+
+                command = commands.recv() => match command {
+                    Some(command) => shared.handle_command(&mut client, &mut peer, command).await?,
+                    None => return Ok(()),
+                },
+                message = client.receive_message() => {
+                    peer.last_message_at = Instant::now();
+
+                    shared.handle_message(&mut client, &mut peer, message?).await?;
+                },
+                _ = std::future::ready(()), if !client.uploads.is_empty() && storage.is_some() => {
+                    let uploaded = client.serve_request(storage.as_ref().unwrap()).await?.unwrap_or(0) as u64;
+
+                    let mut torrent = shared.torrent.lock().unwrap();
+                    torrent.uploaded += uploaded;
+
+                    if let Some(entry) = torrent.peers.get_mut(&addr) {
+                        entry.uploaded += uploaded;
+                    }
+                },
+                _ = keep_alive.tick() => {
+                    if peer.last_message_at.elapsed() > TorrentSession::IDLE_TIMEOUT {
+                        return Err(io::Error::new(io::ErrorKind::TimedOut, "Peer went quiet"));
+                    }
+
+                    client.send_message(&PeerMessage::KeepAlive).await?;
+                },
+            }
+        }
+    }
+
+    /// Brings the picker, our interest and our requests up to date with the peer
+    async fn sync_peer(&self, client: &mut PeerClient<'_>, peer: &mut PeerConnection) -> io::Result<()> {
+        let (interested, requests) = {
+            let mut torrent = self.torrent.lock().unwrap();
+            let torrent = &mut *torrent;
+
+            let Some(picker) = torrent.picker.as_mut() else {
+                return Ok(());
+            };
+
+            // Peers that connected before we had the metadata only get registered now
+            if !peer.registered {
+                if client.peer_has_all {
+                    picker.add_seed(peer.addr);
+                } else {
+                    let mut bitfield = client.peer_pieces.clone();
+                    bitfield.resize(picker.piece_count());
+
+                    picker.add_peer(peer.addr, bitfield);
+                }
+
+                peer.registered = true;
+            }
+
+            let interested = picker.is_interesting(&peer.addr);
+
+            let requests = if interested && !client.state.peer_choking && peer.outstanding.len() < TorrentSession::MAX_REQUESTS {
+                picker.pick_blocks(&peer.addr, TorrentSession::MAX_REQUESTS - peer.outstanding.len())
+            } else {
+                vec![]
+            };
+
+            if let Some(entry) = torrent.peers.get_mut(&peer.addr) {
+                entry.am_interested = interested;
+                entry.peer_interested = client.state.peer_interested;
+                entry.is_seed = client.peer_has_all || client.peer_pieces.count() >= picker.piece_count();
+            }
+
+            (interested, requests)
+        };
+
+        if interested != client.state.am_interested {
+            let message = if interested { PeerMessage::Interested } else { PeerMessage::NotInterested };
+
+            client.send_message(&message).await?;
+        }
+
+        for request in requests {
+            client.send_message(&request.request_message()).await?;
+            peer.outstanding.push(request);
+        }
+
+        Ok(())
+    }
+
+    async fn handle_command(&self, client: &mut PeerClient<'_>, peer: &mut PeerConnection, command: PeerCommand) -> io::Result<()> {
+        match command {
+            PeerCommand::Choke => client.choke().await,
+            PeerCommand::Unchoke => client.unchoke().await,
+            PeerCommand::Have(index) => client.send_have(index).await,
+            PeerCommand::Cancel(request) => {
+                if let Some(position) = peer.outstanding.iter().position(|x| *x == request) {
+                    peer.outstanding.remove(position);
+                    client.send_message(&request.cancel_message()).await?;
+                }
+
+                Ok(())
+            },
+        }
+    }
+
+    async fn handle_message(&self, client: &mut PeerClient<'_>, peer: &mut PeerConnection, message: PeerMessage) -> io::Result<()> {
+        match message {
+            PeerMessage::Have { piece_index } if peer.registered => {
+                if let Some(picker) = self.torrent.lock().unwrap().picker.as_mut() {
+                    picker.peer_has(&peer.addr, piece_index);
+                }
+            },
+            PeerMessage::Bitfield(_) | PeerMessage::HaveAll if peer.registered => {
+                // Registered again with the full picture on the next sync
+                if let Some(picker) = self.torrent.lock().unwrap().picker.as_mut() {
+                    picker.remove_peer(&peer.addr);
+                }
+
+                peer.registered = false;
+            },
+            PeerMessage::Choke if !client.extensions().supports_fast() => {
+                if let Some(picker) = self.torrent.lock().unwrap().picker.as_mut() {
+                    picker.release_requests(&peer.addr);
+                }
+
+                peer.outstanding.clear();
+            },
+            PeerMessage::RejectRequest { index, begin, length } => {
+                let request = BlockRequest { index, begin, length };
+
+                if let Some(position) = peer.outstanding.iter().position(|x| *x == request) {
+                    peer.outstanding.remove(position);
+
+                    if let Some(picker) = self.torrent.lock().unwrap().picker.as_mut() {
+                        picker.request_failed(&peer.addr, &request);
+                    }
+                }
+            },
+            PeerMessage::Piece { index, begin, block } => {
+                let request = BlockRequest { index, begin, length: block.len() as u32 };
+
+                let Some(position) = peer.outstanding.iter().position(|x| *x == request) else {
+                    return Ok(());
+                };
+
+                peer.outstanding.remove(position);
+
+                self.block_received(peer, request, block).await?;
+            },
+            PeerMessage::Request { .. } | PeerMessage::Cancel { .. } => {
+                let (have, storage) = {
+                    let torrent = self.torrent.lock().unwrap();
+
+                    (torrent.picker.as_ref().map(|x| x.have().clone()), torrent.storage.clone())
+                };
+
+                if let (Some(have), Some(storage)) = (have, storage) {
+                    client.handle_upload_message(&message, &have, &storage).await?;
+                }
+            },
+            PeerMessage::Extended { id, payload } if id != 0 => client.serve_metadata(id, &payload).await?,
+            _ => {},
+        }
+
+        Ok(())
+    }
+
+    async fn block_received(&self, peer: &PeerConnection, request: BlockRequest, block: Vec<u8>) -> io::Result<()> {
+        let (outcome, storage) = {
+            let mut torrent = self.torrent.lock().unwrap();
+            let torrent = &mut *torrent;
+
+            let (Some(picker), Some(storage)) = (torrent.picker.as_mut(), torrent.storage.clone()) else {
+                return Ok(());
+            };
+
+            let outcome = picker.block_received(&peer.addr, &request);
+
+            if outcome.accepted {
+                torrent.downloaded += request.length as u64;
+
+                if let Some(entry) = torrent.peers.get_mut(&peer.addr) {
+                    entry.downloaded += request.length as u64;
+                    entry.last_block_at = Some(Instant::now());
+                }
+            }
+
+            for (other, request) in &outcome.cancels {
+                if let Some(entry) = torrent.peers.get(other) {
+                    let _ = entry.commands.send(PeerCommand::Cancel(*request));
+                }
+            }
+
+            (outcome, storage)
+        };
+
+        if !outcome.accepted {
+            return Ok(());
+        }
+
+        storage.write_block(request.index, request.begin, block).await?;
+
+        if !outcome.piece_complete {
+            return Ok(());
+        }
+
+        let valid = storage.verify_piece(request.index).await?;
+
+        let mut torrent = self.torrent.lock().unwrap();
+
+        let Some(picker) = torrent.picker.as_mut() else {
+            return Ok(());
+        };
+
+        if valid {
+            picker.piece_verified(request.index);
+
+            let finished = picker.is_finished();
+
+            for entry in torrent.peers.values() {
+                let _ = entry.commands.send(PeerCommand::Have(request.index));
+            }
+
+            if finished && torrent.is_running() {
+                torrent.state = TorrentState::Seeding;
+
+                // Trackers get `completed` right away rather than on their next announce
+                if let Some(trackers) = &torrent.trackers {
+                    trackers.set_progress(torrent.announce_progress());
+                }
+            }
+        } else {
+            picker.piece_failed(request.index);
+        }
+
+        Ok(())
+    }
+
+    /// Updates the rates every second and runs the choker every `Choker::UNCHOKE_INTERVAL`
+    async fn tick(shared: Arc<Self>) {
+        let mut interval = tokio::time::interval(TorrentSession::TICK_INTERVAL);
+        let rounds_per_choke = (Choker::UNCHOKE_INTERVAL.as_secs() / TorrentSession::TICK_INTERVAL.as_secs()).max(1);
+        let mut ticks = 0u64;
+
+        loop {
+            interval.tick().await;
+            ticks += 1;
+
+            let mut torrent = shared.torrent.lock().unwrap();
+            let torrent = &mut *torrent;
+
+            let seconds = TorrentSession::TICK_INTERVAL.as_secs();
+
+            torrent.download_rate = (torrent.downloaded - torrent.last_downloaded) / seconds;
+            torrent.upload_rate = (torrent.uploaded - torrent.last_uploaded) / seconds;
+            torrent.last_downloaded = torrent.downloaded;
+            torrent.last_uploaded = torrent.uploaded;
+
+            if !ticks.is_multiple_of(rounds_per_choke) {
+                continue;
+            }
+
+            let now = Instant::now();
+            let window = Choker::UNCHOKE_INTERVAL.as_secs();
+
+            let stats = torrent.peers
+                .iter_mut()
+                .map(|(addr, entry)| {
+                    entry.download_rate = (entry.downloaded - entry.round_downloaded) / window;
+                    entry.upload_rate = (entry.uploaded - entry.round_uploaded) / window;
+                    entry.round_downloaded = entry.downloaded;
+                    entry.round_uploaded = entry.uploaded;
+
+                    let stats = PeerStats {
+                        download_rate: entry.download_rate,
+                        upload_rate: entry.upload_rate,
+                        peer_interested: entry.peer_interested,
+                        am_interested: entry.am_interested,
+                        connected_at: entry.connected_at,
+                        last_block_at: entry.last_block_at,
+                    };
+
+                    (*addr, stats)
+                })
+                .collect::<HashMap<SocketAddr, PeerStats>>();
+
+            let seeding = torrent.state == TorrentState::Seeding;
+            let decision = torrent.choker.run(&stats, seeding, now);
+
+            for (peers, command) in [(decision.unchoke, PeerCommand::Unchoke), (decision.choke, PeerCommand::Choke)] {
+                for peer in peers {
+                    if let Some(entry) = torrent.peers.get(&peer) {
+                        let _ = entry.commands.send(command);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Per connection state kept by a peer's task
+#[derive(Debug)]
+struct PeerConnection {
+    addr: SocketAddr,
+
+    /// Added to the picker. Waits for the metadata on magnet links
+    registered: bool,
+    outstanding: Vec<BlockRequest>,
+    last_message_at: Instant,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, path::Path, sync::Arc, time::Duration};
+
+    use crate::{
+        bittorrent::{
+            listener::{ConnectionLimits, PeerListener},
+            piece_picker::Priority,
+        },
+        magnet::Magnet,
+        metainfo::Metainfo,
+        net::{rate_limit::Bandwidth, udp::UdpEndpoint},
+        utils::{hex::encode_hex, testing::{single_file_info, TempDir}},
+    };
+
+    use super::{SessionContext, TorrentConfig, TorrentSession, TorrentState};
+
+    async fn context(peer_id: u8) -> SessionContext {
+        let listener = PeerListener::bind("127.0.0.1:0".parse().unwrap(), ConnectionLimits::new(10)).await.unwrap();
+
+        SessionContext {
+            peer_id: [peer_id; 20],
+            port: listener.local_addr().port(),
+            endpoint: UdpEndpoint::bind("127.0.0.1:0".parse().unwrap()).await.unwrap(),
+            dht: None,
+            listener: Arc::new(listener),
+            bandwidth: Bandwidth::default(),
+        }
+    }
+
+    async fn wait_for(session: &TorrentSession, state: TorrentState) {
+        let reached = tokio::time::timeout(Duration::from_secs(20), async {
+            while session.state() != state {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        });
+
+        assert!(reached.await.is_ok(), "{:?}", session.status());
+    }
+
+    /// A single file torrent named `test`, seeded from `dir`
+    async fn seeder(data: &[u8], dir: &Path) -> (Metainfo, TorrentSession, SocketAddr) {
+        let metainfo = Metainfo::from_info_bytes(&single_file_info("test", 32_768, data)).unwrap();
+
+        std::fs::create_dir_all(dir).unwrap();
+        std::fs::write(dir.join("test"), data).unwrap();
+
+        let seeder_context = context(1).await;
+        let seeder_addr = seeder_context.listener.local_addr();
+
+        let seed_config = TorrentConfig { download_dir: dir.to_path_buf(), ..TorrentConfig::default() };
+        let seeder = TorrentSession::from_metainfo(metainfo.clone(), seeder_context, seed_config);
+
+        seeder.start();
+        wait_for(&seeder, TorrentState::Seeding).await;
+
+        (metainfo, seeder, seeder_addr)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn downloads_from_seeding_session() {
+        let data = (0..100_000u32).map(|x| (x % 251) as u8).collect::<Vec<u8>>();
+
+        let seed_dir = TempDir::new("torrent-seed");
+        let (metainfo, _seeder, seeder_addr) = seeder(&data, &seed_dir).await;
+
+        let download_dir = TempDir::new("torrent-download");
+        let resume_path = download_dir.join("test.resume");
+
+        let config = TorrentConfig {
+            download_dir: download_dir.to_path_buf(),
+            resume_path: Some(resume_path.clone()),
+            ..TorrentConfig::default()
+        };
+
+        let downloader = TorrentSession::from_metainfo(metainfo, context(2).await, config);
+
+        downloader.add_peer(seeder_addr);
+        downloader.start();
+
+        // The seeder only unchokes on its next choking round
+        wait_for(&downloader, TorrentState::Seeding).await;
+
+        let status = downloader.status();
+        assert_eq!(status.progress, 1.0);
+        assert_eq!(status.left, Some(0));
+        assert_eq!(status.downloaded, data.len() as u64);
+
+        downloader.stop().await;
+        assert_eq!(downloader.state(), TorrentState::Stopped);
+        assert!(resume_path.exists());
+
+        assert_eq!(std::fs::read(download_dir.join("test")).unwrap(), data);
+
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn fetches_metadata_from_seeding_session() {
+        let data = (0..50_000u32).map(|x| (x % 241) as u8).collect::<Vec<u8>>();
+
+        let seed_dir = TempDir::new("torrent-metadata-seed");
+        let (metainfo, _seeder, seeder_addr) = seeder(&data, &seed_dir).await;
+
+        let magnet = Magnet::parse(&format!("magnet:?xt=urn:btih:{}", encode_hex(&metainfo.info_hash))).unwrap();
+
+        let download_dir = TempDir::new("torrent-metadata-download");
+        let config = TorrentConfig { download_dir: download_dir.to_path_buf(), ..TorrentConfig::default() };
+        let downloader = TorrentSession::from_magnet(&magnet, context(2).await, config).unwrap();
+
+        downloader.add_peer(seeder_addr);
+        downloader.start();
+
+        wait_for(&downloader, TorrentState::Seeding).await;
+
+        assert_eq!(downloader.metainfo().unwrap().info_bytes, metainfo.info_bytes);
+        assert_eq!(std::fs::read(download_dir.join("test")).unwrap(), data);
+
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn skipped_files_count_as_finished() {
+        let data = vec![7; 40_000];
+
+        let seed_dir = TempDir::new("torrent-skip-seed");
+        let (metainfo, _seeder, _) = seeder(&data, &seed_dir).await;
+
+        let download_dir = TempDir::new("torrent-skip-download");
+        let config = TorrentConfig { download_dir: download_dir.to_path_buf(), ..TorrentConfig::default() };
+        let downloader = TorrentSession::from_metainfo(metainfo, context(2).await, config);
+
+        downloader.set_file_priorities(vec![Priority::Skip]);
+        downloader.start();
+
+        // Nothing is wanted, so there's nothing to download
+        wait_for(&downloader, TorrentState::Seeding).await;
+        assert_eq!(downloader.status().downloaded, 0);
+
+    }
+}
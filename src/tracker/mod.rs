@@ -1,3 +1,7 @@
-mod tracker_udp_client;
+pub mod tracker_udp_client;
+mod tracker_http_client;
+mod tracker_manager;
 
-pub use tracker_udp_client::TrackerUDPClient;
+pub use tracker_udp_client::{AnnounceRequest, AnnounceResponse, TrackerUDPClient};
+pub use tracker_http_client::TrackerHTTPClient;
+pub use tracker_manager::{AnnounceProgress, TrackerIssue, TrackerManager};
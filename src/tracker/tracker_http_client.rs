@@ -0,0 +1,290 @@
+use std::{collections::HashMap, net::{IpAddr, SocketAddr}, time::Duration};
+
+use crate::{
+    net::compact::parse_compact_peers,
+    tracker::{AnnounceRequest, AnnounceResponse},
+    utils::bencode::{BencodeParser, BencodeValue},
+};
+
+
+#[derive(Debug)]
+pub enum TrackerHTTPClientError {
+    InvalidResponse,
+
+    /// The tracker answered with a `failure reason`
+    TrackerError(String),
+    Other(reqwest::Error),
+}
+
+
+#[derive(Debug)]
+pub struct TrackerHTTPClient {
+    announce_url: String,
+    http: reqwest::Client,
+    peer_id: [u8; 20],
+
+    /// Handed out by some trackers, has to be sent back on later announces
+    tracker_id: Option<String>,
+}
+
+
+impl TrackerHTTPClient {
+    const TIMEOUT: Duration = Duration::from_secs(15);
+
+    pub fn new(announce_url: &str, peer_id: [u8; 20]) -> Self {
+        let http = reqwest::Client::builder()
+            .timeout(Self::TIMEOUT)
+            .build()
+            .expect("HTTP client config is static");
+
+        TrackerHTTPClient {
+            announce_url: announce_url.to_owned(),
+            http,
+            peer_id,
+            tracker_id: None,
+        }
+    }
+
+    /// The announce URL with the request encoded in its query string. Some trackers put a passkey
+    /// in the announce URL so we append to any query that's already there.
+    pub fn announce_url(&self, request: &AnnounceRequest) -> String {
+        let mut query = vec![
+            ("info_hash", url_encode(request.info_hash)),
+            ("peer_id", url_encode(&self.peer_id)),
+            ("port", (request.port as u16).to_string()),
+            ("uploaded", request.uploaded.to_string()),
+            ("downloaded", request.downloaded.to_string()),
+            ("left", request.left.to_string()),
+            ("compact", "1".to_owned()),
+            ("key", format!("{:08x}", request.key)),
+        ];
+
+        let event = match request.event {
+            1 => Some("completed"),
+            2 => Some("started"),
+            3 => Some("stopped"),
+            _ => None,
+        };
+
+        if let Some(event) = event {
+            query.push(("event", event.to_owned()));
+        }
+
+        if request.num_want >= 0 {
+            query.push(("numwant", request.num_want.to_string()));
+        }
+
+        if let Some(ip) = request.ip_address {
+            query.push(("ip", ip.to_string()));
+        }
+
+        if let Some(tracker_id) = &self.tracker_id {
+            query.push(("trackerid", url_encode(tracker_id.as_bytes())));
+        }
+
+        let query = query
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect::<Vec<String>>()
+            .join("&");
+
+        let separator = if self.announce_url.contains('?') { '&' } else { '?' };
+
+        format!("{}{}{}", self.announce_url, separator, query)
+    }
+
+    pub async fn announce<'a>(&mut self, request: &AnnounceRequest<'a>) -> Result<AnnounceResponse, TrackerHTTPClientError> {
+        let response = self.http
+            .get(self.announce_url(request))
+            .send()
+            .await
+            .and_then(|x| x.error_for_status())
+            .map_err(TrackerHTTPClientError::Other)?;
+
+        let body = response
+            .bytes()
+            .await
+            .map_err(TrackerHTTPClientError::Other)?;
+
+        let announce_response = parse_announce_response(&body)?;
+
+        if announce_response.tracker_id.is_some() {
+            self.tracker_id = announce_response.tracker_id.clone();
+        }
+
+        Ok(announce_response)
+    }
+}
+
+/// Percent-encodes everything except the RFC 3986 unreserved characters
+fn url_encode(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|&byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (byte as char).to_string(),
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+/// Peers are either a string of compact entries or a list of dicts with `ip` and `port`
+fn parse_peers(value: &BencodeValue, ipv6: bool) -> Vec<SocketAddr> {
+    match value {
+        BencodeValue::Bytes(bytes) => parse_compact_peers(bytes, ipv6),
+        BencodeValue::List(list) => list
+            .iter()
+            .filter_map(|peer| {
+                let peer = peer.dict().ok()?;
+
+                // Hostnames are allowed here but nobody sends them, skip anything that isn't an IP
+                let ip = peer.get("ip".as_bytes())?.bytes().ok()?;
+                let ip = std::str::from_utf8(ip).ok()?.parse::<IpAddr>().ok()?;
+                let port = u16::try_from(*peer.get("port".as_bytes())?.integer().ok()?).ok()?;
+
+                Some(SocketAddr::new(ip, port))
+            })
+            .collect(),
+        _ => vec![],
+    }
+}
+
+pub fn parse_announce_response(data: &[u8]) -> Result<AnnounceResponse, TrackerHTTPClientError> {
+    let value = BencodeParser::new(data)
+        .parse_value()
+        .map_err(|_| TrackerHTTPClientError::InvalidResponse)?;
+
+    let dict: &HashMap<Vec<u8>, BencodeValue> = value
+        .dict()
+        .map_err(|_| TrackerHTTPClientError::InvalidResponse)?;
+
+    let get_string = |key: &str| {
+        dict.get(key.as_bytes())
+            .and_then(|x| x.bytes().ok())
+            .map(|x| String::from_utf8_lossy(x).into_owned())
+    };
+
+    let get_integer = |key: &str| {
+        dict.get(key.as_bytes())
+            .and_then(|x| x.integer().ok())
+            .and_then(|x| i32::try_from(*x).ok())
+    };
+
+    if let Some(reason) = get_string("failure reason") {
+        return Err(TrackerHTTPClientError::TrackerError(reason));
+    }
+
+    let interval = get_integer("interval").ok_or(TrackerHTTPClientError::InvalidResponse)?;
+
+    // IPv6 peers come separately in `peers6` (BEP 7)
+    let mut peers = dict
+        .get("peers".as_bytes())
+        .map(|x| parse_peers(x, false))
+        .unwrap_or_default();
+
+    if let Some(peers6) = dict.get("peers6".as_bytes()) {
+        peers.extend(parse_peers(peers6, true));
+    }
+
+    Ok(AnnounceResponse {
+        // HTTP has no actions or transactions, use what a UDP announce response would carry
+        action: 1,
+        transaction_id: 0,
+        interval,
+        leechers: get_integer("incomplete").unwrap_or(0),
+        seeders: get_integer("complete").unwrap_or(0),
+        peers,
+        warning_message: get_string("warning message"),
+        min_interval: get_integer("min interval"),
+        tracker_id: get_string("tracker id"),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpListener};
+
+    use crate::tracker::AnnounceRequest;
+
+    use super::{parse_announce_response, TrackerHTTPClient, TrackerHTTPClientError};
+
+    #[test]
+    fn parses_dictionary_peers_peers6_and_warnings() {
+        let data = b"d8:completei5e10:incompletei3e8:intervali1800e12:min intervali900e\
+            5:peersld2:ip8:10.0.0.17:peer id20:abcdefghij01234567894:porti6881eed2:ip7:example4:porti1eee\
+            6:peers618:\x20\x01\x0d\xb8\0\0\0\0\0\0\0\0\0\0\0\x01\x1a\xe115:warning message4:slowe";
+
+        let response = parse_announce_response(data).unwrap();
+
+        assert_eq!(response.interval, 1800);
+        assert_eq!(response.min_interval, Some(900));
+        assert_eq!(response.seeders, 5);
+        assert_eq!(response.leechers, 3);
+        assert_eq!(response.peers, vec![
+            "10.0.0.1:6881".parse::<SocketAddr>().unwrap(),
+            "[2001:db8::1]:6881".parse::<SocketAddr>().unwrap(),
+        ]);
+        assert_eq!(response.warning_message.as_deref(), Some("slow"));
+    }
+
+    #[test]
+    fn returns_failure_reason() {
+        let data = "d14:failure reason17:torrent not founde";
+
+        let error = parse_announce_response(data.as_bytes()).unwrap_err();
+
+        assert!(matches!(error, TrackerHTTPClientError::TrackerError(x) if x == "torrent not found"));
+    }
+
+    #[tokio::test]
+    async fn announces_to_stub_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+
+            let mut buf = vec![0u8; 4096];
+            let len = stream.read(&mut buf).await.unwrap();
+            let request = String::from_utf8_lossy(&buf[..len]).into_owned();
+
+            let mut body = "d8:intervali900e10:tracker id3:abc5:peers6:".as_bytes().to_vec();
+            body.extend_from_slice(&[10, 0, 0, 2, 0x1a, 0xe1]);
+            body.push(b'e');
+
+            let mut response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len(),
+            ).into_bytes();
+            response.extend_from_slice(&body);
+
+            stream.write_all(&response).await.unwrap();
+
+            request
+        });
+
+        let mut client = TrackerHTTPClient::new(&format!("http://{}/announce?passkey=x", addr), *b"-RB0001-abcdefghijkl");
+        let info_hash = [0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc, 0xde, 0xf1, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef, 0x12, 0x34, 0x56, 0x78, 0x9a];
+
+        let response = client
+            .announce(&AnnounceRequest {
+                info_hash: &info_hash,
+                event: 2,
+                num_want: 50,
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+
+        let request = server.await.unwrap();
+
+        assert!(request.starts_with("GET /announce?passkey=x&info_hash=%124Vx%9A%BC%DE%F1%23Eg%89%AB%CD%EF%124Vx%9A&"));
+        assert!(request.contains("&peer_id=-RB0001-abcdefghijkl&"));
+        assert!(request.contains("&compact=1&"));
+        assert!(request.contains("&event=started&numwant=50 "));
+        assert_eq!(response.interval, 900);
+        assert_eq!(response.peers, vec!["10.0.0.2:6881".parse::<SocketAddr>().unwrap()]);
+        assert_eq!(client.tracker_id.as_deref(), Some("abc"));
+    }
+}
@@ -0,0 +1,556 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use futures::future::join_all;
+use rand::seq::SliceRandom;
+use tokio::{
+    sync::{mpsc, watch, Mutex as AsyncMutex},
+    task::JoinHandle,
+    time::timeout,
+};
+use url::{Host, Url};
+
+use crate::{
+    magnet::Magnet,
+    metainfo::Metainfo,
+    net::udp::UdpEndpoint,
+    tracker::{AnnounceRequest, AnnounceResponse, TrackerHTTPClient, TrackerUDPClient},
+};
+
+/// Transfer totals reported on every announce
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AnnounceProgress {
+    pub downloaded: u64,
+    pub uploaded: u64,
+    pub left: u64,
+}
+
+/// BEP 15 event codes, HTTP trackers get them by name
+const EVENT_NONE: i32 = 0;
+const EVENT_COMPLETED: i32 = 1;
+const EVENT_STARTED: i32 = 2;
+const EVENT_STOPPED: i32 = 3;
+
+/// Announce state of a single tier, shared between its task and `TrackerManager::stop`
+#[derive(Debug, Default)]
+struct Tier {
+    peer_id: [u8; 20],
+
+    /// Clients are kept per tracker URL. HTTP trackers may hand out a tracker id and UDP ones a
+    /// connection id, both of which have to be reused
+    http_clients: HashMap<String, TrackerHTTPClient>,
+    udp_clients: HashMap<String, TrackerUDPClient>,
+
+    /// The tracker that accepted our `started` event, it's the one told when we stop
+    started_with: Option<String>,
+
+    /// Whether we've been downloading and `completed` still has to be sent
+    downloading: bool,
+}
+
+/// Latest problem a tracker had, kept until it answers cleanly
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TrackerIssue {
+    /// The announce failed, including failure reasons sent by the tracker
+    Error(String),
+
+    /// The tracker answered but attached a warning message
+    Warning(String),
+}
+
+/// Announces a torrent to every tracker tier (BEP 12) and merges the peers they return.
+///
+/// Trackers are shuffled within their tier. Each tier announces on its own schedule, trying its
+/// trackers in order until one answers. That tracker is moved to the front of the tier so it's
+/// tried first next time.
+///
+/// Finishing the download is announced right away with `completed`, and `stop` tells every
+/// tracker we started with that we're leaving.
+#[derive(Debug)]
+pub struct TrackerManager {
+    info_hash: [u8; 20],
+    peer_id: [u8; 20],
+    port: u16,
+    endpoint: Arc<UdpEndpoint>,
+
+    tiers: Arc<Mutex<Vec<Vec<String>>>>,
+    tier_states: Vec<Arc<AsyncMutex<Tier>>>,
+    progress: Arc<Mutex<AnnounceProgress>>,
+
+    /// By tracker URL
+    issues: Arc<Mutex<HashMap<String, TrackerIssue>>>,
+
+    /// Whether nothing is left, wakes the tiers up when the download finishes
+    finished: watch::Sender<bool>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Drop for TrackerManager {
+    fn drop(&mut self) {
+        for task in &self.tasks {
+            task.abort();
+        }
+    }
+}
+
+impl TrackerManager {
+    /// How long to wait before retrying a tier where every tracker failed. Doubles on every
+    /// failure up to `MAX_RETRY_INTERVAL`
+    const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+    const MAX_RETRY_INTERVAL: Duration = Duration::from_secs(30 * 60);
+
+    /// Lower bound for tracker supplied intervals so a broken tracker can't make us spam it
+    const MIN_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(30);
+
+    const NUM_WANT: i32 = 50;
+
+    /// How long leaving a swarm may take, trackers that don't answer in time are skipped
+    const STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+    pub fn new(info_hash: [u8; 20], peer_id: [u8; 20], tiers: Vec<Vec<String>>, endpoint: Arc<UdpEndpoint>, port: u16) -> Self {
+        let mut rng = rand::thread_rng();
+
+        let tiers = tiers
+            .into_iter()
+            .filter(|tier| !tier.is_empty())
+            .map(|mut tier| {
+                tier.shuffle(&mut rng);
+
+                tier
+            })
+            .collect();
+
+        Self {
+            info_hash,
+            peer_id,
+            port,
+            endpoint,
+            tiers: Arc::new(Mutex::new(tiers)),
+            tier_states: vec![],
+            progress: Arc::new(Mutex::new(AnnounceProgress::default())),
+            issues: Arc::new(Mutex::new(HashMap::new())),
+            finished: watch::Sender::new(false),
+            tasks: vec![],
+        }
+    }
+
+    pub fn from_metainfo(metainfo: &Metainfo, peer_id: [u8; 20], endpoint: Arc<UdpEndpoint>, port: u16) -> Self {
+        Self::new(metainfo.info_hash, peer_id, metainfo.tracker_tiers(), endpoint, port)
+    }
+
+    /// Every `tr` parameter gets its own tier. `None` if the magnet has no v1 info-hash
+    pub fn from_magnet(magnet: &Magnet, peer_id: [u8; 20], endpoint: Arc<UdpEndpoint>, port: u16) -> Option<Self> {
+        let tiers = magnet.trackers
+            .iter()
+            .map(|x| vec![x.clone()])
+            .collect();
+
+        Some(Self::new(magnet.info_hash?, peer_id, tiers, endpoint, port))
+    }
+
+    /// Current tracker order, working trackers first within each tier
+    pub fn tiers(&self) -> Vec<Vec<String>> {
+        self.tiers.lock().unwrap().clone()
+    }
+
+    /// Trackers that failed or warned us on their latest announce, sorted by URL
+    pub fn issues(&self) -> Vec<(String, TrackerIssue)> {
+        let mut issues = self.issues.lock().unwrap().clone().into_iter().collect::<Vec<_>>();
+        issues.sort_by(|a, b| a.0.cmp(&b.0));
+
+        issues
+    }
+
+    pub fn set_progress(&self, progress: AnnounceProgress) {
+        *self.progress.lock().unwrap() = progress;
+        self.finished.send_replace(progress.left == 0);
+    }
+
+    /// Starts announcing to every tier. Peers come out of the returned channel. Tiers reporting
+    /// the same peer around the same time only pass it on once, later announces hand it out
+    /// again so peers we lost can be retried. Announcing stops when the receiver is dropped or
+    /// the manager goes away.
+    pub fn start(&mut self) -> mpsc::Receiver<SocketAddr> {
+        let (sender, receiver) = mpsc::channel(1024);
+        let seen: Arc<Mutex<HashMap<SocketAddr, Instant>>> = Arc::new(Mutex::new(HashMap::new()));
+
+        for task in self.tasks.drain(..) {
+            task.abort();
+        }
+
+        let tier_count = self.tiers.lock().unwrap().len();
+        self.tier_states = (0..tier_count)
+            .map(|_| Arc::new(AsyncMutex::new(Tier { peer_id: self.peer_id, ..Default::default() })))
+            .collect();
+
+        for tier in 0..tier_count {
+            let task = Self::announce_tier(
+                tier,
+                self.info_hash,
+                self.port,
+                self.endpoint.clone(),
+                self.tiers.clone(),
+                self.tier_states[tier].clone(),
+                self.progress.clone(),
+                self.issues.clone(),
+                self.finished.subscribe(),
+                sender.clone(),
+                seen.clone(),
+            );
+
+            self.tasks.push(tokio::spawn(task));
+        }
+
+        receiver
+    }
+
+    /// Stops announcing and sends `stopped` to every tracker we announced `started` to
+    pub async fn stop(&mut self) {
+        for task in self.tasks.drain(..) {
+            task.abort();
+        }
+
+        let progress = *self.progress.lock().unwrap();
+
+        let stops = self.tier_states.drain(..).map(|tier| {
+            let endpoint = self.endpoint.clone();
+            let info_hash = self.info_hash;
+            let port = self.port;
+
+            async move {
+                // Aborted tasks release the lock once they're dropped
+                let mut tier = tier.lock().await;
+
+                let Some(url) = tier.started_with.take() else {
+                    return;
+                };
+
+                let request = Self::request(&info_hash, port, progress, EVENT_STOPPED);
+
+                let _ = timeout(Self::STOP_TIMEOUT, Self::announce(&url, &request, &endpoint, &mut tier)).await;
+            }
+        });
+
+        join_all(stops).await;
+    }
+
+    fn request(info_hash: &[u8; 20], port: u16, progress: AnnounceProgress, event: i32) -> AnnounceRequest<'_> {
+        AnnounceRequest {
+            info_hash,
+            downloaded: progress.downloaded as i64,
+            left: progress.left as i64,
+            uploaded: progress.uploaded as i64,
+            num_want: if event == EVENT_STOPPED { 0 } else { Self::NUM_WANT },
+            port: port as i16,
+            event,
+            ..Default::default()
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn announce_tier(
+        tier: usize,
+        info_hash: [u8; 20],
+        port: u16,
+        endpoint: Arc<UdpEndpoint>,
+        tiers: Arc<Mutex<Vec<Vec<String>>>>,
+        state: Arc<AsyncMutex<Tier>>,
+        progress: Arc<Mutex<AnnounceProgress>>,
+        issues: Arc<Mutex<HashMap<String, TrackerIssue>>>,
+        mut finished: watch::Receiver<bool>,
+        sender: mpsc::Sender<SocketAddr>,
+        seen: Arc<Mutex<HashMap<SocketAddr, Instant>>>,
+    ) {
+        let mut failures = 0;
+
+        loop {
+            let trackers = tiers.lock().unwrap()[tier].clone();
+            let progress = *progress.lock().unwrap();
+            let mut state = state.lock().await;
+
+            let event = if state.started_with.is_none() {
+                EVENT_STARTED
+            } else if state.downloading && progress.left == 0 {
+                EVENT_COMPLETED
+            } else {
+                EVENT_NONE
+            };
+
+            let request = Self::request(&info_hash, port, progress, event);
+
+            let mut answered = None;
+
+            for url in trackers {
+                match Self::announce(&url, &request, &endpoint, &mut state).await {
+                    Ok(response) => {
+                        match &response.warning_message {
+                            Some(warning) => issues.lock().unwrap().insert(url.clone(), TrackerIssue::Warning(warning.clone())),
+                            None => issues.lock().unwrap().remove(&url),
+                        };
+
+                        answered = Some((url, response));
+
+                        break;
+                    },
+                    Err(err) => {
+                        issues.lock().unwrap().insert(url, TrackerIssue::Error(err));
+                    },
+                }
+            }
+
+            let wait = match answered {
+                Some((url, response)) => {
+                    // Switching trackers within the tier means the new one never saw `started`,
+                    // it gets one next time
+                    if event == EVENT_STARTED || state.started_with.as_ref() == Some(&url) {
+                        state.started_with = Some(url.clone());
+                    } else {
+                        state.started_with = None;
+                    }
+
+                    state.downloading = progress.left > 0;
+                    failures = 0;
+
+                    // Promote the tracker that answered
+                    {
+                        let mut tiers = tiers.lock().unwrap();
+                        let tier = &mut tiers[tier];
+
+                        if let Some(position) = tier.iter().position(|x| *x == url) {
+                            let url = tier.remove(position);
+                            tier.insert(0, url);
+                        }
+                    }
+
+                    for peer in response.peers {
+                        let fresh = {
+                            let mut seen = seen.lock().unwrap();
+                            let fresh = seen.get(&peer).is_none_or(|x| x.elapsed() >= Self::MIN_ANNOUNCE_INTERVAL);
+
+                            if fresh {
+                                seen.insert(peer, Instant::now());
+                            }
+
+                            fresh
+                        };
+
+                        if fresh && sender.send(peer).await.is_err() {
+                            return;
+                        }
+                    }
+
+                    let interval = response.interval.max(response.min_interval.unwrap_or(0)).max(0);
+
+                    Duration::from_secs(interval as u64).max(Self::MIN_ANNOUNCE_INTERVAL)
+                },
+                None => {
+                    failures += 1;
+
+                    (Self::RETRY_INTERVAL * 2u32.pow(failures.min(5) - 1)).min(Self::MAX_RETRY_INTERVAL)
+                },
+            };
+
+            let downloading = state.downloading;
+            drop(state);
+
+            tokio::select! {
+                _ = tokio::time::sleep(wait) => {},
+                _ = finished.wait_for(|x| *x), if downloading => {},
+                _ = sender.closed() => return,
+            }
+        }
+    }
+
+    async fn announce(
+        url: &str,
+        request: &AnnounceRequest<'_>,
+        endpoint: &Arc<UdpEndpoint>,
+        tier: &mut Tier,
+    ) -> Result<AnnounceResponse, String> {
+        let parsed = Url::parse(url).map_err(|x| format!("Invalid tracker URL: {}", x))?;
+
+        match parsed.scheme() {
+            "http" | "https" => tier.http_clients
+                .entry(url.to_owned())
+                .or_insert_with(|| TrackerHTTPClient::new(url, tier.peer_id))
+                .announce(request)
+                .await
+                .map_err(|x| format!("{:?}", x)),
+            "udp" => {
+                if !tier.udp_clients.contains_key(url) {
+                    let host = resolvable_host(&parsed).ok_or("Tracker URL has no host")?;
+                    let host = host.as_str();
+                    let port = parsed.port().ok_or("Tracker URL has no port")?;
+
+                    let addr: SocketAddr = tokio::net::lookup_host((host, port))
+                        .await
+                        .map_err(|x| format!("Failed to resolve {}: {}", host, x))?
+                        .find(|x| endpoint.supports(x))
+                        .ok_or(format!("{} has no address our socket can reach", host))?;
+
+                    let mut client = TrackerUDPClient::new(&addr, tier.peer_id, endpoint.clone());
+                    client.connect().await.map_err(|x| format!("{:?}", x))?;
+
+                    tier.udp_clients.insert(url.to_owned(), client);
+                }
+
+                // The client reconnects by itself once the connection id expires
+                tier.udp_clients
+                    .get_mut(url)
+                    .unwrap()
+                    .announce(request)
+                    .await
+                    .map_err(|x| format!("{:?}", x))
+            },
+            scheme => Err(format!("Unsupported tracker scheme {}", scheme)),
+        }
+    }
+}
+
+/// The URL's host in a form `lookup_host` takes. `host_str` keeps the brackets around IPv6
+/// addresses, which don't parse as an address
+fn resolvable_host(url: &Url) -> Option<String> {
+    match url.host()? {
+        Host::Ipv6(addr) => Some(addr.to_string()),
+        host => Some(host.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, net::SocketAddr, time::Duration};
+
+    use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpListener, sync::mpsc};
+
+    use crate::net::udp::UdpEndpoint;
+
+    use super::{resolvable_host, AnnounceProgress, TrackerIssue, TrackerManager};
+
+    /// HTTP tracker answering every announce with the same compact peers. Passes on the request
+    /// line of each announce
+    async fn spawn_http_tracker(peers: Vec<[u8; 6]>) -> (String, mpsc::UnboundedReceiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (sender, receiver) = mpsc::unbounded_channel();
+
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+
+                let mut buf = vec![0u8; 4096];
+                let len = stream.read(&mut buf).await.unwrap();
+
+                let request = String::from_utf8_lossy(&buf[..len]);
+                let _ = sender.send(request.lines().next().unwrap_or_default().to_owned());
+
+                let mut body = format!("d8:intervali1800e5:peers{}:", peers.len() * 6).into_bytes();
+                body.extend(peers.iter().flatten());
+                body.push(b'e');
+
+                let mut response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len(),
+                ).into_bytes();
+                response.extend_from_slice(&body);
+
+                stream.write_all(&response).await.unwrap();
+            }
+        });
+
+        (format!("http://{}/announce", addr), receiver)
+    }
+
+    async fn next_event(requests: &mut mpsc::UnboundedReceiver<String>) -> Option<String> {
+        let request = tokio::time::timeout(Duration::from_secs(5), requests.recv()).await.unwrap().unwrap();
+
+        request
+            .split(['?', '&', ' '])
+            .find_map(|x| x.strip_prefix("event="))
+            .map(|x| x.to_owned())
+    }
+
+    #[tokio::test]
+    async fn announces_to_all_tiers_and_dedups_peers() {
+        let (good, _) = spawn_http_tracker(vec![[10, 0, 0, 1, 0x1a, 0xe1], [10, 0, 0, 2, 0x1a, 0xe1]]).await;
+        let (other, _) = spawn_http_tracker(vec![[10, 0, 0, 2, 0x1a, 0xe1], [10, 0, 0, 3, 0x1a, 0xe1]]).await;
+
+        // Nothing listens on port 1
+        let dead = "http://127.0.0.1:1/announce".to_owned();
+
+        let endpoint = UdpEndpoint::bind("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let tiers = vec![vec![dead.clone(), good.clone()], vec![other.clone()]];
+        let mut manager = TrackerManager::new([1; 20], [2; 20], tiers, endpoint, 6881);
+
+        let mut peers = manager.start();
+        let mut received = HashSet::new();
+
+        for _ in 0..3 {
+            let peer = tokio::time::timeout(Duration::from_secs(5), peers.recv()).await.unwrap().unwrap();
+
+            assert!(received.insert(peer));
+        }
+
+        let expected = ["10.0.0.1:6881", "10.0.0.2:6881", "10.0.0.3:6881"]
+            .iter()
+            .map(|x| x.parse::<SocketAddr>().unwrap())
+            .collect::<HashSet<SocketAddr>>();
+
+        assert_eq!(received, expected);
+        assert!(tokio::time::timeout(Duration::from_millis(100), peers.recv()).await.is_err());
+        assert_eq!(manager.tiers()[0][0], good);
+    }
+
+    #[tokio::test]
+    async fn sends_started_completed_and_stopped() {
+        let (url, mut requests) = spawn_http_tracker(vec![]).await;
+
+        let endpoint = UdpEndpoint::bind("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let mut manager = TrackerManager::new([1; 20], [2; 20], vec![vec![url]], endpoint, 6881);
+        manager.set_progress(AnnounceProgress { downloaded: 0, uploaded: 0, left: 100 });
+
+        let _peers = manager.start();
+
+        assert_eq!(next_event(&mut requests).await.as_deref(), Some("started"));
+
+        // Finishing doesn't wait for the 30 minute interval
+        manager.set_progress(AnnounceProgress { downloaded: 100, uploaded: 0, left: 0 });
+
+        assert_eq!(next_event(&mut requests).await.as_deref(), Some("completed"));
+
+        manager.stop().await;
+
+        assert_eq!(next_event(&mut requests).await.as_deref(), Some("stopped"));
+        assert!(requests.try_recv().is_err());
+    }
+
+    #[test]
+    fn strips_brackets_from_ipv6_hosts() {
+        let host = |url: &str| resolvable_host(&url.parse().unwrap());
+
+        assert_eq!(host("udp://[2001:db8::1]:6969/announce").as_deref(), Some("2001:db8::1"));
+        assert_eq!(host("udp://10.0.0.1:6969").as_deref(), Some("10.0.0.1"));
+        assert_eq!(host("udp://tracker.example.org:80").as_deref(), Some("tracker.example.org"));
+    }
+
+    #[tokio::test]
+    async fn reports_failing_trackers() {
+        let dead = "http://127.0.0.1:1/announce".to_owned();
+
+        let endpoint = UdpEndpoint::bind("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let mut manager = TrackerManager::new([1; 20], [2; 20], vec![vec![dead.clone()]], endpoint, 6881);
+        let _peers = manager.start();
+
+        let reported = tokio::time::timeout(Duration::from_secs(5), async {
+            while manager.issues().is_empty() {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        });
+
+        assert!(reported.await.is_ok());
+        assert!(matches!(&manager.issues()[..], [(url, TrackerIssue::Error(_))] if *url == dead));
+    }
+}
@@ -1,8 +1,6 @@
-use std::{io, net::{Ipv4Addr, SocketAddr, SocketAddrV4}};
+use std::{io, net::{IpAddr, SocketAddr}, sync::Arc, time::{Duration, Instant}};
 
-use rand::Rng;
-
-use crate::net::udp::send_udp_packet;
+use crate::net::{compact::parse_compact_peers, udp::{RequestOptions, UdpEndpoint}};
 
 
 #[derive(Debug)]
@@ -11,46 +9,98 @@ pub enum TrackerUDPClientError {
 
     InvalidResponse,
     TransactionIdMismatch,
+
+    /// The tracker answered with an error (action 3)
+    TrackerError(String),
     Other(io::Error)
 }
 
 
 #[derive(Debug)]
-pub struct TrackerUDPClient<'addr> {
-    sock_addr: &'addr SocketAddr,
-    transaction_id: i32,
-    node_id: [u8; 20],
+pub struct TrackerUDPClient {
+    sock_addr: SocketAddr,
+    endpoint: Arc<UdpEndpoint>,
+    peer_id: [u8; 20],
 
     connection_id: Option<[u8; 8]>,
-}
+    connected_at: Option<Instant>,
 
+    /// Requests wait `base_timeout * 2^n` for the n-th retransmission, up to `max_retransmits`
+    base_timeout: Duration,
+    max_retransmits: u32,
+}
 
-impl<'addr> TrackerUDPClient<'addr> {
-    pub fn new(sock_addr: &'addr SocketAddr) -> Self {
-        let node_id = rand::thread_rng().gen::<[u8; 20]>();
 
+impl TrackerUDPClient {
+    pub fn new(sock_addr: &SocketAddr, peer_id: [u8; 20], endpoint: Arc<UdpEndpoint>) -> Self {
         TrackerUDPClient {
-            sock_addr,
-            transaction_id: 0,
-            node_id,
+            sock_addr: *sock_addr,
+            endpoint,
+            peer_id,
             connection_id: None,
+            connected_at: None,
+            base_timeout: Self::BASE_TIMEOUT,
+            max_retransmits: Self::DEFAULT_MAX_RETRANSMITS,
         }
     }
 
-    fn get_unique_transaction_id(&mut self) -> i32 {
-        let value = self.transaction_id;
+    /// Connection ids are only valid for a minute (BEP 15)
+    const CONNECTION_ID_TTL: Duration = Duration::from_secs(60);
+
+    const BASE_TIMEOUT: Duration = Duration::from_secs(15);
 
-        self.transaction_id += 1;
+    /// BEP 15 allows up to 8 retransmissions, which adds up to over an hour. Three keep it to
+    /// 15 + 30 + 60 + 120 = 225 seconds so a dead tracker doesn't hold everything up.
+    const DEFAULT_MAX_RETRANSMITS: u32 = 3;
 
-        value
+    pub fn set_retransmission(&mut self, base_timeout: Duration, max_retransmits: u32) {
+        self.base_timeout = base_timeout;
+        self.max_retransmits = max_retransmits.min(8);
     }
-    
-    fn get_connection_id(&self) -> Result<&[u8; 8], TrackerUDPClientError> {
-        if let Some(connection_id) = self.connection_id.as_ref() {
-            return Ok(connection_id);
+
+    /// Transaction ids come from the shared endpoint so they never collide with other requests
+    fn get_unique_transaction_id(&self) -> i32 {
+        i32::from_be_bytes(self.endpoint.next_transaction_id())
+    }
+
+    /// Our connection id, reconnecting first if it expired
+    async fn get_connection_id(&mut self) -> Result<[u8; 8], TrackerUDPClientError> {
+        let connected_at = self.connected_at.ok_or(TrackerUDPClientError::NotConnected)?;
+
+        if connected_at.elapsed() >= Self::CONNECTION_ID_TTL {
+            self.connect().await?;
+        }
+
+        self.connection_id.ok_or(TrackerUDPClientError::NotConnected)
+    }
+
+    /// Sends a request following the BEP 15 retransmission schedule and turns error responses
+    /// into `TrackerError`
+    async fn send_request(&self, transaction_id: i32, data: &[u8]) -> Result<Vec<u8>, TrackerUDPClientError> {
+        let mut result = Err(io::Error::new(io::ErrorKind::TimedOut, "Tracker didn't respond"));
+
+        for n in 0..=self.max_retransmits {
+            let options = RequestOptions {
+                timeout: self.base_timeout * 2u32.pow(n),
+                retries: 0,
+            };
+
+            result = self.endpoint
+                .request(&self.sock_addr, &transaction_id.to_be_bytes(), data, options)
+                .await;
+
+            if !matches!(&result, Err(err) if err.kind() == io::ErrorKind::TimedOut) {
+                break;
+            }
+        }
+
+        let response = result.map_err(TrackerUDPClientError::Other)?;
+
+        if response.len() >= 8 && i32::from_be_bytes(response[0..4].try_into().unwrap()) == 3 {
+            return Err(TrackerUDPClientError::TrackerError(String::from_utf8_lossy(&response[8..]).into_owned()));
         }
 
-        return Err(TrackerUDPClientError::NotConnected);
+        Ok(response)
     }
 
     pub async fn connect(&mut self) -> Result<(), TrackerUDPClientError> {
@@ -68,13 +118,9 @@ impl<'addr> TrackerUDPClient<'addr> {
         ];
         
         connect_packet_data[12..16].copy_from_slice(&transaction_id.to_be_bytes());
-        
 
-        let response = send_udp_packet(&self.sock_addr, &connect_packet_data)
-            .await
-            .map_err(|e| TrackerUDPClientError::Other(e))?;
+        let response = self.send_request(transaction_id, &connect_packet_data).await?;
 
-        
         if response.len() < 16 {
             return Err(TrackerUDPClientError::InvalidResponse);
         }
@@ -87,13 +133,14 @@ impl<'addr> TrackerUDPClient<'addr> {
 
         let connection_id = &response[8..16];
         self.connection_id = Some(connection_id.try_into().unwrap());
+        self.connected_at = Some(Instant::now());
 
         Ok(())
     }
 
     pub async fn announce<'a>(&mut self, request: &AnnounceRequest<'a>) -> Result<AnnounceResponse, TrackerUDPClientError> {
+        let connection_id = self.get_connection_id().await?;
         let transaction_id = self.get_unique_transaction_id();
-        let connection_id = self.get_connection_id()?;
 
         let AnnounceRequest {
             info_hash,
@@ -104,11 +151,12 @@ impl<'addr> TrackerUDPClient<'addr> {
             ip_address,
             port,
             event,
+            key,
         } = request;
 
         let mut announce_packet_data = [0u8; 98];
         // connection_id
-        announce_packet_data[0..8].copy_from_slice(connection_id);
+        announce_packet_data[0..8].copy_from_slice(&connection_id);
         // action
         announce_packet_data[8..12].copy_from_slice(&[0x00, 0x00, 0x00, 0x01]);
         // transaction_id
@@ -116,7 +164,7 @@ impl<'addr> TrackerUDPClient<'addr> {
         // info_hash
         announce_packet_data[16..36].copy_from_slice(*info_hash);
         // peer_id
-        announce_packet_data[36..56].copy_from_slice(&self.node_id);
+        announce_packet_data[36..56].copy_from_slice(&self.peer_id);
         // downloaded
         announce_packet_data[56..64].copy_from_slice(&downloaded.to_be_bytes());
         // left
@@ -126,23 +174,24 @@ impl<'addr> TrackerUDPClient<'addr> {
         // event
         announce_packet_data[80..84].copy_from_slice(&event.to_be_bytes());
         // ip_address
-        announce_packet_data[84..88].copy_from_slice(ip_address);
-        // key. TODO: Not sure what this is used for
-        announce_packet_data[88..92].copy_from_slice(&[0x00; 4]);
+        if let Some(IpAddr::V4(ip)) = ip_address {
+            announce_packet_data[84..88].copy_from_slice(&ip.octets());
+        }
+        // key
+        announce_packet_data[88..92].copy_from_slice(&key.to_be_bytes());
         // num_wait
         announce_packet_data[92..96].copy_from_slice(&num_want.to_be_bytes());
         // port
         announce_packet_data[96..98].copy_from_slice(&port.to_be_bytes());
 
-        let response = send_udp_packet(&self.sock_addr, &announce_packet_data)
-            .await
-            .map_err(|e| TrackerUDPClientError::Other(e))?;
+        let response = self.send_request(transaction_id, &announce_packet_data).await?;
 
         if response.len() < 20 {
             return Err(TrackerUDPClientError::InvalidResponse);
         }
 
-        let announce_response = AnnounceResponse::try_from(&response[0..])
+        // Trackers reached over IPv6 answer with 18 byte IPv6 peers (BEP 15)
+        let announce_response = AnnounceResponse::from_bytes(&response, self.sock_addr.is_ipv6())
             .map_err(|_| TrackerUDPClientError::InvalidResponse)?;
         
         if announce_response.action != 1 {
@@ -155,6 +204,72 @@ impl<'addr> TrackerUDPClient<'addr> {
 
         Ok(announce_response)
     }
+
+    /// Swarm stats for every hash in `info_hashes`, in the same order. Hashes are sent in batches
+    /// since a scrape packet only fits about 74 of them.
+    pub async fn scrape(&mut self, info_hashes: &[[u8; 20]]) -> Result<Vec<ScrapeStats>, TrackerUDPClientError> {
+        let mut stats = Vec::with_capacity(info_hashes.len());
+
+        for batch in info_hashes.chunks(MAX_SCRAPE_HASHES) {
+            let connection_id = self.get_connection_id().await?;
+            let transaction_id = self.get_unique_transaction_id();
+
+            let mut scrape_packet_data = Vec::with_capacity(16 + batch.len() * 20);
+            // connection_id
+            scrape_packet_data.extend_from_slice(&connection_id);
+            // action
+            scrape_packet_data.extend_from_slice(&[0x00, 0x00, 0x00, 0x02]);
+            // transaction_id
+            scrape_packet_data.extend_from_slice(&transaction_id.to_be_bytes());
+            // info_hashes
+            scrape_packet_data.extend(batch.iter().flatten());
+
+            let response = self.send_request(transaction_id, &scrape_packet_data).await?;
+
+            if response.len() < 8 + batch.len() * 12 {
+                return Err(TrackerUDPClientError::InvalidResponse);
+            }
+
+            let action = i32::from_be_bytes(response[0..4].try_into().unwrap());
+
+            if action != 2 {
+                return Err(TrackerUDPClientError::InvalidResponse);
+            }
+
+            let resp_transaction_id = i32::from_be_bytes(response[4..8].try_into().unwrap());
+
+            if transaction_id != resp_transaction_id {
+                return Err(TrackerUDPClientError::TransactionIdMismatch);
+            }
+
+            let batch_stats = batch
+                .iter()
+                .zip(response[8..].chunks_exact(12))
+                .map(|(info_hash, bytes)| ScrapeStats {
+                    info_hash: *info_hash,
+                    seeders: i32::from_be_bytes(bytes[0..4].try_into().unwrap()),
+                    completed: i32::from_be_bytes(bytes[4..8].try_into().unwrap()),
+                    leechers: i32::from_be_bytes(bytes[8..12].try_into().unwrap()),
+                });
+
+            stats.extend(batch_stats);
+        }
+
+        Ok(stats)
+    }
+}
+
+/// Most hashes a single scrape request can carry (BEP 15)
+pub const MAX_SCRAPE_HASHES: usize = 74;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScrapeStats {
+    pub info_hash: [u8; 20],
+    pub seeders: i32,
+
+    /// Number of times the torrent was downloaded to completion
+    pub completed: i32,
+    pub leechers: i32,
 }
 
 #[derive(Debug)]
@@ -166,11 +281,16 @@ pub struct AnnounceRequest<'hash> {
 
     pub num_want: i32,
 
-    pub ip_address: [u8; 4],
+    /// Address the tracker should hand out instead of the one the announce came from. The UDP
+    /// protocol only has room for an IPv4 address, IPv6 ones are left for the tracker to detect
+    pub ip_address: Option<IpAddr>,
     pub port: i16,
 
     // 0: none; 1: completed; 2: started; 3: stopped
     pub event: i32,
+
+    /// Random value identifying us to the tracker even if our IP changes
+    pub key: i32,
 }
 
 impl<'a> Default for AnnounceRequest<'a> {
@@ -181,9 +301,10 @@ impl<'a> Default for AnnounceRequest<'a> {
             left: 0,
             uploaded: 0,
             num_want: -1,
-            ip_address: [0; 4],
+            ip_address: None,
             port: 6969,
             event: 0,
+            key: 0,
         }
     }
 }
@@ -195,13 +316,17 @@ pub struct AnnounceResponse {
     pub interval: i32,
     pub leechers: i32,
     pub seeders: i32,
-    pub peers: Vec<SocketAddrV4>,
-}
+    pub peers: Vec<SocketAddr>,
 
-impl TryFrom<&[u8]> for AnnounceResponse {
-    type Error = String;
+    /// Only sent by HTTP trackers
+    pub warning_message: Option<String>,
+    pub min_interval: Option<i32>,
+    pub tracker_id: Option<String>,
+}
 
-    fn try_from(data: &[u8]) -> Result<Self, Self::Error> {
+impl AnnounceResponse {
+    /// Parses a UDP announce response. `ipv6` selects the peer format
+    pub fn from_bytes(data: &[u8], ipv6: bool) -> Result<Self, String> {
         if data.len() < 20 {
             return Err("Invalid data length".to_owned());
         }
@@ -212,10 +337,7 @@ impl TryFrom<&[u8]> for AnnounceResponse {
         let leechers = i32::from_be_bytes(data[12..16].try_into().unwrap());
         let seeders = i32::from_be_bytes(data[16..20].try_into().unwrap());
 
-        let peers = data[20..]
-            .chunks(6)
-            .map(|bytes| SocketAddrV4::new(Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]), u16::from_be_bytes([bytes[4], bytes[5]])))
-            .collect::<Vec<SocketAddrV4>>();
+        let peers = parse_compact_peers(&data[20..], ipv6);
 
         Ok(Self {
             action,
@@ -224,6 +346,134 @@ impl TryFrom<&[u8]> for AnnounceResponse {
             leechers,
             seeders,
             peers,
+            warning_message: None,
+            min_interval: None,
+            tracker_id: None,
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, time::{Duration, Instant}};
+
+    use tokio::net::UdpSocket;
+
+    use crate::{net::udp::UdpEndpoint, tracker::AnnounceRequest};
+
+    use super::{AnnounceResponse, ScrapeStats, TrackerUDPClient, TrackerUDPClientError};
+
+    /// Fake tracker answering connects and scrapes and rejecting announces. Every hash gets
+    /// seeders = its first byte. Returns the action of every request and the number of hashes in
+    /// each scrape.
+    async fn spawn_tracker(scrapes: usize) -> (SocketAddr, tokio::task::JoinHandle<(Vec<u8>, Vec<usize>)>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+
+        let handle = tokio::spawn(async move {
+            let mut buf = [0u8; 2048];
+            let mut actions = vec![];
+            let mut batch_sizes = vec![];
+
+            while batch_sizes.len() < scrapes {
+                let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+                let request = &buf[..len];
+
+                actions.push(request[11]);
+
+                let mut response = request[8..16].to_vec();
+
+                match request[11] {
+                    0 => response.extend_from_slice(&[7; 8]),
+                    1 => {
+                        response[3] = 3;
+                        response.extend_from_slice("torrent not registered".as_bytes());
+                    },
+                    2 => {
+                        let hashes = request[16..].chunks_exact(20).collect::<Vec<_>>();
+                        batch_sizes.push(hashes.len());
+
+                        for hash in hashes {
+                            response.extend_from_slice(&(hash[0] as i32).to_be_bytes());
+                            response.extend_from_slice(&1i32.to_be_bytes());
+                            response.extend_from_slice(&2i32.to_be_bytes());
+                        }
+                    },
+                    _ => unreachable!(),
+                }
+
+                socket.send_to(&response, from).await.unwrap();
+            }
+
+            (actions, batch_sizes)
+        });
+
+        (addr, handle)
+    }
+
+    #[tokio::test]
+    async fn scrapes_in_batches() {
+        let (tracker, handle) = spawn_tracker(2).await;
+
+        let endpoint = UdpEndpoint::bind("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let mut client = TrackerUDPClient::new(&tracker, [2; 20], endpoint);
+        client.connect().await.unwrap();
+
+        let info_hashes = (0..100u8).map(|x| [x; 20]).collect::<Vec<[u8; 20]>>();
+        let stats = client.scrape(&info_hashes).await.unwrap();
+
+        assert_eq!(handle.await.unwrap().1, vec![74, 26]);
+        assert_eq!(stats.len(), 100);
+        assert_eq!(stats[80], ScrapeStats { info_hash: [80; 20], seeders: 80, completed: 1, leechers: 2 });
+    }
+
+    #[tokio::test]
+    async fn reports_tracker_errors_and_reconnects() {
+        let (tracker, handle) = spawn_tracker(1).await;
+
+        let endpoint = UdpEndpoint::bind("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let mut client = TrackerUDPClient::new(&tracker, [2; 20], endpoint);
+        client.connect().await.unwrap();
+
+        let error = client.announce(&AnnounceRequest::default()).await.unwrap_err();
+        assert!(matches!(error, TrackerUDPClientError::TrackerError(x) if x == "torrent not registered"));
+
+        client.connected_at = Some(Instant::now() - Duration::from_secs(61));
+        client.scrape(&[[1; 20]]).await.unwrap();
+
+        assert_eq!(handle.await.unwrap().0, vec![0, 1, 0, 2]);
+    }
+
+    #[tokio::test]
+    async fn retransmits_with_backoff() {
+        let tracker = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let tracker_addr = tracker.local_addr().unwrap();
+
+        let endpoint = UdpEndpoint::bind("127.0.0.1:0".parse().unwrap()).await.unwrap();
+        let mut client = TrackerUDPClient::new(&tracker_addr, [2; 20], endpoint);
+        client.set_retransmission(Duration::from_millis(10), 2);
+
+        let started = Instant::now();
+        let error = client.connect().await.unwrap_err();
+
+        // 10 + 20 + 40 ms
+        assert!(started.elapsed() >= Duration::from_millis(70));
+        assert!(matches!(error, TrackerUDPClientError::Other(x) if x.kind() == std::io::ErrorKind::TimedOut));
+
+        let mut buf = [0u8; 64];
+        for _ in 0..3 {
+            tracker.recv_from(&mut buf).await.unwrap();
+        }
+    }
+
+    #[test]
+    fn parses_ipv6_announce_response() {
+        let mut data = vec![0, 0, 0, 1, 0, 0, 0, 9, 0, 0, 7, 8, 0, 0, 0, 1, 0, 0, 0, 2];
+        data.extend_from_slice(&[0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0x1a, 0xe1]);
+
+        let response = AnnounceResponse::from_bytes(&data, true).unwrap();
+
+        assert_eq!(response.interval, 1800);
+        assert_eq!(response.peers, vec!["[2001:db8::1]:6881".parse::<SocketAddr>().unwrap()]);
+    }
+}
@@ -0,0 +1,49 @@
+const ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+pub fn encode_base32(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity((bytes.len() * 8).div_ceil(5));
+
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+
+    for byte in bytes {
+        buffer = (buffer << 8) | *byte as u32;
+        bits += 8;
+
+        while bits >= 5 {
+            bits -= 5;
+            encoded.push(ALPHABET[((buffer >> bits) & 0x1F) as usize] as char);
+        }
+    }
+
+    if bits > 0 {
+        encoded.push(ALPHABET[((buffer << (5 - bits)) & 0x1F) as usize] as char);
+    }
+
+    encoded
+}
+
+/// Decodes an unpadded RFC 4648 base32 string. Lowercase letters are accepted as well since some
+/// clients emit them in magnet links.
+pub fn decode_base32(string: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(string.len() * 5 / 8);
+
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+
+    for c in string.bytes() {
+        let value = ALPHABET
+            .iter()
+            .position(|x| *x == c.to_ascii_uppercase())? as u32;
+
+        buffer = (buffer << 5) | value;
+        bits += 5;
+
+        if bits >= 8 {
+            bits -= 8;
+            decoded.push((buffer >> bits) as u8);
+        }
+    }
+
+    Some(decoded)
+}
@@ -1,4 +1,4 @@
-use std::{borrow::Borrow, collections::HashMap, fmt::{Debug, Formatter, Write}, str};
+use std::{collections::HashMap, fmt::Debug, str};
 
 #[derive(PartialEq, Eq)]
 pub enum BencodeValue {
@@ -11,10 +11,7 @@ pub enum BencodeValue {
 impl Debug for BencodeValue {
     fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Self::Integer(n) => {
-                print!("hi");
-                fmt.write_str(&n.to_string())
-            },
+            Self::Integer(n) => fmt.write_str(&n.to_string()),
             Self::Bytes(bytes) => {
                 let mut list_fmt = fmt.debug_list();
 
@@ -40,7 +37,7 @@ impl Debug for BencodeValue {
 
                     let key = {
                         if let Ok(string) = str::from_utf8(key) {
-                            format!("{}", string)
+                            string.to_owned()
                         } else {
                             format!("{:04X?}", key)
                         }
@@ -109,18 +106,18 @@ impl BencodeValue {
 
     fn write_serialized_bytes(bytes: &[u8], buff: &mut Vec<u8>) {
         buff.extend_from_slice(bytes.len().to_string().as_bytes());
-        buff.push(':' as u8);
+        buff.push(b':');
         buff.extend_from_slice(bytes);
     }
 
     fn write_serialized_list(list: &Vec<BencodeValue>, buff: &mut Vec<u8>) {
-        buff.push('l' as u8);
+        buff.push(b'l');
 
         for value in list {
             buff.extend_from_slice(&value.serialize());
         }
 
-        buff.push('e' as u8)
+        buff.push(b'e')
     }
 
     fn write_serialized_dict(dict: &HashMap<Vec<u8>, BencodeValue>, buff: &mut Vec<u8>) {
@@ -131,7 +128,7 @@ impl BencodeValue {
         // TODO: Does the sorting here actually work?
         entries.sort_by(|a, b| a.0.cmp(b.0));
 
-        buff.push('d' as u8);
+        buff.push(b'd');
 
         for (key, value) in entries {
             // Write the key
@@ -143,7 +140,7 @@ impl BencodeValue {
             buff.extend(value.serialize());
         }
 
-        buff.push('e' as u8);
+        buff.push(b'e');
     }
 }
 
@@ -151,7 +148,7 @@ impl TryFrom<&[u8]> for BencodeValue {
     type Error = BencodeParserError;
 
     fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
-        todo!()
+        BencodeParser::new(value).parse_value()
     }
 }
 
@@ -175,8 +172,14 @@ impl<'data> BencodeParser<'data> {
         Self { data, ptr: 0 }
     }
 
+    /// Offset of the first byte that hasn't been consumed yet. Some messages (ut_metadata pieces)
+    /// append raw data after a bencoded dict so this is where that data starts.
+    pub fn position(&self) -> usize {
+        self.ptr
+    }
+
     pub fn parse_value(&mut self) -> Result<BencodeValue, BencodeParserError> {
-        match self.data[self.ptr] as char {
+        match self.peek()? as char {
             'i' => Ok(BencodeValue::Integer(self.consume_integer()?)),
             'l' => Ok(BencodeValue::List(self.consume_list()?)),
             'd' => Ok(BencodeValue::Dict(self.consume_dict()?)),
@@ -193,6 +196,52 @@ impl<'data> BencodeParser<'data> {
         }
     }
 
+    /// Parses a dict and returns the exact bytes of the value stored under `key` as they appear in
+    /// the input. Needed for things like the info-hash which must be computed over the original
+    /// encoding rather than a re-serialization.
+    pub fn parse_raw_dict_value(
+        &mut self,
+        key: &[u8],
+    ) -> Result<Option<&'data [u8]>, BencodeParserError> {
+        if self.peek()? as char != 'd' {
+            return Err(BencodeParserError {
+                error: "Expected a dict".to_owned(),
+                pos: self.ptr,
+            });
+        }
+
+        let mut raw_value = None;
+
+        // Skip the start marker 'd'
+        self.ptr += 1;
+
+        while self.peek()? as char != 'e' {
+            let current_key = self.consume_bytes()?;
+
+            let start = self.ptr;
+            self.parse_value()?;
+
+            if current_key == key {
+                raw_value = Some(&self.data[start..self.ptr]);
+            }
+        }
+
+        // Skip the end marker 'e'
+        self.ptr += 1;
+
+        Ok(raw_value)
+    }
+
+    fn peek(&self) -> Result<u8, BencodeParserError> {
+        self.data
+            .get(self.ptr)
+            .copied()
+            .ok_or(BencodeParserError {
+                error: "Unexpected end of data".to_owned(),
+                pos: self.ptr,
+            })
+    }
+
     fn consume_dict(
         &mut self,
     ) -> Result<HashMap<Vec<u8>, BencodeValue>, BencodeParserError> {
@@ -201,12 +250,8 @@ impl<'data> BencodeParser<'data> {
         // Skip the start marker 'd'
         self.ptr += 1;
 
-        // println!("PTR: {:?}", self.ptr);
-
-        while self.data[self.ptr] as char != 'e' {
+        while self.peek()? as char != 'e' {
             let key = self.consume_bytes()?;
-            // println!("DICT Consumed key");
-            // println!("PTR: {:?}", self.ptr);
             let value = self.parse_value()?;
             
             dict.insert(key, value);
@@ -224,7 +269,7 @@ impl<'data> BencodeParser<'data> {
 
         let mut list: Vec<BencodeValue> = vec![];
 
-        while self.data[self.ptr] as char != 'e' {
+        while self.peek()? as char != 'e' {
             list.push(self.parse_value()?);
         }
 
@@ -240,17 +285,24 @@ impl<'data> BencodeParser<'data> {
         *ptr += 1; // Skip the 'i'
 
         let integer_length: usize = data[*ptr..]
-            .into_iter()
+            .iter()
             .take_while(|b| **b as char != 'e')
             .count();
 
+        if *ptr + integer_length >= data.len() {
+            return Err(BencodeParserError {
+                error: "Unterminated integer".to_owned(),
+                pos: *ptr,
+            });
+        }
+
         let number_str = String::from_utf8(data[*ptr..(*ptr + integer_length)].to_owned());
 
         let integer = number_str
             .ok()
             .and_then(|s| s.parse::<i64>().ok())
             .ok_or(BencodeParserError {
-                error: format!("Failed to parse integer"),
+                error: "Failed to parse integer".to_owned(),
                 pos: *ptr,
             })?;
 
@@ -260,7 +312,6 @@ impl<'data> BencodeParser<'data> {
     }
 
     fn consume_bytes(&mut self) -> Result<Vec<u8>, BencodeParserError> {
-        // println!("Consuming bytes: {:?}", self.ptr);
         let Self { data, ptr } = self;
 
         let len = {
@@ -283,7 +334,14 @@ impl<'data> BencodeParser<'data> {
 
             len
         };
-        let bytes = data[(*ptr)..(*ptr + len)].to_owned();
+
+        let bytes = data
+            .get((*ptr)..(*ptr).saturating_add(len))
+            .ok_or(BencodeParserError {
+                error: format!("Bytestring of length {} exceeds the data", len),
+                pos: *ptr,
+            })?
+            .to_owned();
 
         *ptr += len;
 
@@ -354,4 +412,22 @@ mod tests {
 
         assert_eq!(list, parsed);
     }
+
+    #[test]
+    fn returns_raw_dict_value() {
+        let data = "d3:fooi1e4:infod1:xi5e1:yli1eee3:zzzi2ee".as_bytes();
+
+        let raw = BencodeParser::new(data)
+            .parse_raw_dict_value("info".as_bytes())
+            .unwrap();
+
+        assert_eq!(raw, Some("d1:xi5e1:yli1eee".as_bytes()));
+    }
+
+    #[test]
+    fn fails_on_truncated_data() {
+        for data in ["d3:foo", "l4:spa", "i12", "d3:fooi1e", "10:abc"] {
+            assert!(BencodeParser::new(data.as_bytes()).parse_value().is_err(), "{}", data);
+        }
+    }
 }
@@ -1,2 +1,5 @@
 pub mod bencode;
+pub mod base32;
 pub mod hex;
+#[cfg(test)]
+pub mod testing;
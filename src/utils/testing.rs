@@ -0,0 +1,75 @@
+use std::{collections::HashMap, ops::Deref, path::{Path, PathBuf}};
+
+use sha1::{Digest, Sha1};
+
+use super::bencode::BencodeValue;
+
+/// Directory under the system temp dir that's removed again when dropped, so failing tests don't
+/// leave it behind
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    /// The name has to be unique across the tests, the process id keeps parallel runs apart
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("rustbittorrent-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+
+        Self(path)
+    }
+}
+
+impl Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TempDir {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+fn info_dict(name: &str, piece_length: u64, data: &[u8], mut entries: HashMap<Vec<u8>, BencodeValue>) -> Vec<u8> {
+    let pieces = data.chunks(piece_length as usize).flat_map(Sha1::digest).collect::<Vec<u8>>();
+
+    entries.insert("name".as_bytes().to_vec(), BencodeValue::Bytes(name.as_bytes().to_vec()));
+    entries.insert("piece length".as_bytes().to_vec(), BencodeValue::Integer(piece_length as i64));
+    entries.insert("pieces".as_bytes().to_vec(), BencodeValue::Bytes(pieces));
+
+    BencodeValue::Dict(entries).serialize()
+}
+
+/// Bencoded info dict of a single file torrent holding `data`
+pub fn single_file_info(name: &str, piece_length: u64, data: &[u8]) -> Vec<u8> {
+    let entries = HashMap::from([("length".as_bytes().to_vec(), BencodeValue::Integer(data.len() as i64))]);
+
+    info_dict(name, piece_length, data, entries)
+}
+
+/// Bencoded info dict of a multi file torrent, the paths of the files are separated by `/`
+pub fn multi_file_info(name: &str, piece_length: u64, files: &[(&str, &[u8])]) -> Vec<u8> {
+    let list = files
+        .iter()
+        .map(|(path, data)| {
+            let path = path.split('/').map(|x| BencodeValue::Bytes(x.as_bytes().to_vec())).collect();
+
+            BencodeValue::Dict(HashMap::from([
+                ("length".as_bytes().to_vec(), BencodeValue::Integer(data.len() as i64)),
+                ("path".as_bytes().to_vec(), BencodeValue::List(path)),
+            ]))
+        })
+        .collect();
+
+    let data = files.iter().flat_map(|(_, data)| data.iter().copied()).collect::<Vec<u8>>();
+
+    info_dict(name, piece_length, &data, HashMap::from([("files".as_bytes().to_vec(), BencodeValue::List(list))]))
+}